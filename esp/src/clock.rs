@@ -1,4 +1,5 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use esp_idf_svc::sys;
 use libc;
 
 pub fn now() -> DateTime<Utc> {
@@ -22,3 +23,14 @@ pub fn set_time(now: DateTime<Utc>) {
         unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &tp as *const libc::timespec) };
     assert_eq!(is_error, 0);
 }
+
+/// Moves the clock by `delta` gradually; a new call replaces whatever is left of the previous one
+pub fn adjtime(delta: TimeDelta) {
+    let us = delta.num_microseconds().unwrap();
+    let tv = sys::timeval {
+        tv_sec: us.div_euclid(1_000_000),
+        tv_usec: us.rem_euclid(1_000_000).try_into().unwrap(),
+    };
+    let is_error = unsafe { sys::adjtime(&tv as *const sys::timeval, std::ptr::null_mut()) };
+    assert_eq!(is_error, 0);
+}
@@ -1,8 +1,14 @@
-use crate::{clock_face::ClockFace, metrics::Metrics};
-use esp_idf_svc::http::server::{Connection, EspHttpServer, Response};
+use crate::clock;
+use crate::ntp_settings::NtpSettings;
+use crate::nvs_store::NvsStore;
+use crate::{clock_face::ClockFace, metrics, metrics::Metrics};
+use esp_idf_svc::http::server::{Connection, EspHttpServer, Request, Response};
 use esp_idf_svc::http::Method;
 use esp_idf_svc::io::ErrorType;
 use esp_idf_svc::io::EspIOError;
+use ntp::leapfile::LeapTable;
+use ntp::persist::ReceiverState;
+use ntp::server::GPSServer;
 use serde::Deserialize;
 use std::sync::{Arc, Mutex};
 
@@ -12,6 +18,109 @@ struct Form {
     brightness: u8,
 }
 
+#[derive(Deserialize)]
+struct NtpForm {
+    keys: String,
+}
+
+#[derive(Deserialize)]
+struct RestrictForm {
+    rules: String,
+}
+
+#[derive(Deserialize)]
+struct LeapForm {
+    list: String,
+}
+
+/// Uploaded keys and rules are stored in NVS, which limits the size of a value
+const MAX_BODY_LEN: usize = 1024;
+/// The upstream leap-seconds.list is mostly comments, which are dropped before it is stored
+const MAX_LEAP_BODY_LEN: usize = 16 * 1024;
+
+/// Reads the request body; None if it is longer than `max` bytes
+fn read_body<T: Connection>(req: &mut Request<T>, max: usize) -> Result<Option<String>, EspIOError>
+where
+    EspIOError: From<<T as ErrorType>::Error>,
+{
+    // one byte more than allowed tells a body of `max` bytes from a longer one
+    let mut buf: Vec<u8> = vec![0; max + 1];
+    let mut len = 0;
+    while len <= max {
+        let n = req.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    if len > max {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&buf[..len]).into_owned()))
+}
+
+fn too_large<T: Connection>(req: Request<T>, max: usize) -> Result<(), EspIOError>
+where
+    EspIOError: From<<T as ErrorType>::Error>,
+{
+    let mut resp = req.into_response(413, None, &[("content-type", "text/plain")])?;
+    resp.write(format!("At most {} bytes can be uploaded, nothing was changed", max).as_bytes())?;
+    Ok(())
+}
+
+/// Secrets are never sent back, only how many keys are loaded
+fn ntp_form<T: Connection>(
+    resp: &mut Response<T>,
+    s: &Arc<Mutex<GPSServer>>,
+    l: &Arc<Mutex<LeapTable>>,
+) -> Result<(), EspIOError>
+where
+    EspIOError: From<<T as ErrorType>::Error>,
+{
+    let (key_count, rules) = {
+        let srv = s.lock().unwrap();
+        (srv.keys.len(), srv.restrictions.to_string())
+    };
+    resp.write(b"<form method=post action='/ntp'>")?;
+    resp.write(
+        format!(
+            "{} NTP keys loaded, replace with (ntpd keys format):",
+            key_count
+        )
+        .as_bytes(),
+    )?;
+    resp.write(b"<textarea name='keys'></textarea>")?;
+    resp.write(r#"<input type="submit" value="Change">"#.as_bytes())?;
+    resp.write(b"</form>")?;
+    resp.write(b"<form method=post action='/ntp/restrict'>")?;
+    resp.write(b"Access rules, one per line, <code>restrict &lt;default | address[/prefix]&gt; &lt;serve | ignore | kod | noquery&gt;</code>:")?;
+    resp.write(b"<textarea name='rules'>")?;
+    // addresses and action names need no escaping
+    resp.write(rules.as_bytes())?;
+    resp.write(b"</textarea>")?;
+    resp.write(r#"<input type="submit" value="Change">"#.as_bytes())?;
+    resp.write(b"</form>")?;
+    let expires = l.lock().unwrap().expires;
+    resp.write(b"<form method=post action='/ntp/leap'>")?;
+    resp.write(
+        format!(
+            "Leap seconds list expires {}, replace with a newer leap-seconds.list:",
+            expires
+        )
+        .as_bytes(),
+    )?;
+    resp.write(b"<textarea name='list'></textarea>")?;
+    resp.write(r#"<input type="submit" value="Change">"#.as_bytes())?;
+    resp.write(b"</form>")?;
+    resp.write(b"<form method=post action='/gps/forget'>")?;
+    resp.write(
+        b"Time from the receiver long before the last good time is not used; if that was wrong:",
+    )?;
+    resp.write(r#"<input type="submit" value="Forget the last good time">"#.as_bytes())?;
+    resp.write(b"</form>")?;
+    Ok::<(), EspIOError>(())
+}
+
 fn index<T: Connection>(resp: &mut Response<T>, c: Arc<Mutex<ClockFace>>) -> Result<(), EspIOError>
 where
     EspIOError: From<<T as ErrorType>::Error>,
@@ -54,11 +163,32 @@ where
 pub(crate) fn server(
     m: Arc<Mutex<Metrics>>,
     c: Arc<Mutex<ClockFace>>,
+    s: Arc<Mutex<GPSServer>>,
+    n: Arc<Mutex<NtpSettings>>,
+    l: Arc<Mutex<LeapTable>>,
+    r: Arc<Mutex<ReceiverState<NvsStore>>>,
 ) -> Result<EspHttpServer<'static>, EspIOError> {
     let mut httpserver = EspHttpServer::new(&Default::default())?;
 
     let c1 = c.clone();
     let c2 = c.clone();
+    let c3 = c.clone();
+    let s1 = s.clone();
+    let s2 = s.clone();
+    let s3 = s.clone();
+    let s4 = s.clone();
+    let c4 = c.clone();
+    let c5 = c.clone();
+    let c6 = c.clone();
+    let s5 = s.clone();
+    let s6 = s.clone();
+    let n2 = n.clone();
+    let n3 = n.clone();
+    let l1 = l.clone();
+    let l2 = l.clone();
+    let l3 = l.clone();
+    let l4 = l.clone();
+    let l5 = l.clone();
     httpserver.fn_handler("/", Method::Post, move |mut req| {
         let mut buf: Vec<u8> = vec![0; 64];
         req.read(&mut buf)?;
@@ -75,12 +205,78 @@ pub(crate) fn server(
         }
 
         let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
-        index(&mut resp, c1.clone())
+        index(&mut resp, c1.clone())?;
+        ntp_form(&mut resp, &s1, &l1)
     })?;
 
     httpserver.fn_handler("/", Method::Get, move |req| {
         let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
-        index(&mut resp, c2.clone())
+        index(&mut resp, c2.clone())?;
+        ntp_form(&mut resp, &s2, &l2)
+    })?;
+
+    httpserver.fn_handler("/ntp", Method::Post, move |mut req| {
+        let Some(body) = read_body(&mut req, MAX_BODY_LEN)? else {
+            return too_large(req, MAX_BODY_LEN);
+        };
+        match serde_urlencoded::from_str::<NtpForm>(body.trim_end_matches(char::from(0))) {
+            Ok(f) => {
+                if let Some(keys) = n.lock().unwrap().set_keys(&f.keys) {
+                    s3.lock().unwrap().set_keys(keys);
+                }
+            }
+            Err(e) => println!("Bad NTP settings form: {:?}", e),
+        }
+
+        let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
+        index(&mut resp, c3.clone())?;
+        ntp_form(&mut resp, &s3, &l3)
+    })?;
+
+    httpserver.fn_handler("/ntp/restrict", Method::Post, move |mut req| {
+        let Some(body) = read_body(&mut req, MAX_BODY_LEN)? else {
+            return too_large(req, MAX_BODY_LEN);
+        };
+        match serde_urlencoded::from_str::<RestrictForm>(body.trim_end_matches(char::from(0))) {
+            Ok(f) => {
+                if let Some(rules) = n2.lock().unwrap().set_restrictions(&f.rules) {
+                    s4.lock().unwrap().set_restrictions(rules);
+                }
+            }
+            Err(e) => println!("Bad restrict form: {:?}", e),
+        }
+
+        let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
+        index(&mut resp, c4.clone())?;
+        ntp_form(&mut resp, &s4, &l4)
+    })?;
+
+    httpserver.fn_handler("/ntp/leap", Method::Post, move |mut req| {
+        let Some(body) = read_body(&mut req, MAX_LEAP_BODY_LEN)? else {
+            return too_large(req, MAX_LEAP_BODY_LEN);
+        };
+        match serde_urlencoded::from_str::<LeapForm>(body.trim_end_matches(char::from(0))) {
+            Ok(f) => {
+                let current = l.lock().unwrap().clone();
+                if let Some(table) = n3.lock().unwrap().set_leap_table(&f.list, &current) {
+                    *l.lock().unwrap() = table;
+                }
+            }
+            Err(e) => println!("Bad leap seconds form: {:?}", e),
+        }
+
+        let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
+        index(&mut resp, c5.clone())?;
+        ntp_form(&mut resp, &s5, &l)
+    })?;
+
+    httpserver.fn_handler("/gps/forget", Method::Post, move |req| {
+        r.lock().unwrap().forget_good_time();
+        println!("Forgot the last good time");
+
+        let mut resp = req.into_response(200, None, &[("content-type", "text/html")])?;
+        index(&mut resp, c6.clone())?;
+        ntp_form(&mut resp, &s6, &l5)
     })?;
 
     httpserver.fn_handler("/metrics", Method::Get, move |req| {
@@ -90,6 +286,10 @@ pub(crate) fn server(
             resp.write(ln.as_bytes())?;
             resp.write(b"\n")?;
         }
+        for ln in metrics::server_state(&s.lock().unwrap(), clock::now()) {
+            resp.write(ln.as_bytes())?;
+            resp.write(b"\n")?;
+        }
         Ok::<(), EspIOError>(())
     })?;
 
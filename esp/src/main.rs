@@ -3,26 +3,52 @@ mod clock_face;
 mod http;
 mod max7219;
 mod metrics;
+mod ntp_settings;
+mod nvs_store;
+mod steering;
+mod timepulse;
 mod uart;
 mod wifi;
 
 use chrono::{DateTime, Utc};
 use esp_idf_hal::prelude::Peripherals;
 use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use ntp::leap::LeapMode;
+use ntp::leapfile::LeapTable;
+use ntp::persist::ReceiverState;
+use ntp::pps::{nearest_second, Pps, PpsConfig, PpsSample};
 use ntp::proto::*;
-use ntp::server::GPSServer;
-use std::net::UdpSocket;
+use ntp::ratelimit::{Decision, RateLimitConfig, RateLimiter};
+use ntp::restrict::Action;
+use ntp::server::{measure_precision, GPSServer};
+use ntp::source::{SourceUpdate, TimeSource};
+use std::net::{ToSocketAddrs, UdpSocket};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::metrics::{Metric, Metrics};
+use ubx::config::AckTracker;
 use ubx::helpers::disable_nmea;
-use ubx::proto::{Frame, PacketIterator, ParsedPacket};
-use ubx::proto_nav::{NavPacket, NavStatusPoll, SVInfoPoll, TimeGPS};
+use ubx::latency::SerialLatency;
+use ubx::proto::{Frame, PacketIterator, ParsedPacket, Poll};
+use ubx::proto_cfg::{CfgMsg, CfgRate, PortId, TimeRef};
+use ubx::proto_nav::{NavPacket, NavStatusPoll, SVInfoPoll, TimeGPS, TimeLS};
+use ubx::proto_tim::{TimPacket, TimePulse};
 
 const SSID: &'static str = env!("SSID");
 const PASS: &'static str = env!("PASS");
+/// Comma separated servers to fall back on when the receiver has no fix, e.g. "pool.ntp.org"
+const UPSTREAM: Option<&'static str> = option_env!("UPSTREAM_NTP");
+/// Delay between the time in a NAV-TIMEGPS frame and the frame going out, in microseconds
+const UBX_FIXED_LATENCY_US: Option<&'static str> = option_env!("UBX_FIXED_LATENCY_US");
+/// Smear leap seconds over this many hours, centred on midnight, instead of announcing them
+const LEAP_SMEAR_HOURS: Option<&'static str> = option_env!("LEAP_SMEAR_HOURS");
+/// How long to wait for the receiver to acknowledge configuration, and how often to resend it
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(1);
+const CONFIG_RETRIES: u32 = 3;
+/// Serial time takes over again when pulses stop for this long
+const PPS_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() -> std::io::Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -32,6 +58,8 @@ fn main() -> std::io::Result<()> {
     let modem = peripherals.modem;
     let tx = peripherals.pins.gpio15;
     let rx = peripherals.pins.gpio4;
+    // the receiver's timepulse output
+    let pps_pin = peripherals.pins.gpio18;
 
     let sclk = peripherals.pins.gpio25;
     let scs = peripherals.pins.gpio26;
@@ -61,29 +89,82 @@ fn main() -> std::io::Result<()> {
     max7219.shift_out(8, &[0b0011_0000, 0b0000_1111, 0b1111_0000, 0b1010_0101]);
     */
     max7219.render("1234567890123456");
-    let gpsserver = Arc::new(Mutex::new(GPSServer::new()));
+    let mut srv = GPSServer::new();
+    srv.set_clock_precision(measure_precision(clock::now));
+    if let Some(hours) = LEAP_SMEAR_HOURS {
+        let hours: u64 = hours.parse().expect("Bad LEAP_SMEAR_HOURS");
+        srv.set_leap_mode(LeapMode::Smear {
+            window: Duration::from_secs(hours * 60 * 60),
+        });
+    }
+    let gpsserver = Arc::new(Mutex::new(srv));
     let gpsserver2 = gpsserver.clone();
+    let gpsserver3 = gpsserver.clone();
+    let gpsserver4 = gpsserver.clone();
     let u = uart::Ublox::new(peripherals.uart1, tx, rx);
-
-    let _ = u.write(&disable_nmea(9600));
+    let acks = AckTracker::new();
 
     let (metric_tx, metric_rx) = mpsc::channel();
     let metric_tx2 = metric_tx.clone();
     let metric_tx3 = metric_tx.clone();
+    let (edge_tx, edge_rx) = mpsc::channel();
 
     let nvsp = EspDefaultNvsPartition::take().unwrap();
     let nvs = EspDefaultNvs::new(nvsp.clone(), "name", true).unwrap();
 
+    let ntp_nvs = EspDefaultNvs::new(nvsp.clone(), "ntp", true).unwrap();
+    let ntp_settings = ntp_settings::NtpSettings::with_nvs(ntp_nvs);
+    gpsserver.lock().unwrap().set_keys(ntp_settings.keys());
+    gpsserver
+        .lock()
+        .unwrap()
+        .set_restrictions(ntp_settings.restrictions());
+    let leap_table = Arc::new(Mutex::new(ntp_settings.leap_table()));
+    let leap_table2 = leap_table.clone();
+    let ntp_settings = Arc::new(Mutex::new(ntp_settings));
+    // GPS, its pulses and upstream servers all steer the clock through the one discipline
+    let steering = Arc::new(Mutex::new(steering::Steering::new(
+        ntp_settings.clone(),
+        metric_tx.clone(),
+    )));
+    let steering2 = steering.clone();
+
+    // next to the clock face's settings; the keys do not overlap
+    let gps_nvs = EspDefaultNvs::new(nvsp.clone(), "name", true).unwrap();
+    let receiver_state = ReceiverState::load(nvs_store::NvsStore::with_nvs(gps_nvs));
+    println!(
+        "last leap seconds {:?}, last good time {:?}",
+        receiver_state.leap_seconds(),
+        receiver_state.last_good()
+    );
+    let receiver_state = Arc::new(Mutex::new(receiver_state));
+    let receiver_state2 = receiver_state.clone();
+
     let c = clock_face::ClockFace::with_nvs(nvs);
     let clockm = Arc::new(Mutex::new(c));
     let clockm2 = clockm.clone();
 
     thread::scope(|s| {
         s.spawn(|| {
-            poll_ubx(&u);
+            poll_ubx(&u, &acks);
         });
         s.spawn(|| {
-            handle_ubx_feed(&u, gpsserver, metric_tx3);
+            handle_ubx_feed(
+                &u,
+                &acks,
+                gpsserver,
+                steering,
+                leap_table,
+                receiver_state,
+                edge_rx,
+                metric_tx3,
+            );
+        });
+        s.spawn(move || {
+            // without pulses the clock follows serial time alone
+            if let Err(e) = timepulse::capture_edges(pps_pin, edge_tx) {
+                println!("Could not capture timepulse edges: {:?}", e);
+            }
         });
 
         let metrics = Metrics::default();
@@ -101,13 +182,25 @@ fn main() -> std::io::Result<()> {
         let _w = wifi::configure(SSID, PASS, nvsp, modem).expect("Could not configure wifi");
         println!("Wifi is up");
 
+        if let Some(upstream) = UPSTREAM {
+            s.spawn(move || poll_upstream(upstream, gpsserver4, steering2));
+        }
+
         s.spawn(move || {
             println!("Handling NTP queries");
             handle_ntp_queries(gpsserver2, metric_tx2)
         });
 
         println!("Serving metrics");
-        let _h = http::server(metrics2, clockm).expect("Could not start up metrics server");
+        let _h = http::server(
+            metrics2,
+            clockm,
+            gpsserver3,
+            ntp_settings,
+            leap_table2,
+            receiver_state2,
+        )
+        .expect("Could not start up metrics server");
 
         loop {
             let now = clockm2.lock().unwrap().now();
@@ -120,88 +213,333 @@ fn main() -> std::io::Result<()> {
     })
 }
 
-fn poll_ubx(u: &uart::Ublox<'_>) {
+/// Moves the link to `uart::FAST_BAUD` and has the receiver output what handle_ubx_feed needs by
+/// itself, once per second navigation solution; polls for it if the receiver does not take the
+/// configuration
+fn poll_ubx(u: &uart::Ublox<'_>, acks: &AckTracker) {
+    // answers arrive through handle_ubx_feed
+    let res = acks.switch_baud(
+        |b| u.write(b),
+        |b| u.set_baud(b),
+        uart::BAUD,
+        uart::FAST_BAUD,
+        CONFIG_TIMEOUT,
+        CONFIG_RETRIES,
+    );
+    println!("Switching to {} baud: {:?}", uart::FAST_BAUD, res);
+    let rate = CfgRate {
+        measurement: Duration::from_secs(1),
+        measurements_per_solution: 1,
+        time_ref: TimeRef::GPS,
+    };
+    let output = |c, id, every| CfgMsg::on_port(c, id, PortId::UART1, every).packet();
+    let config = [
+        disable_nmea(u.baud()),
+        rate.packet(),
+        output(TimeGPS::class(), TimeGPS::id(), 5),
+        output(TimePulse::class(), TimePulse::id(), 5),
+        output(NavStatusPoll::class(), NavStatusPoll::id(), 5),
+        output(SVInfoPoll::class(), SVInfoPoll::id(), 5),
+        output(TimeLS::class(), TimeLS::id(), 60),
+    ];
+    let mut accepted = true;
+    for p in &config {
+        let res = acks.send_config(|b| u.write(b), p, CONFIG_TIMEOUT, CONFIG_RETRIES);
+        println!("Configuring {:?} {:#x}: {:?}", p.class, p.id, res);
+        accepted &= res.is_ok();
+    }
+    if accepted {
+        return;
+    }
     let buf = TimeGPS::frame();
     let buf2 = NavStatusPoll::frame();
     let buf3 = SVInfoPoll::frame();
+    let buf4 = TimePulse::frame();
+    let buf5 = TimeLS::frame();
     loop {
         let _ = u.write(&buf);
+        let _ = u.write(&buf4);
         thread::sleep(Duration::from_secs(1));
         let _ = u.write(&buf2);
         thread::sleep(Duration::from_secs(1));
         let _ = u.write(&buf3);
+        let _ = u.write(&buf5);
         thread::sleep(Duration::from_secs(3));
     }
 }
 fn handle_ubx_feed(
     u: &uart::Ublox<'_>,
+    acks: &AckTracker,
     gpsserver: Arc<Mutex<GPSServer>>,
+    steering: Arc<Mutex<steering::Steering>>,
+    leap_table: Arc<Mutex<LeapTable>>,
+    receiver_state: Arc<Mutex<ReceiverState<nvs_store::NvsStore>>>,
+    edges: mpsc::Receiver<DateTime<Utc>>,
     metrics: mpsc::Sender<Metric>,
 ) {
     let byte_iter = u.into_iter();
     let mut synced_once = false;
+    let mut latency = SerialLatency::new(uart::BAUD);
+    if let Some(us) = UBX_FIXED_LATENCY_US {
+        latency.fixed = Duration::from_micros(us.parse().expect("Bad UBX_FIXED_LATENCY_US"));
+    }
+    let mut pps = Pps::new(PpsConfig::default());
+    let mut last_pps: Option<Instant> = None;
+    let mut leap_sec: Option<i8> = None;
+    let mut accuracy = Duration::ZERO;
+    // without a fix the receiver keeps time with its battery backed RTC
+    let mut has_fix = false;
     for packet in PacketIterator::new(byte_iter) {
-        let pp = ParsedPacket::from(packet);
+        // how far UTC is ahead of the local clock, until it is corrected for a leap second
+        let leap_offset = {
+            let mut s = gpsserver.lock().unwrap();
+            if let Some(leap) = s.leap_due(clock::now()) {
+                // UTC moved, the clock's error did not: neither the discipline nor the drift
+                // estimate are told about this step
+                clock::set_time(clock::now() + leap.correction());
+                s.leap_applied();
+                println!("applied leap second {:?}", leap);
+            }
+            let now = clock::now();
+            s.leap_corrected(now) - now
+        };
+        let local = clock::now() + leap_offset;
+        latency.baud = u.baud();
+        let age = latency.age(&packet, Instant::now());
+        let pp = match ParsedPacket::try_from(packet) {
+            Ok(pp) => pp,
+            Err(e) => {
+                // pulses still waiting are paired with the next packet
+                println!("Could not decode packet: {:?}", e);
+                continue;
+            }
+        };
+        let mut paired: Vec<PpsSample> = edges
+            .try_iter()
+            .filter_map(|e| pps.edge(e + leap_offset))
+            .collect();
+        // while pulses arrive, serial time only labels them
+        let pps_active = last_pps.is_some_and(|t| t.elapsed() < PPS_TIMEOUT);
         match pp {
             ParsedPacket::Navigation(n) => match n {
                 NavPacket::TimeGPS(t) => {
                     metrics.send(Metric::Accuracy(t.accuracy)).unwrap();
-                    let now: Option<DateTime<Utc>> = t.into();
-                    if now.is_some() {
-                        let now = now.unwrap();
-                        let adj = (now - clock::now()).num_milliseconds();
-                        if synced_once {
-                            metrics.send(Metric::ClockAdjust(adj)).unwrap();
+                    accuracy = t.accuracy;
+                    let confirmed = t.leap_seconds().is_some();
+                    let mut receiver_state = receiver_state.lock().unwrap();
+                    // the receiver's count is only used once confirmed, or when it matches
+                    // the one confirmed before the last reboot
+                    let receiver = receiver_state.check_leap(t.leap_seconds_unchecked(), confirmed);
+                    let checked = t
+                        .table_leap_seconds(&leap_table.lock().unwrap())
+                        .or(receiver);
+                    if t.leap_seconds().is_some_and(|l| Some(l) != checked) {
+                        println!(
+                            "receiver says {:?} leap seconds, the table {:?}",
+                            t.leap_seconds(),
+                            checked
+                        );
+                    }
+                    leap_sec = checked.or(leap_sec);
+                    let gps = checked
+                        .and_then(|l| t.utc_with(l))
+                        .filter(|gps| receiver_state.plausible(*gps));
+                    if let Some(gps) = gps {
+                        // the time in the frame is `age` old by the time it is parsed
+                        let now = gps + chrono::TimeDelta::from_std(age).unwrap();
+                        let offset = now - local;
+                        if has_fix {
+                            if confirmed {
+                                receiver_state.good_time(gps);
+                            }
+                            paired.extend(pps.label(nearest_second(gps)));
+                            if synced_once {
+                                metrics
+                                    .send(Metric::ClockAdjust(offset.num_milliseconds()))
+                                    .unwrap();
+                            }
+                            synced_once = true;
+                            if !pps_active {
+                                gpsserver
+                                    .lock()
+                                    .unwrap()
+                                    .update_reference_time(now, t.accuracy);
+                                steering.lock().unwrap().steer(offset, Instant::now());
+                            }
+                        } else {
+                            let mut s = gpsserver.lock().unwrap();
+                            s.update_source(SourceUpdate {
+                                source: TimeSource::Rtc,
+                                at: local,
+                                offset,
+                                error: t.accuracy,
+                            });
+                            if s.selected(local).map(|u| u.source) == Some(TimeSource::Rtc) {
+                                steering.lock().unwrap().steer(offset, Instant::now());
+                            }
                         }
-                        synced_once = true;
-                        gpsserver.lock().unwrap().update_reference_time(now);
-                        clock::set_time(now);
                     }
                 }
                 NavPacket::Status(s) => {
+                    has_fix = s.fix.valid();
                     metrics.send(Metric::HasFix(s.fix.valid())).unwrap();
                     metrics.send(Metric::SensorUptime(s.uptime)).unwrap();
                 }
                 NavPacket::TimeUTC(_t) => {
                     println!("UTC");
                 }
+                NavPacket::LeapSecond(t) => {
+                    leap_sec = t.leap_seconds().or(leap_sec);
+                    if let Some((seconds, change)) = t.countdown() {
+                        gpsserver
+                            .lock()
+                            .unwrap()
+                            .leap_countdown(local, seconds, change);
+                    }
+                }
                 NavPacket::SVInfo(s) => {
                     metrics
                         .send(Metric::SatelliteCount(s.healthy_channels))
                         .unwrap();
                 }
             },
-            ParsedPacket::Nack => {}
+            ParsedPacket::Timing(TimPacket::TimePulse(tp)) => {
+                if let (Some(leap), true) = (leap_sec, has_fix) {
+                    paired.extend(pps.label(tp.time(leap)));
+                }
+            }
+            ParsedPacket::Ack(a) => acks.record(a),
             ParsedPacket::Configuration(c) => {
                 println!("Configuration, {:?}", c)
             }
+            ParsedPacket::Unknown(p) => {
+                println!("Unhandled packet class {:?} id {:#x}", p.class, p.id)
+            }
         }
+        for s in paired {
+            last_pps = Some(Instant::now());
+            // the offset at the edge still holds, up to the drift since
+            gpsserver
+                .lock()
+                .unwrap()
+                .update_reference_time(clock::now() + leap_offset + s.offset, accuracy);
+            steering.lock().unwrap().steer(s.offset, Instant::now());
+            metrics
+                .send(Metric::Pps {
+                    offset: s.offset,
+                    rejected: pps.rejected(),
+                })
+                .unwrap();
+        }
+    }
+}
+/// Feeds the upstream servers to the source selection, and steers the clock to them while
+/// one of them is selected
+fn poll_upstream(
+    upstream: &str,
+    s: Arc<Mutex<GPSServer>>,
+    steering: Arc<Mutex<steering::Steering>>,
+) {
+    loop {
+        for host in upstream.split(',').map(str::trim) {
+            let addr = match (host, 123).to_socket_addrs().map(|mut a| a.next()) {
+                Ok(Some(addr)) => addr,
+                _ => {
+                    println!("could not resolve {}", host);
+                    continue;
+                }
+            };
+            let sample = match ntp::client::query(addr, Duration::from_secs(1)) {
+                Ok(sample) => sample,
+                Err(e) => {
+                    println!("no answer from {}: {:?}", host, e);
+                    continue;
+                }
+            };
+            let delay = sample.delay.abs().to_std().unwrap_or(Duration::ZERO);
+            let update = SourceUpdate {
+                source: TimeSource::Ntp {
+                    addr: addr.ip(),
+                    stratum: sample.stratum,
+                },
+                at: clock::now(),
+                offset: sample.offset,
+                error: delay / 2 + sample.root_distance,
+            };
+            let mut srv = s.lock().unwrap();
+            srv.update_source(update);
+            if srv.selected(update.at).map(|u| u.source) == Some(update.source) {
+                steering
+                    .lock()
+                    .unwrap()
+                    .steer(update.offset, Instant::now());
+            }
+        }
+        // the default maxpoll of busy pools; they rate limit anything much faster
+        thread::sleep(Duration::from_secs(64));
     }
 }
+
 fn handle_ntp_queries(
     s: Arc<Mutex<GPSServer>>,
     metrics: mpsc::Sender<Metric>,
 ) -> std::io::Result<()> {
     let socket = UdpSocket::bind("0.0.0.0:123")?;
+    // on the heap, thread stacks are small and extension fields make queries large
+    let mut buf = vec![0; NTP_MAX_MESSAGE_LEN];
+    // the default table is a few kB, which the ESP can spare
+    let mut limiter = RateLimiter::new(RateLimitConfig::default());
     loop {
-        let mut buf = [0; 128]; // 48 should be enough
         let (amt, src) = socket.recv_from(&mut buf)?;
-        if amt != NTP_MESSAGE_LEN {
-            println!("bad, len was {}: {:?}", amt, &buf);
-            continue;
-        }
         metrics.send(Metric::ReceivedNtpQuery).unwrap();
         println!("pkt from {:?}", src);
-        let buf = &mut buf[..NTP_MESSAGE_LEN];
-        let q = NTPQuery::deserialize(buf).unwrap();
+        let action = s.lock().unwrap().restrictions.action(src.ip());
+        if action == Action::Ignore {
+            metrics
+                .send(Metric::RejectedNtpQuery("restricted"))
+                .unwrap();
+            continue;
+        }
+        let decision = limiter.check(src.ip(), Instant::now());
+        if decision == Decision::Drop {
+            metrics
+                .send(Metric::RejectedNtpQuery("rate_limited"))
+                .unwrap();
+            continue;
+        }
+        let q = match NTPMessage::deserialize(&buf[..amt]) {
+            Ok(q) => q,
+            Err(e) => {
+                println!("rejected packet from {:?}: {:?}", src, e);
+                metrics.send(Metric::RejectedNtpQuery(e.reason())).unwrap();
+                continue;
+            }
+        };
         let answer = {
             let srv = s.lock().unwrap();
-            if srv.reftime.is_some() {
-                metrics.send(Metric::AnsweredNtpQuery).unwrap();
-            }
             let now = clock::now();
-            srv.answer_query(q, now, now)
+            srv.answer_query(&q, &buf[..amt], now, now)
+        };
+        let mut answer = match answer {
+            Ok(a) => a,
+            Err(r) => {
+                println!("not answering {:?}: {:?}", src, r);
+                metrics.send(Metric::RejectedNtpQuery(r.reason())).unwrap();
+                continue;
+            }
         };
+        if action == Action::Kod {
+            answer.kiss_o_death(*b"DENY");
+            metrics.send(Metric::DenyKod).unwrap();
+        } else if decision == Decision::Kod {
+            answer.kiss_o_death(*b"RATE");
+            metrics.send(Metric::RateKod).unwrap();
+        } else if answer.peer_stratum != NTP_STRATUM_UNSYNCHRONIZED {
+            metrics.send(Metric::AnsweredNtpQuery).unwrap();
+        }
+        if answer.mac.as_ref().is_some_and(|m| m.is_crypto_nak()) {
+            metrics.send(Metric::CryptoNak).unwrap();
+        }
 
         let outbuf = answer.serialize();
         socket.send_to(&outbuf, src)?;
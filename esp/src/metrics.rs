@@ -1,3 +1,6 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use ntp::server::GPSServer;
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 const QUANTILES: [u8; 4] = [10, 50, 90, 99];
@@ -12,6 +15,27 @@ pub enum Metric {
     ClockAdjust(i64),
     ReceivedNtpQuery,
     AnsweredNtpQuery,
+    /// Reason the query was not answered
+    RejectedNtpQuery(&'static str),
+    /// A query failed authentication
+    CryptoNak,
+    /// A client polled too often and was told to slow down
+    RateKod,
+    /// A client was denied by an access rule
+    DenyKod,
+    /// State of the clock discipline loop after an update
+    Discipline {
+        offset: TimeDelta,
+        freq_ppm: f64,
+        time_constant: u8,
+    },
+    /// Frequency error of the oscillator, fitted over the last hour of adjustments
+    Drift(f64),
+    /// A timepulse edge was paired with its time
+    Pps {
+        offset: TimeDelta,
+        rejected: u32,
+    },
 }
 
 #[derive(Debug)]
@@ -68,8 +92,30 @@ pub struct Metrics {
     rcvd_ntp_queries: u32,
     /// counter
     answered_ntp_queries: u32,
+    /// counter, by reason
+    rejected_ntp_queries: BTreeMap<&'static str, u32>,
+    /// counter
+    crypto_naks: u32,
+    /// counter
+    rate_kods: u32,
+    /// counter
+    deny_kods: u32,
     /// gauge
     clock_adjust: QuantileMetric<i64>,
+    /// gauge
+    clock_offset: TimeDelta,
+    /// gauge
+    clock_freq_ppm: f64,
+    /// gauge, log2 seconds
+    clock_time_constant: u8,
+    /// gauge
+    clock_drift_ppm: Option<f64>,
+    /// gauge
+    pps_offset: Option<TimeDelta>,
+    /// counter
+    pps_pairs: u32,
+    /// counter
+    pps_rejected: u32,
 
     quantiles: Vec<u8>,
 }
@@ -87,9 +133,20 @@ impl Metrics {
             sat_count: QuantileMetric::new(30),
             accuracy: QuantileMetric::new(30),
             clock_adjust: QuantileMetric::new(30),
+            clock_offset: TimeDelta::zero(),
+            clock_freq_ppm: 0.0,
+            clock_time_constant: 0,
+            clock_drift_ppm: None,
+            pps_offset: None,
+            pps_pairs: 0,
+            pps_rejected: 0,
             has_fix: false,
             rcvd_ntp_queries: 0,
             answered_ntp_queries: 0,
+            rejected_ntp_queries: BTreeMap::new(),
+            crypto_naks: 0,
+            rate_kods: 0,
+            deny_kods: 0,
             uptime: Duration::default(),
         }
     }
@@ -102,6 +159,27 @@ impl Metrics {
             Metric::SensorUptime(n) => self.uptime = n,
             Metric::ReceivedNtpQuery => self.rcvd_ntp_queries += 1,
             Metric::AnsweredNtpQuery => self.answered_ntp_queries += 1,
+            Metric::RejectedNtpQuery(reason) => {
+                *self.rejected_ntp_queries.entry(reason).or_insert(0) += 1
+            }
+            Metric::CryptoNak => self.crypto_naks += 1,
+            Metric::RateKod => self.rate_kods += 1,
+            Metric::DenyKod => self.deny_kods += 1,
+            Metric::Discipline {
+                offset,
+                freq_ppm,
+                time_constant,
+            } => {
+                self.clock_offset = offset;
+                self.clock_freq_ppm = freq_ppm;
+                self.clock_time_constant = time_constant;
+            }
+            Metric::Drift(ppm) => self.clock_drift_ppm = Some(ppm),
+            Metric::Pps { offset, rejected } => {
+                self.pps_offset = Some(offset);
+                self.pps_pairs += 1;
+                self.pps_rejected = rejected;
+            }
         }
     }
 
@@ -137,6 +215,29 @@ impl Metrics {
             }
         }
 
+        ret.push(format!(
+            "esp_clock_offset_us {}",
+            self.clock_offset.num_microseconds().unwrap_or(i64::MAX)
+        ));
+        ret.push(format!(
+            "esp_clock_frequency_ppm {:.3}",
+            self.clock_freq_ppm
+        ));
+        ret.push(format!(
+            "esp_clock_time_constant {}",
+            self.clock_time_constant
+        ));
+        if let Some(ppm) = self.clock_drift_ppm {
+            ret.push(format!("esp_clock_drift_ppm {:.3}", ppm));
+        }
+        if let Some(offset) = self.pps_offset {
+            ret.push(format!(
+                "esp_pps_offset_us {}",
+                offset.num_microseconds().unwrap_or(i64::MAX)
+            ));
+        }
+        ret.push(format!("esp_pps_pairs {}", self.pps_pairs));
+        ret.push(format!("esp_pps_rejected {}", self.pps_rejected));
         ret.push(format!("esp_sensor_uptime_sec {}", self.uptime.as_secs()));
         ret.push(format!("esp_has_fix {}", self.has_fix as u8));
         ret.push(format!(
@@ -147,6 +248,33 @@ impl Metrics {
             "esp_answered_ntp_queries {}",
             self.answered_ntp_queries
         ));
+        ret.push(format!("esp_crypto_nak_responses {}", self.crypto_naks));
+        ret.push(format!("esp_rate_kod_responses {}", self.rate_kods));
+        ret.push(format!("esp_deny_kod_responses {}", self.deny_kods));
+        for (reason, count) in self.rejected_ntp_queries.iter() {
+            ret.push(format!(
+                r#"esp_rejected_ntp_queries{{reason="{reason}"}} {count}"#
+            ));
+        }
         ret
     }
 }
+
+/// The server state depends on the current time, so it is read at scrape time instead of being pushed
+pub fn server_state(srv: &GPSServer, now: DateTime<Utc>) -> Vec<String> {
+    let mut ret = vec![];
+    ret.push(format!(
+        r#"esp_ntp_sync_state{{state="{}"}} 1"#,
+        srv.state(now).name()
+    ));
+    if let Some(age) = srv.reference_age(now) {
+        ret.push(format!("esp_reference_age_sec {}", age.as_secs()));
+    }
+    if let Some(u) = srv.selected(now) {
+        ret.push(format!(
+            r#"esp_ntp_selected_source{{source="{}"}} 1"#,
+            u.source.name()
+        ));
+    }
+    ret
+}
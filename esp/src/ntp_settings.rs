@@ -0,0 +1,140 @@
+use esp_idf_svc::nvs::EspDefaultNvs;
+use ntp::auth::KeyTable;
+use ntp::leapfile::LeapTable;
+use ntp::restrict::Restrictions;
+
+/// NTP server settings which are editable from the web UI and survive reboots
+pub struct NtpSettings {
+    nvs: EspDefaultNvs,
+}
+
+impl NtpSettings {
+    const KEYS_KEY: &'static str = "ntp_keys";
+    const RESTRICT_KEY: &'static str = "ntp_restrict";
+    const DRIFT_KEY: &'static str = "ntp_drift";
+    const LEAP_KEY: &'static str = "ntp_leap";
+
+    pub fn with_nvs(nvs: EspDefaultNvs) -> NtpSettings {
+        NtpSettings { nvs }
+    }
+
+    fn get_str(&self, key: &str) -> String {
+        let mut buf: &mut [u8] = &mut [0; 1024];
+        match self.nvs.get_str(key, &mut buf) {
+            Ok(Some(s)) => s.to_string(),
+            Ok(None) => String::new(),
+            Err(e) => {
+                println!("Could not read {} from nvs: {}", key, e);
+                String::new()
+            }
+        }
+    }
+
+    /// Keys are stored in the ntpd keys file format
+    pub fn keys(&self) -> KeyTable {
+        match KeyTable::parse(&self.get_str(Self::KEYS_KEY)) {
+            Ok(k) => k,
+            Err(e) => {
+                println!("Stored keys are not valid: {:?}", e);
+                KeyTable::new()
+            }
+        }
+    }
+
+    /// Only valid keys are stored
+    pub fn set_keys(&mut self, text: &str) -> Option<KeyTable> {
+        let keys = match KeyTable::parse(text) {
+            Ok(k) => k,
+            Err(e) => {
+                println!("Not storing invalid keys: {:?}", e);
+                return None;
+            }
+        };
+        let res = self.nvs.set_str(Self::KEYS_KEY, text);
+        println!("Storing keys res = {:?}", res);
+        Some(keys)
+    }
+
+    /// Everyone is served until rules are stored
+    pub fn restrictions(&self) -> Restrictions {
+        match Restrictions::parse(&self.get_str(Self::RESTRICT_KEY)) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Stored restrictions are not valid: {:?}", e);
+                Restrictions::new()
+            }
+        }
+    }
+
+    /// Only valid rules are stored
+    pub fn set_restrictions(&mut self, text: &str) -> Option<Restrictions> {
+        let rules = match Restrictions::parse(text) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Not storing invalid restrictions: {:?}", e);
+                return None;
+            }
+        };
+        let res = self.nvs.set_str(Self::RESTRICT_KEY, text);
+        println!("Storing restrictions res = {:?}", res);
+        Some(rules)
+    }
+
+    /// The uploaded leap-seconds.list while it is newer than the built-in one
+    pub fn leap_table(&self) -> LeapTable {
+        let builtin = LeapTable::builtin();
+        let text = self.get_str(Self::LEAP_KEY);
+        if text.is_empty() {
+            return builtin;
+        }
+        match LeapTable::parse(&text) {
+            Ok(t) if t.expires > builtin.expires => t,
+            Ok(_) => builtin,
+            Err(e) => {
+                println!("Stored leap seconds list is not valid: {:?}", e);
+                builtin
+            }
+        }
+    }
+
+    /// Only a valid list which expires after `current` is stored, without its comments
+    pub fn set_leap_table(&mut self, text: &str, current: &LeapTable) -> Option<LeapTable> {
+        let table = match LeapTable::parse(text) {
+            Ok(t) if t.expires > current.expires => t,
+            Ok(t) => {
+                println!(
+                    "Not storing a list expiring {}, before {}",
+                    t.expires, current.expires
+                );
+                return None;
+            }
+            Err(e) => {
+                println!("Not storing invalid leap seconds list: {:?}", e);
+                return None;
+            }
+        };
+        let res = self.nvs.set_str(Self::LEAP_KEY, &LeapTable::compact(text));
+        println!("Storing leap seconds list res = {:?}", res);
+        Some(table)
+    }
+
+    /// Frequency error of the oscillator in ppm, stored as text like ntpd's drift file
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let text = self.get_str(Self::DRIFT_KEY);
+        if text.is_empty() {
+            return None;
+        }
+        match text.trim().parse() {
+            Ok(ppm) => Some(ppm),
+            Err(e) => {
+                println!("Stored drift is not valid: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn set_drift_ppm(&mut self, ppm: f64) {
+        let res = self.nvs.set_str(Self::DRIFT_KEY, &format!("{:.3}", ppm));
+        println!("Storing drift res = {:?}", res);
+    }
+}
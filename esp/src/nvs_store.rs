@@ -0,0 +1,31 @@
+use esp_idf_svc::nvs::EspDefaultNvs;
+use ntp::persist::KeyValue;
+
+/// NVS namespace as a `KeyValue`, values are stored as strings
+pub struct NvsStore {
+    nvs: EspDefaultNvs,
+}
+
+impl NvsStore {
+    pub fn with_nvs(nvs: EspDefaultNvs) -> NvsStore {
+        NvsStore { nvs }
+    }
+}
+
+impl KeyValue for NvsStore {
+    fn get(&self, key: &str) -> Option<String> {
+        let mut buf: &mut [u8] = &mut [0; 64];
+        match self.nvs.get_str(key, &mut buf) {
+            Ok(s) => s.map(str::to_string),
+            Err(e) => {
+                println!("Could not read {} from nvs: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let res = self.nvs.set_str(key, value);
+        println!("Storing {}={} res = {:?}", key, value, res);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::clock;
+use crate::metrics::Metric;
+use crate::ntp_settings::NtpSettings;
+use chrono::TimeDelta;
+use ntp::discipline::{Adjustment, Discipline, DisciplineConfig};
+use ntp::drift::{DriftConfig, DriftEstimator};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the drift estimate is saved, flash wears out
+const DRIFT_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Steers the local clock from offset measurements, whichever source they come from
+pub struct Steering {
+    discipline: Discipline,
+    drift: DriftEstimator,
+    settings: Arc<Mutex<NtpSettings>>,
+    saved_drift: Option<f64>,
+    last_save: Instant,
+    metrics: mpsc::Sender<Metric>,
+}
+
+impl Steering {
+    /// Starts from the drift estimate saved in `settings`, if any
+    pub fn new(settings: Arc<Mutex<NtpSettings>>, metrics: mpsc::Sender<Metric>) -> Steering {
+        let saved_drift = settings.lock().unwrap().drift_ppm();
+        let discipline = Discipline::with_frequency(
+            DisciplineConfig {
+                // the RTC was seen drifting ~10ms every 5s, see the README
+                max_freq_ppm: 2500.0,
+                ..Default::default()
+            },
+            saved_drift.unwrap_or(0.0),
+        );
+        Steering {
+            discipline,
+            drift: DriftEstimator::new(DriftConfig::default()),
+            settings,
+            saved_drift,
+            last_save: Instant::now(),
+            metrics,
+        }
+    }
+
+    /// `offset` is reference time minus local time, measured at `at`
+    pub fn steer(&mut self, offset: TimeDelta, at: Instant) {
+        self.drift.sample(offset, at);
+        match self.discipline.update(offset, at) {
+            Adjustment::Step(d) => {
+                clock::set_time(clock::now() + d);
+                self.drift.correction(d);
+            }
+            Adjustment::Slew(d) => {
+                clock::adjtime(d);
+                self.drift.correction(d);
+            }
+            Adjustment::Ignore => println!("ignoring offset {}", offset),
+        }
+        if let Some(ppm) = self.drift.ppm() {
+            self.metrics.send(Metric::Drift(ppm)).unwrap();
+            let moved = self.saved_drift.map_or(true, |s| (s - ppm).abs() > 0.1);
+            if moved && at - self.last_save > DRIFT_SAVE_INTERVAL {
+                self.settings.lock().unwrap().set_drift_ppm(ppm);
+                self.saved_drift = Some(ppm);
+                self.last_save = at;
+            }
+        }
+        self.metrics
+            .send(Metric::Discipline {
+                offset: self.discipline.offset(),
+                freq_ppm: self.discipline.freq_ppm(),
+                time_constant: self.discipline.time_constant(),
+            })
+            .unwrap();
+    }
+}
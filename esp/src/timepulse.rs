@@ -0,0 +1,43 @@
+use crate::clock;
+use chrono::{DateTime, TimeDelta, Utc};
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::gpio::{InputPin, InterruptType, OutputPin, PinDriver, Pull};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::sys::EspError;
+use esp_idf_hal::task::notification::Notification;
+use esp_idf_svc::sys;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc;
+
+/// esp_timer time of the last edge, in microseconds since boot; the realtime clock takes a lock,
+/// which can't be done in an ISR
+static EDGE_US: AtomicI64 = AtomicI64::new(0);
+
+/// Sends the local time of every rising edge on `pin` to `edges`
+pub fn capture_edges<P: InputPin + OutputPin>(
+    pin: impl Peripheral<P = P>,
+    edges: mpsc::Sender<DateTime<Utc>>,
+) -> Result<(), EspError> {
+    let mut pin = PinDriver::input(pin)?;
+    pin.set_pull(Pull::Down)?;
+    pin.set_interrupt_type(InterruptType::PosEdge)?;
+    let notification = Notification::new();
+    let notifier = notification.notifier();
+    unsafe {
+        pin.subscribe(move || {
+            EDGE_US.store(sys::esp_timer_get_time(), Ordering::Relaxed);
+            notifier.notify_and_yield(NonZeroU32::new(1).unwrap());
+        })?;
+    }
+    loop {
+        // interrupts are disabled after each one fires
+        pin.enable_interrupt()?;
+        notification.wait(BLOCK);
+        let since_edge = unsafe { sys::esp_timer_get_time() } - EDGE_US.load(Ordering::Relaxed);
+        let edge = clock::now() - TimeDelta::microseconds(since_edge);
+        if edges.send(edge).is_err() {
+            return Ok(());
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use esp_idf_hal::delay::{BLOCK, NON_BLOCK};
 use esp_idf_hal::gpio::{Gpio0, Gpio1, InputPin, OutputPin};
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_hal::sys::EspError;
@@ -6,9 +7,22 @@ use esp_idf_hal::uart::Uart;
 use esp_idf_hal::uart::UartDriver;
 use esp_idf_hal::units::Hertz;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use ubx::latency::{backdate, transmission_time};
+
+/// The receiver's rate after a reset
+pub const BAUD: u32 = 9600;
+/// The rate `poll_ubx` moves the link to
+pub const FAST_BAUD: u32 = 115200;
+/// The driver hands received bytes over once the line has been idle for this many byte times,
+/// UART_TOUT_THRESH_DEFAULT
+const RX_TIMEOUT_BYTES: usize = 10;
 
 pub struct Ublox<'d> {
     pub(crate) u: UartDriver<'d>,
+    /// Changes while the reader dates bytes with it
+    baud: AtomicU32,
 }
 
 impl<'d> Ublox<'d> {
@@ -23,10 +37,25 @@ impl<'d> Ublox<'d> {
             rx,
             Option::<Gpio0>::None,
             Option::<Gpio1>::None,
-            &Config::new().baudrate(Hertz(9600)),
+            &Config::new().baudrate(Hertz(BAUD)),
         )
         .expect("Can't set up UartDriver");
-        Ublox { u }
+        Ublox {
+            u,
+            baud: AtomicU32::new(BAUD),
+        }
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud.load(Ordering::Relaxed)
+    }
+
+    /// Changes the local rate once everything written so far went out
+    pub fn set_baud(&self, baud: u32) -> Result<(), EspError> {
+        self.u.wait_tx_done(BLOCK)?;
+        self.u.change_baudrate(Hertz(baud))?;
+        self.baud.store(baud, Ordering::Relaxed);
+        Ok(())
     }
 
     pub fn into_iter(&'d self) -> UbloxIterator<'d> {
@@ -41,23 +70,28 @@ impl<'d> Ublox<'d> {
     }
 }
 
+/// Yields each byte with when it arrived, going by when the driver handed it over and the baud
+/// rate
 pub struct UbloxIterator<'a> {
-    buf: VecDeque<u8>,
+    buf: VecDeque<(u8, Instant)>,
     u: &'a Ublox<'a>,
 }
 
 impl<'a> Iterator for UbloxIterator<'a> {
-    type Item = u8;
-    fn next(&mut self) -> Option<u8> {
+    type Item = (u8, Instant);
+    fn next(&mut self) -> Option<(u8, Instant)> {
         if let Some(b) = self.buf.pop_front() {
             return Some(b);
         }
-        let mut read: usize = 0;
         let mut buf = vec![0 as u8; 128];
-        while read == 0 {
-            read = self.u.u.read(buf.as_mut_slice(), 20).unwrap();
-        }
-        self.buf.extend(&buf);
+        // returns as soon as the driver has anything, the rest of the burst is read below
+        while self.u.u.read(&mut buf[..1], BLOCK).unwrap() == 0 {}
+        let handed_over = Instant::now();
+        let pending = self.u.u.remaining_read().unwrap().min(buf.len() - 1);
+        let read = 1 + self.u.u.read(&mut buf[1..1 + pending], NON_BLOCK).unwrap();
+        let baud = self.u.baud();
+        let last_at = handed_over - transmission_time(RX_TIMEOUT_BYTES, baud);
+        self.buf.extend(backdate(&buf[..read], last_at, baud));
         self.buf.pop_front()
     }
 }
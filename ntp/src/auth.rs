@@ -0,0 +1,232 @@
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+
+/// Symmetric key MAC algorithms, RFC 5905 and RFC 8573
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    AesCmac,
+}
+
+impl Algorithm {
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::AesCmac => 16,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    /// Line number of a line that does not look like `keyid type key`
+    Malformed(usize),
+    /// Key id 0 is reserved for crypto-NAKs
+    BadKeyId(usize),
+    UnknownAlgorithm(usize),
+    /// AES-128-CMAC needs exactly 16 bytes of key
+    BadKeyLength(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Key {
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl Key {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            Algorithm::Md5 => {
+                let mut h = Md5::new();
+                h.update(&self.secret);
+                h.update(data);
+                h.finalize().to_vec()
+            }
+            Algorithm::Sha1 => {
+                let mut h = Sha1::new();
+                h.update(&self.secret);
+                h.update(data);
+                h.finalize().to_vec()
+            }
+            Algorithm::AesCmac => {
+                // the key length is validated when the key is loaded
+                let mut m = <Cmac<Aes128>>::new_from_slice(&self.secret).unwrap();
+                m.update(data);
+                m.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Constant time comparison against a received digest
+    pub fn verify(&self, data: &[u8], digest: &[u8]) -> bool {
+        let expected = self.digest(data);
+        if expected.len() != digest.len() {
+            return false;
+        }
+        expected
+            .iter()
+            .zip(digest)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+/// key id -> key
+#[derive(Debug, Default, Clone)]
+pub struct KeyTable {
+    keys: HashMap<u32, Key>,
+}
+
+impl KeyTable {
+    pub fn new() -> KeyTable {
+        KeyTable::default()
+    }
+
+    pub fn insert(&mut self, key_id: u32, key: Key) {
+        self.keys.insert(key_id, key);
+    }
+
+    pub fn get(&self, key_id: u32) -> Option<&Key> {
+        self.keys.get(&key_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Parses an ntpd style keys file; each line is `keyid type key`, with `#` comments.
+    /// Keys longer than 20 characters are hex encoded, shorter ones are used verbatim as ASCII.
+    pub fn parse(text: &str) -> Result<KeyTable, KeyError> {
+        let mut table = KeyTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            // ntpd allows a trailing list of addresses which may use the key; it is ignored
+            if parts.len() < 3 {
+                return Err(KeyError::Malformed(lineno));
+            }
+            let key_id: u32 = parts[0].parse().map_err(|_| KeyError::BadKeyId(lineno))?;
+            if key_id == 0 {
+                return Err(KeyError::BadKeyId(lineno));
+            }
+            let algorithm = match parts[1].to_ascii_uppercase().as_str() {
+                "M" | "MD5" => Algorithm::Md5,
+                "SHA1" | "SHA-1" => Algorithm::Sha1,
+                "AES128CMAC" | "AES-128-CMAC" | "AES" => Algorithm::AesCmac,
+                _ => return Err(KeyError::UnknownAlgorithm(lineno)),
+            };
+            let secret = match parse_hex(parts[2]) {
+                Some(bytes) if parts[2].len() > 20 => bytes,
+                _ => parts[2].as_bytes().to_vec(),
+            };
+            if algorithm == Algorithm::AesCmac && secret.len() != 16 {
+                return Err(KeyError::BadKeyLength(lineno));
+            }
+            table.insert(key_id, Key { algorithm, secret });
+        }
+        Ok(table)
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = "
+# ntp.keys
+1 M secret  # a comment
+2 SHA1 0123456789abcdef0123456789abcdef01234567
+3 AES128CMAC 000102030405060708090a0b0c0d0e0f
+";
+
+    #[test]
+    fn parse_keys_file() {
+        let t = KeyTable::parse(KEYS).unwrap();
+        let k = t.get(1).unwrap();
+        assert_eq!(k.algorithm, Algorithm::Md5);
+        assert_eq!(k.secret, b"secret");
+        let k = t.get(2).unwrap();
+        assert_eq!(k.algorithm, Algorithm::Sha1);
+        assert_eq!(k.secret.len(), 20);
+        assert_eq!(k.secret[..2], [0x01, 0x23]);
+        assert_eq!(t.get(3).unwrap().algorithm, Algorithm::AesCmac);
+        assert!(t.get(4).is_none());
+    }
+
+    #[test]
+    fn parse_keys_errors() {
+        assert_eq!(KeyTable::parse("1 M").unwrap_err(), KeyError::Malformed(1));
+        assert_eq!(
+            KeyTable::parse("\n0 M secret").unwrap_err(),
+            KeyError::BadKeyId(2)
+        );
+        assert_eq!(
+            KeyTable::parse("1 DES secret").unwrap_err(),
+            KeyError::UnknownAlgorithm(1)
+        );
+        assert_eq!(
+            KeyTable::parse("1 AES128CMAC short").unwrap_err(),
+            KeyError::BadKeyLength(1)
+        );
+    }
+
+    #[test]
+    fn digests() {
+        let key = |algorithm, secret: &str| Key {
+            algorithm,
+            secret: parse_hex(secret).unwrap(),
+        };
+        let md5 = Key {
+            algorithm: Algorithm::Md5,
+            secret: b"key".to_vec(),
+        };
+        assert_eq!(
+            md5.digest(b"data"),
+            parse_hex("3f13977a2262dae86874aee1610c7e6d").unwrap()
+        );
+        let sha1 = Key {
+            algorithm: Algorithm::Sha1,
+            secret: b"key".to_vec(),
+        };
+        assert_eq!(
+            sha1.digest(b"data"),
+            parse_hex("18cd09d4f5389bbebf50df12209de3ae63de3eaf").unwrap()
+        );
+        // RFC 4493 test vectors
+        let cmac = key(Algorithm::AesCmac, "2b7e151628aed2a6abf7158809cf4f3c");
+        assert_eq!(
+            cmac.digest(b""),
+            parse_hex("bb1d6929e95937287fa37d129b756746").unwrap()
+        );
+        let msg = parse_hex("6bc1bee22e409f96e93d7e117393172a").unwrap();
+        let expected = parse_hex("070a16b46b4d4144f79bdd9dd04a287c").unwrap();
+        assert_eq!(cmac.digest(&msg), expected);
+        assert!(cmac.verify(&msg, &expected));
+        assert!(!cmac.verify(&msg, &expected[1..]));
+        assert!(!cmac.verify(b"other", &expected));
+    }
+}
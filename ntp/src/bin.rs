@@ -1,37 +1,140 @@
 use chrono::{offset::Local, Utc};
+use ntp::auth::KeyTable;
+#[cfg(feature = "nts")]
+use ntp::nts::{CookieJar, NTS_KE_PORT};
 use ntp::proto::*;
-use ntp::server::GPSServer;
+use ntp::ratelimit::{Decision, RateLimitConfig, RateLimiter};
+use ntp::restrict::{Action, Restrictions};
+use ntp::server::{measure_precision, GPSServer};
+use std::env;
+use std::fs;
 use std::net::UdpSocket;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+struct Args {
+    /// ntpd style keys file, for queries carrying a MAC
+    keys: Option<String>,
+    /// Access rules, see `Restrictions::parse`
+    restrict: Option<String>,
+    /// PEM certificate chain and key; enables NTS-KE
+    nts_cert: Option<String>,
+    nts_key: Option<String>,
+}
+
+fn parse_args() -> Args {
+    let usage =
+        "usage: ntp_server [--keys FILE] [--restrict FILE] [--nts-cert FILE --nts-key FILE] [KEYS]";
+    let mut args = Args {
+        keys: None,
+        restrict: None,
+        nts_cert: None,
+        nts_key: None,
+    };
+    let mut it = env::args().skip(1);
+    while let Some(flag) = it.next() {
+        // a bare argument is the keys file, as before there were flags
+        if !flag.starts_with("--") && args.keys.is_none() {
+            args.keys = Some(flag);
+            continue;
+        }
+        let value = Some(it.next().expect(usage));
+        match flag.as_str() {
+            "--keys" => args.keys = value,
+            "--restrict" => args.restrict = value,
+            "--nts-cert" => args.nts_cert = value,
+            "--nts-key" => args.nts_key = value,
+            _ => panic!("{}", usage),
+        }
+    }
+    args
+}
 
 fn main() -> std::io::Result<()> {
-    let s = Arc::new(Mutex::new(GPSServer::new()));
+    let args = parse_args();
+    let mut srv = GPSServer::new();
+    srv.set_clock_precision(measure_precision(|| Local::now().with_timezone(&Utc)));
+    if let Some(path) = args.keys {
+        let keys = KeyTable::parse(&fs::read_to_string(path)?).expect("Could not parse keys");
+        srv.set_keys(keys);
+    }
+    if let Some(path) = args.restrict {
+        let rules = fs::read_to_string(path)?;
+        srv.set_restrictions(Restrictions::parse(&rules).expect("Could not parse restrictions"));
+    }
+    match (args.nts_cert, args.nts_key) {
+        #[cfg(feature = "nts")]
+        (Some(cert), Some(key)) => {
+            let config = ntp::nts_ke::server_config(&fs::read(cert)?, &fs::read(key)?)?;
+            let jar = CookieJar::new();
+            srv.set_cookie_jar(jar.clone());
+            let listener = std::net::TcpListener::bind(("0.0.0.0", NTS_KE_PORT))?;
+            thread::spawn(move || ntp::nts_ke::serve(listener, config, jar));
+        }
+        (None, None) => (),
+        _ => panic!("NTS needs both --nts-cert and --nts-key, and the nts feature"),
+    }
+    let s = Arc::new(Mutex::new(srv));
     let s2 = s.clone();
 
     thread::spawn(move || handle_ntp_queries(s2));
     loop {
         let now = Local::now().with_timezone(&Utc);
-        s.lock().unwrap().update_reference_time(now);
+        // the local clock is the reference
+        s.lock().unwrap().update_reference_time(now, Duration::ZERO);
         std::thread::sleep(Duration::from_millis(1000));
     }
 }
 
 fn handle_ntp_queries(s: Arc<Mutex<GPSServer>>) -> std::io::Result<()> {
     let socket = UdpSocket::bind("127.0.0.1:123")?;
+    let mut buf = vec![0; NTP_MAX_MESSAGE_LEN];
+    let mut limiter = RateLimiter::new(RateLimitConfig::default());
     loop {
-        let mut buf = [0; 128];
         let (amt, src) = socket.recv_from(&mut buf)?;
-        if amt != NTP_MESSAGE_LEN {
-            println!("bad, len was {}: {:?}", amt, &buf);
-            continue;
-        }
         let now = Local::now().with_timezone(&Utc);
         println!("pkt from {:?}", src);
-        let buf = &mut buf[..NTP_MESSAGE_LEN];
-        let q = NTPQuery::deserialize(buf).unwrap();
-        let a = s.lock().unwrap().answer_query(q, now, now);
+        let action = s.lock().unwrap().restrictions.action(src.ip());
+        if action == Action::Ignore {
+            println!("ignoring {:?}", src);
+            continue;
+        }
+        let decision = limiter.check(src.ip(), Instant::now());
+        if decision == Decision::Drop {
+            println!("rate limiting {:?}, dropping packet", src);
+            continue;
+        }
+        let q = match NTPMessage::deserialize(&buf[..amt]) {
+            Ok(q) => q,
+            Err(e) => {
+                println!("rejected packet from {:?}: {:?}", src, e);
+                continue;
+            }
+        };
+        let a = {
+            let srv = s.lock().unwrap();
+            println!("server is {}", srv.state(now).name());
+            srv.answer_query(&q, &buf[..amt], now, now)
+        };
+        let mut a = match a {
+            Ok(a) => a,
+            Err(r) => {
+                println!("not answering {:?}: {:?}", src, r);
+                continue;
+            }
+        };
+
+        if action == Action::Kod {
+            println!("denying {:?}", src);
+            a.kiss_o_death(*b"DENY");
+        } else if decision == Decision::Kod {
+            println!("rate limiting {:?}, sending kiss-o'-death", src);
+            a.kiss_o_death(*b"RATE");
+        }
+        if a.mac.as_ref().is_some_and(|m| m.is_crypto_nak()) {
+            println!("query from {:?} failed authentication", src);
+        }
 
         let outbuf = a.serialize();
         socket.send_to(&outbuf, src)?;
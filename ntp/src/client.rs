@@ -0,0 +1,245 @@
+//! SNTP client, RFC 4330
+use crate::proto::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One measurement against a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// How far the server's clock is ahead of ours
+    pub offset: TimeDelta,
+    /// Round trip time, minus the time the server held on to the query
+    pub delay: TimeDelta,
+    /// The server's own distance to its reference: half its root delay plus its root dispersion
+    pub root_distance: Duration,
+    pub stratum: u8,
+    pub ref_id: Reference,
+    pub leap: LeapIndicator,
+}
+
+impl Sample {
+    /// From the query's transmit time `t1`, the server's receive and transmit times `t2` and
+    /// `t3`, and the response's arrival `t4`
+    pub fn from_timestamps(
+        t1: DateTime<Utc>,
+        t2: DateTime<Utc>,
+        t3: DateTime<Utc>,
+        t4: DateTime<Utc>,
+    ) -> (TimeDelta, TimeDelta) {
+        let offset = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay = (t4 - t1) - (t3 - t2);
+        (offset, delay)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Parse(ParseError),
+    /// The response is not a server response to the query we sent
+    Bogus,
+    /// The server sent a kiss-o'-death with this code, e.g. RATE or DENY
+    KissOfDeath([u8; 4]),
+    /// The server has no time to give: stratum 16 or the alarm leap indicator
+    Unsynchronized,
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ParseError> for ClientError {
+    fn from(e: ParseError) -> Self {
+        ClientError::Parse(e)
+    }
+}
+
+/// The host clock; chrono's `clock` feature is not enabled for this crate
+pub fn system_now() -> DateTime<Utc> {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    DateTime::from_timestamp(d.as_secs() as i64, d.subsec_nanos()).unwrap()
+}
+
+fn client_query(t1: DateTime<Utc>) -> NTPMessage {
+    let zero = NTPTimestamp::from(ntp_zero());
+    NTPMessage {
+        flags: NTPFlags {
+            l: LeapIndicator::NoWarning,
+            v: VersionNumber(NTP_VERSION),
+            m: Mode::Client,
+        },
+        peer_stratum: 0,
+        peer_polling_interval: 0,
+        peer_clock_precision: PeerPrecision(0),
+        root_delay: Fix32 { i: 0, f: 0 },
+        root_dispersion: Fix32 { i: 0, f: 0 },
+        ref_id: Reference::Ascii([0; 4]),
+        ref_tstamp: zero,
+        origin_tstamp: zero,
+        rcv_tstamp: zero,
+        transmit_tstamp: NTPTimestamp::from(t1),
+        extensions: vec![],
+        mac: None,
+    }
+}
+
+/// Checks `response` answers `query`, which was sent at `t1`; `t4` is when the response arrived
+fn sample(
+    query: &NTPMessage,
+    response: &NTPMessage,
+    t1: DateTime<Utc>,
+    t4: DateTime<Utc>,
+) -> Result<Sample, ClientError> {
+    if response.flags.m != Mode::Server || response.origin_tstamp != query.transmit_tstamp {
+        return Err(ClientError::Bogus);
+    }
+    if response.peer_stratum == 0 {
+        let code = match response.ref_id {
+            Reference::Ascii(code) => code,
+            _ => [0; 4],
+        };
+        return Err(ClientError::KissOfDeath(code));
+    }
+    if response.peer_stratum >= NTP_STRATUM_UNSYNCHRONIZED
+        || response.flags.l == LeapIndicator::Alarm
+    {
+        return Err(ClientError::Unsynchronized);
+    }
+    let t2 = DateTime::<Utc>::from(response.rcv_tstamp);
+    let t3 = DateTime::<Utc>::from(response.transmit_tstamp);
+    let (offset, delay) = Sample::from_timestamps(t1, t2, t3, t4);
+    let root_distance = f32::from(response.root_delay) / 2.0 + f32::from(response.root_dispersion);
+    Ok(Sample {
+        offset,
+        delay,
+        root_distance: Duration::from_secs_f32(root_distance.max(0.0)),
+        stratum: response.peer_stratum,
+        ref_id: response.ref_id.clone(),
+        leap: response.flags.l,
+    })
+}
+
+/// Sends a single query over `socket` and waits for the answer, timestamping with `clock`
+pub fn query_with(
+    socket: &UdpSocket,
+    clock: impl Fn() -> DateTime<Utc>,
+) -> Result<Sample, ClientError> {
+    let t1 = clock();
+    let q = client_query(t1);
+    socket.send(&q.serialize())?;
+    let mut buf = vec![0; NTP_MAX_MESSAGE_LEN];
+    let amt = socket.recv(&mut buf)?;
+    let t4 = clock();
+    let response = NTPMessage::deserialize(&buf[..amt])?;
+    sample(&q, &response, t1, t4)
+}
+
+/// Queries the server at `addr` once, using the host clock
+pub fn query(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Sample, ClientError> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address to query"))?;
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(addr)?;
+    query_with(&socket, system_now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::GPSServer;
+    use chrono::TimeZone;
+    use std::thread;
+
+    fn t0() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn offset_and_delay() {
+        let ms = TimeDelta::milliseconds;
+        // the server is 100ms ahead, 10ms each way on the wire, 1ms of processing
+        let (offset, delay) =
+            Sample::from_timestamps(t0(), t0() + ms(110), t0() + ms(111), t0() + ms(21));
+        assert_eq!(offset, ms(100));
+        assert_eq!(delay, ms(20));
+
+        // and 100ms behind, with an asymmetric path
+        let (offset, delay) =
+            Sample::from_timestamps(t0(), t0() - ms(90), t0() - ms(90), t0() + ms(30));
+        assert_eq!(offset, ms(-105));
+        assert_eq!(delay, ms(30));
+    }
+
+    #[test]
+    fn rejects_bogus_responses() {
+        let q = client_query(t0());
+        let mut srv = GPSServer::new();
+        srv.update_reference_time(t0(), Duration::ZERO);
+        let mut a = srv.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        assert!(sample(&q, &a, t0(), t0()).is_ok());
+
+        a.origin_tstamp = NTPTimestamp::from(t0() + TimeDelta::seconds(1));
+        assert!(matches!(
+            sample(&q, &a, t0(), t0()),
+            Err(ClientError::Bogus)
+        ));
+
+        let mut a = srv.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        a.kiss_o_death(*b"RATE");
+        assert!(matches!(
+            sample(&q, &a, t0(), t0()),
+            Err(ClientError::KissOfDeath(code)) if &code == b"RATE"
+        ));
+
+        // a server which lost its reference answers, but with nothing to use
+        let mut a = srv.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        a.peer_stratum = NTP_STRATUM_UNSYNCHRONIZED;
+        assert!(matches!(
+            sample(&q, &a, t0(), t0()),
+            Err(ClientError::Unsynchronized)
+        ));
+        let mut a = srv.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        a.flags.l = LeapIndicator::Alarm;
+        assert!(matches!(
+            sample(&q, &a, t0(), t0()),
+            Err(ClientError::Unsynchronized)
+        ));
+    }
+
+    #[test]
+    fn query_local_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut srv = GPSServer::new();
+            // one second ahead of the host clock
+            let ahead = || system_now() + TimeDelta::seconds(1);
+            srv.update_reference_time(ahead(), Duration::ZERO);
+            let mut buf = [0; NTP_MAX_MESSAGE_LEN];
+            let (amt, src) = server.recv_from(&mut buf).unwrap();
+            let q = NTPMessage::deserialize(&buf[..amt]).unwrap();
+            let a = srv
+                .answer_query(&q, &q.serialize(), ahead(), ahead())
+                .unwrap();
+            server.send_to(&a.serialize(), src).unwrap();
+        });
+        let s = query(addr, Duration::from_secs(1)).unwrap();
+        assert_eq!(s.stratum, NTP_STRATUM_ONE);
+        assert_eq!(s.ref_id, Reference::GPS);
+        assert_eq!(s.leap, LeapIndicator::NoWarning);
+        assert!((s.offset - TimeDelta::seconds(1)).abs() < TimeDelta::milliseconds(50));
+        assert!(s.delay < TimeDelta::milliseconds(50));
+    }
+}
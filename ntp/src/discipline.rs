@@ -0,0 +1,300 @@
+//! Clock discipline, a PLL/FLL hybrid loosely following RFC 5905 section 11.3: offset
+//! measurements become small slews of the local clock instead of steps
+use chrono::TimeDelta;
+use std::time::{Duration, Instant};
+
+/// The phase is corrected with a time constant of `PLL_GAIN * 2^tc` seconds
+const PLL_GAIN: f64 = 4.0;
+/// Weight of each frequency measurement once the FLL is active
+const FLL_GAIN: f64 = 0.25;
+/// The time constant moves once the poll-adjust counter goes past this, RFC 5905 LIMIT
+const COUNT_LIMIT: i32 = 30;
+/// Offsets within this many jitters count as quiet, RFC 5905 PGATE
+const JITTER_GATE: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DisciplineConfig {
+    /// Larger offsets are stepped instead of slewed; RFC 5905 uses 128ms
+    pub step_threshold: Duration,
+    /// A large offset is only stepped once it persists for this long, a single bad reading is
+    /// ignored
+    pub stepout: Duration,
+    /// Bound on the frequency correction, about what a crystal can be off by
+    pub max_freq_ppm: f64,
+    /// Bounds on the time constant, in log2 seconds
+    pub min_time_constant: u8,
+    pub max_time_constant: u8,
+    /// Updates further apart than this also correct the frequency from how fast the offset
+    /// changed (FLL); below it, white phase noise dominates and only the PLL is used
+    pub allan_intercept: Duration,
+}
+
+impl Default for DisciplineConfig {
+    fn default() -> Self {
+        DisciplineConfig {
+            step_threshold: Duration::from_millis(128),
+            // the receiver is polled every few seconds
+            stepout: Duration::from_secs(30),
+            max_freq_ppm: 500.0,
+            min_time_constant: 2,
+            max_time_constant: 10,
+            allan_intercept: Duration::from_secs(2048),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjustment {
+    /// Move the clock by this much at once
+    Step(TimeDelta),
+    /// Move the clock by this much gradually, e.g. with `adjtime`, before the next update
+    Slew(TimeDelta),
+    /// The offset looks like a spike, leave the clock alone
+    Ignore,
+}
+
+pub(crate) fn secs(d: TimeDelta) -> f64 {
+    d.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e9
+}
+
+fn delta(s: f64) -> TimeDelta {
+    TimeDelta::nanoseconds((s * 1e9) as i64)
+}
+
+#[derive(Debug, Clone)]
+pub struct Discipline {
+    config: DisciplineConfig,
+    /// Last offset, seconds
+    offset: f64,
+    /// Frequency correction, seconds per second; positive when the local clock runs slow
+    freq: f64,
+    /// log2 seconds
+    time_constant: u8,
+    /// RMS of the differences between consecutive offsets, seconds
+    jitter: f64,
+    /// Poll-adjust counter, the time constant grows while offsets stay within the jitter
+    count: i32,
+    /// When the last update was, and how much it slewed
+    last: Option<(Instant, f64)>,
+    /// When the offsets went over the step threshold
+    spike_since: Option<Instant>,
+}
+
+impl Discipline {
+    pub fn new(config: DisciplineConfig) -> Discipline {
+        Discipline {
+            offset: 0.0,
+            freq: 0.0,
+            time_constant: config.min_time_constant,
+            jitter: 0.0,
+            count: 0,
+            last: None,
+            spike_since: None,
+            config,
+        }
+    }
+
+    /// Starts from a known frequency correction, e.g. a saved drift estimate
+    pub fn with_frequency(config: DisciplineConfig, freq_ppm: f64) -> Discipline {
+        let max = config.max_freq_ppm;
+        Discipline {
+            freq: freq_ppm.clamp(-max, max) / 1e6,
+            ..Discipline::new(config)
+        }
+    }
+
+    /// Last measured offset
+    pub fn offset(&self) -> TimeDelta {
+        delta(self.offset)
+    }
+
+    pub fn freq_ppm(&self) -> f64 {
+        self.freq * 1e6
+    }
+
+    /// In log2 seconds
+    pub fn time_constant(&self) -> u8 {
+        self.time_constant
+    }
+
+    /// Feeds a measurement of reference time minus local time, taken at `now`
+    pub fn update(&mut self, offset: TimeDelta, now: Instant) -> Adjustment {
+        let x = secs(offset);
+        if x.abs() > self.config.step_threshold.as_secs_f64() {
+            // the first reading is stepped right away, the clock may be off by years
+            if self.last.is_some() {
+                let since = *self.spike_since.get_or_insert(now);
+                if now - since < self.config.stepout {
+                    return Adjustment::Ignore;
+                }
+            }
+            self.offset = 0.0;
+            self.time_constant = self.config.min_time_constant;
+            self.count = 0;
+            self.last = None;
+            self.spike_since = None;
+            return Adjustment::Step(offset);
+        }
+        self.spike_since = None;
+
+        let (phase, interval) = match self.last {
+            None => (x, 0.0),
+            Some((at, slewed)) => {
+                let mu = (now - at).as_secs_f64().max(f64::EPSILON);
+                // the loop cannot react faster than it is fed
+                let tc = f64::from(1u32 << self.time_constant).max(mu);
+                // how fast the offset changed since the last update, once the slew is removed
+                let residual = (x - self.offset + slewed) / mu - self.freq;
+                let diff = x - self.offset;
+                self.jitter =
+                    (self.jitter.powi(2) + (diff.powi(2) - self.jitter.powi(2)) / 4.0).sqrt();
+
+                self.freq += x * mu / (PLL_GAIN * tc).powi(2);
+                if mu > self.config.allan_intercept.as_secs_f64() {
+                    self.freq += residual * FLL_GAIN;
+                }
+                let max = self.config.max_freq_ppm / 1e6;
+                self.freq = self.freq.clamp(-max, max);
+
+                self.adjust_time_constant(x);
+                (x * (mu / (PLL_GAIN * tc)).min(1.0), mu)
+            }
+        };
+        // the frequency correction for the next interval, assuming it is as long as this one
+        let slew = phase + self.freq * interval;
+        self.offset = x;
+        self.last = Some((now, slew));
+        Adjustment::Slew(delta(slew))
+    }
+
+    /// Lengthens the time constant while offsets are within the noise, shortens it otherwise
+    fn adjust_time_constant(&mut self, x: f64) {
+        let tc = i32::from(self.time_constant);
+        if x.abs() < JITTER_GATE * self.jitter {
+            self.count += tc;
+            if self.count > COUNT_LIMIT {
+                self.count = 0;
+                self.time_constant = (self.time_constant + 1).min(self.config.max_time_constant);
+            }
+        } else {
+            self.count -= 2 * tc;
+            if self.count < -COUNT_LIMIT {
+                self.count = 0;
+                self.time_constant = self
+                    .time_constant
+                    .saturating_sub(1)
+                    .max(self.config.min_time_constant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local clock running `drift_ppm` slow, read every `interval` with some noise
+    struct Simulation {
+        discipline: Discipline,
+        now: Instant,
+        offset: f64,
+        seed: u32,
+    }
+
+    impl Simulation {
+        fn new(offset: f64) -> Simulation {
+            Simulation {
+                discipline: Discipline::new(DisciplineConfig::default()),
+                now: Instant::now(),
+                offset,
+                seed: 1,
+            }
+        }
+
+        /// Uniform noise in +-`amplitude`, from a fixed LCG so runs are repeatable
+        fn noise(&mut self, amplitude: f64) -> f64 {
+            self.seed = self.seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (f64::from(self.seed >> 8) / f64::from(1u32 << 24) * 2.0 - 1.0) * amplitude
+        }
+
+        fn run(&mut self, steps: u32, interval: Duration, drift_ppm: f64, noise: f64) -> f64 {
+            let mut worst: f64 = 0.0;
+            for _ in 0..steps {
+                let measured = self.offset + self.noise(noise);
+                match self.discipline.update(delta(measured), self.now) {
+                    Adjustment::Step(d) | Adjustment::Slew(d) => self.offset -= secs(d),
+                    Adjustment::Ignore => {}
+                }
+                self.now += interval;
+                self.offset += drift_ppm / 1e6 * interval.as_secs_f64();
+                worst = worst.max(self.offset.abs());
+            }
+            worst
+        }
+    }
+
+    #[test]
+    fn converges_on_drift() {
+        let mut s = Simulation::new(0.05);
+        let five = Duration::from_secs(5);
+        s.run(2000, five, 30.0, 0.001);
+        assert!((s.discipline.freq_ppm() - 30.0).abs() < 1.0);
+        // after the first hours, the offset stays within the noise of the readings
+        let worst = s.run(1000, five, 30.0, 0.001);
+        assert!(worst < 0.001, "{}", worst);
+        assert!(s.discipline.time_constant() > DisciplineConfig::default().min_time_constant);
+    }
+
+    #[test]
+    fn fll_at_long_intervals() {
+        let mut s = Simulation::new(0.0);
+        s.run(50, Duration::from_secs(4096), -20.0, 0.0001);
+        assert!((s.discipline.freq_ppm() + 20.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn steps_only_persistent_offsets() {
+        let mut d = Discipline::new(DisciplineConfig::default());
+        let t0 = Instant::now();
+        let at = |s| t0 + Duration::from_secs(s);
+        let big = TimeDelta::seconds(3);
+        assert_eq!(d.update(big, at(0)), Adjustment::Step(big));
+        assert!(matches!(
+            d.update(TimeDelta::milliseconds(1), at(5)),
+            Adjustment::Slew(_)
+        ));
+        // a single outlier is ignored
+        assert_eq!(d.update(big, at(10)), Adjustment::Ignore);
+        assert!(matches!(
+            d.update(TimeDelta::zero(), at(15)),
+            Adjustment::Slew(_)
+        ));
+        // but not for longer than the stepout
+        for s in [20, 30, 45] {
+            assert_eq!(d.update(big, at(s)), Adjustment::Ignore);
+        }
+        assert_eq!(d.update(big, at(50)), Adjustment::Step(big));
+        assert_eq!(
+            d.time_constant(),
+            DisciplineConfig::default().min_time_constant
+        );
+    }
+
+    #[test]
+    fn starts_from_a_known_frequency() {
+        let mut s = Simulation::new(0.0);
+        s.discipline = Discipline::with_frequency(DisciplineConfig::default(), 30.0);
+        // the loop has nothing to learn, the offset stays small from the start
+        let worst = s.run(200, Duration::from_secs(5), 30.0, 0.0);
+        assert!(worst < 0.0002, "{}", worst);
+        let d = Discipline::with_frequency(DisciplineConfig::default(), 1e6);
+        assert_eq!(d.freq_ppm(), 500.0);
+    }
+
+    #[test]
+    fn frequency_is_bounded() {
+        let mut s = Simulation::new(0.0);
+        s.run(2000, Duration::from_secs(5), 2000.0, 0.0);
+        assert!((s.discipline.freq_ppm() - 500.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,143 @@
+//! Frequency error of the local oscillator, fitted over the history of clock adjustments. The
+//! estimate is meant to be saved, like ntpd's drift file, so the discipline loop does not have to
+//! relearn it after a reboot.
+use crate::discipline::secs;
+use chrono::TimeDelta;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct DriftConfig {
+    /// Readings closer together than this are skipped; the fit is over a long window, not a
+    /// dense one
+    pub sample_interval: Duration,
+    /// Number of readings kept
+    pub window: usize,
+    /// No estimate until the readings span at least this long
+    pub min_span: Duration,
+}
+
+impl Default for DriftConfig {
+    fn default() -> Self {
+        DriftConfig {
+            sample_interval: Duration::from_secs(60),
+            // about an hour, short enough to follow temperature changes
+            window: 64,
+            min_span: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Least squares fit of the phase of the free running clock, the offset it would have without
+/// any corrections, against time
+#[derive(Debug, Clone)]
+pub struct DriftEstimator {
+    config: DriftConfig,
+    /// Sum of the corrections applied to the clock, seconds
+    corrected: f64,
+    /// Time of the first reading
+    start: Option<Instant>,
+    /// Seconds since `start`, free running phase in seconds
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl DriftEstimator {
+    pub fn new(config: DriftConfig) -> DriftEstimator {
+        DriftEstimator {
+            config,
+            corrected: 0.0,
+            start: None,
+            samples: VecDeque::with_capacity(config.window),
+        }
+    }
+
+    /// Records a step or slew of the clock by `d`. Slews are assumed to be done by the next
+    /// reading.
+    pub fn correction(&mut self, d: TimeDelta) {
+        self.corrected += secs(d);
+    }
+
+    /// Records a measurement of reference time minus local time, taken at `now`
+    pub fn sample(&mut self, offset: TimeDelta, now: Instant) {
+        let start = *self.start.get_or_insert(now);
+        let t = (now - start).as_secs_f64();
+        if let Some((last, _)) = self.samples.back() {
+            if t - last < self.config.sample_interval.as_secs_f64() {
+                return;
+            }
+        }
+        if self.samples.len() == self.config.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((t, secs(offset) + self.corrected));
+    }
+
+    /// Positive when the local clock runs slow, the same sign as the discipline's frequency
+    pub fn ppm(&self) -> Option<f64> {
+        let (first, _) = self.samples.front()?;
+        let (last, _) = self.samples.back()?;
+        if last - first < self.config.min_span.as_secs_f64() {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let mean_t = self.samples.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_p = self.samples.iter().map(|(_, p)| p).sum::<f64>() / n;
+        let (cov, var) = self.samples.iter().fold((0.0, 0.0), |(cov, var), (t, p)| {
+            (
+                cov + (t - mean_t) * (p - mean_p),
+                var + (t - mean_t).powi(2),
+            )
+        });
+        Some(cov / var * 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_through_corrections() {
+        let mut e = DriftEstimator::new(DriftConfig::default());
+        let t0 = Instant::now();
+        // 12ppm slow, corrected every 5s, with a step and some alternating noise
+        let mut offset = 0.02;
+        for i in 0..2000u32 {
+            let now = t0 + Duration::from_secs(5 * u64::from(i));
+            let noise = if i % 2 == 0 { 0.0005 } else { -0.0005 };
+            let measured = TimeDelta::nanoseconds(((offset + noise) * 1e9) as i64);
+            e.sample(measured, now);
+            let correction = if i == 100 { measured } else { measured / 3 };
+            e.correction(correction);
+            offset -= secs(correction);
+            offset += 12e-6 * 5.0;
+        }
+        let ppm = e.ppm().unwrap();
+        assert!((ppm - 12.0).abs() < 0.1, "{}", ppm);
+    }
+
+    #[test]
+    fn needs_a_long_enough_span() {
+        let mut e = DriftEstimator::new(DriftConfig::default());
+        let t0 = Instant::now();
+        for i in 0..15u64 {
+            e.sample(
+                TimeDelta::milliseconds(i as i64),
+                t0 + Duration::from_secs(i * 60),
+            );
+        }
+        assert_eq!(e.ppm(), None);
+        // too close to the last reading, skipped
+        e.sample(
+            TimeDelta::seconds(10),
+            t0 + Duration::from_secs(14 * 60 + 1),
+        );
+        assert_eq!(e.ppm(), None);
+        e.sample(
+            TimeDelta::milliseconds(15),
+            t0 + Duration::from_secs(15 * 60),
+        );
+        let ppm = e.ppm().unwrap();
+        assert!((ppm - 1000.0 / 60.0).abs() < 1e-6, "{}", ppm);
+    }
+}
@@ -0,0 +1,200 @@
+//! Leap seconds: when to announce them, and how the timescale moves once they happen
+use crate::proto::LeapIndicator;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use std::time::Duration;
+
+/// How served time goes through a leap second
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LeapMode {
+    /// Announced with the leap indicator, and the second is inserted or deleted at midnight
+    #[default]
+    Step,
+    /// Spread linearly over `window`, centred on midnight, without announcing it; a 24h window
+    /// smears noon to noon
+    Smear { window: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeapDirection {
+    /// The last minute of the day has 61 seconds, 23:59:60 is repeated as 23:59:59
+    Insert,
+    /// The last minute of the day has 59 seconds
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeapSecond {
+    /// The instant, in the timescale before the event, from which `correction` applies: midnight
+    /// for an inserted second, a second earlier for a deleted one
+    pub at: DateTime<Utc>,
+    pub direction: LeapDirection,
+}
+
+impl LeapSecond {
+    /// At the end of the UTC day `midnight` closes
+    pub fn new(midnight: DateTime<Utc>, direction: LeapDirection) -> LeapSecond {
+        let at = match direction {
+            LeapDirection::Insert => midnight,
+            LeapDirection::Delete => midnight - TimeDelta::seconds(1),
+        };
+        LeapSecond { at, direction }
+    }
+
+    /// From a receiver's count down: `change` seconds are added to UTC in `seconds` from `now`.
+    /// None when there is no change, or the event is already past.
+    pub fn from_countdown(now: DateTime<Utc>, seconds: i64, change: i8) -> Option<LeapSecond> {
+        let direction = match change {
+            1 => LeapDirection::Insert,
+            -1 => LeapDirection::Delete,
+            _ => return None,
+        };
+        if seconds <= 0 {
+            return None;
+        }
+        // leap seconds are only ever at the end of a UTC day; rounding absorbs the receiver's
+        // and the local clock's errors
+        let event = now + TimeDelta::seconds(seconds);
+        let midnight = event.duration_round(TimeDelta::days(1)).ok()?;
+        Some(LeapSecond::new(midnight, direction))
+    }
+
+    /// Announced during the whole UTC day of the event, cleared once it passed
+    pub fn indicator(&self, now: DateTime<Utc>) -> LeapIndicator {
+        let day_start = self.midnight() - TimeDelta::days(1);
+        if now < day_start || self.passed(now) {
+            return LeapIndicator::NoWarning;
+        }
+        match self.direction {
+            LeapDirection::Insert => LeapIndicator::LastMinuteHas61Seconds,
+            LeapDirection::Delete => LeapIndicator::LastMinuteHas59Seconds,
+        }
+    }
+
+    pub fn passed(&self, now: DateTime<Utc>) -> bool {
+        now >= self.at
+    }
+
+    /// How a clock that does not know about the event has to move once it passed
+    pub fn correction(&self) -> TimeDelta {
+        match self.direction {
+            LeapDirection::Insert => TimeDelta::seconds(-1),
+            LeapDirection::Delete => TimeDelta::seconds(1),
+        }
+    }
+
+    /// How far time served at `t` is moved while smearing over `window`: nothing before the
+    /// window, `correction` after it
+    pub fn smear(&self, t: DateTime<Utc>, window: Duration) -> TimeDelta {
+        let Ok(window) = TimeDelta::from_std(window) else {
+            return TimeDelta::zero();
+        };
+        let start = self.midnight() - window / 2;
+        if t < start {
+            return TimeDelta::zero();
+        }
+        if t >= start + window {
+            return self.correction();
+        }
+        let elapsed = i128::from((t - start).num_nanoseconds().unwrap_or(i64::MAX));
+        let window = i128::from(window.num_nanoseconds().unwrap_or(i64::MAX));
+        let correction = i128::from(self.correction().num_nanoseconds().unwrap_or(0));
+        // within an i64 since elapsed < window
+        TimeDelta::nanoseconds((correction * elapsed / window) as i64)
+    }
+
+    /// Whether a clock that does not know about the event has to be corrected by `t`; smearing
+    /// hides the event until the end of the window
+    pub fn due(&self, t: DateTime<Utc>, mode: LeapMode) -> bool {
+        match mode {
+            LeapMode::Step => self.passed(t),
+            LeapMode::Smear { window } => self.smear(t, window) == self.correction(),
+        }
+    }
+
+    /// The end of the UTC day the event closes
+    pub fn midnight(&self) -> DateTime<Utc> {
+        match self.direction {
+            LeapDirection::Insert => self.at,
+            LeapDirection::Delete => self.at + TimeDelta::seconds(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn new_year() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn countdown() {
+        let now = Utc.with_ymd_and_hms(2016, 12, 20, 13, 14, 15).unwrap();
+        let seconds = (new_year() - now).num_seconds();
+        // a few seconds off either way still lands on midnight
+        for error in [-3, 0, 3] {
+            let leap = LeapSecond::from_countdown(now, seconds + error, 1).unwrap();
+            assert_eq!(leap.at, new_year());
+            assert_eq!(leap.direction, LeapDirection::Insert);
+        }
+        let leap = LeapSecond::from_countdown(now, seconds, -1).unwrap();
+        assert_eq!(leap.at, new_year() - TimeDelta::seconds(1));
+        assert_eq!(LeapSecond::from_countdown(now, seconds, 0), None);
+        // the receiver keeps reporting the last event for a while
+        assert_eq!(LeapSecond::from_countdown(now, -10, 1), None);
+    }
+
+    #[test]
+    fn announced_on_the_day() {
+        let leap = LeapSecond::new(new_year(), LeapDirection::Insert);
+        let at = |h, m, s| Utc.with_ymd_and_hms(2016, 12, 31, h, m, s).unwrap();
+        assert_eq!(
+            leap.indicator(at(0, 0, 0) - TimeDelta::seconds(1)),
+            LeapIndicator::NoWarning
+        );
+        assert_eq!(
+            leap.indicator(at(0, 0, 0)),
+            LeapIndicator::LastMinuteHas61Seconds
+        );
+        assert_eq!(
+            leap.indicator(at(23, 59, 59)),
+            LeapIndicator::LastMinuteHas61Seconds
+        );
+        assert_eq!(leap.indicator(new_year()), LeapIndicator::NoWarning);
+
+        let leap = LeapSecond::new(new_year(), LeapDirection::Delete);
+        assert_eq!(
+            leap.indicator(at(23, 59, 58)),
+            LeapIndicator::LastMinuteHas59Seconds
+        );
+        // 23:59:59 does not exist
+        assert!(leap.passed(at(23, 59, 59)));
+        assert_eq!(leap.correction(), TimeDelta::seconds(1));
+    }
+
+    #[test]
+    fn smear_curve() {
+        let window = Duration::from_secs(24 * 60 * 60);
+        let hours = |h| new_year() + TimeDelta::hours(h);
+        let leap = LeapSecond::new(new_year(), LeapDirection::Insert);
+        let ms = TimeDelta::milliseconds;
+        assert_eq!(leap.smear(hours(-13), window), ms(0));
+        assert_eq!(leap.smear(hours(-12), window), ms(0));
+        assert_eq!(leap.smear(hours(-6), window), ms(-250));
+        assert_eq!(leap.smear(hours(0), window), ms(-500));
+        assert_eq!(leap.smear(hours(6), window), ms(-750));
+        assert_eq!(leap.smear(hours(12), window), ms(-1000));
+        assert_eq!(leap.smear(hours(13), window), ms(-1000));
+        assert!(!leap.due(hours(11), LeapMode::Smear { window }));
+        assert!(leap.due(hours(12), LeapMode::Smear { window }));
+        assert!(leap.due(hours(0), LeapMode::Step));
+
+        let leap = LeapSecond::new(new_year(), LeapDirection::Delete);
+        assert_eq!(leap.smear(hours(-6), window), ms(250));
+        assert_eq!(leap.smear(hours(12), window), ms(1000));
+        // no window, a step at midnight
+        assert_eq!(leap.smear(hours(0), Duration::ZERO), ms(1000));
+    }
+}
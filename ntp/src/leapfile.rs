@@ -0,0 +1,219 @@
+//! Leap second tables in the format of the IERS `leap-seconds.list`
+use crate::proto::ntp_zero;
+use chrono::{DateTime, TimeDelta, Utc};
+use sha1::{Digest, Sha1};
+
+/// The list this was built with; it is only good until its expiry date
+const BUILTIN: &str = include_str!("leap-seconds.list");
+/// GPS time was UTC at its epoch in 1980, when TAI-UTC was 19s
+const GPS_TAI_OFFSET: i16 = 19;
+
+#[derive(Debug, PartialEq)]
+pub enum LeapFileError {
+    /// Line number of a line that does not look like `ntp-seconds tai-offset`
+    Malformed(usize),
+    /// Entries are not in increasing order of time
+    OutOfOrder(usize),
+    MissingExpiry,
+    MissingUpdate,
+    /// The `#h` line does not match the contents
+    BadHash,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeapTable {
+    /// When the list was last updated
+    pub updated: DateTime<Utc>,
+    /// No leap seconds other than the listed ones happen before this
+    pub expires: DateTime<Utc>,
+    /// Start of each period and TAI-UTC during it, in seconds
+    entries: Vec<(DateTime<Utc>, i16)>,
+}
+
+fn from_ntp_seconds(s: u64) -> Option<DateTime<Utc>> {
+    Some(ntp_zero() + TimeDelta::seconds(i64::try_from(s).ok()?))
+}
+
+impl LeapTable {
+    /// The table embedded in the binary
+    pub fn builtin() -> LeapTable {
+        // checked by the tests
+        LeapTable::parse(BUILTIN).unwrap()
+    }
+
+    /// `text` without the comments, which are most of the upstream file; what `parse` reads is
+    /// kept, so the hash still holds
+    pub fn compact(text: &str) -> String {
+        text.lines()
+            .map(str::trim)
+            .filter_map(|l| {
+                if l.starts_with("#$") || l.starts_with("#@") || l.starts_with("#h") {
+                    return Some(l);
+                }
+                Some(l.split('#').next().unwrap().trim()).filter(|l| !l.is_empty())
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Parses the contents of a `leap-seconds.list`; the hash is checked when there is one
+    pub fn parse(text: &str) -> Result<LeapTable, LeapFileError> {
+        let mut updated = None;
+        let mut expires = None;
+        let mut hash = None;
+        let mut entries: Vec<(DateTime<Utc>, i16)> = vec![];
+        let mut hashed = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.trim();
+            let number = |s: &str| -> Result<u64, LeapFileError> {
+                s.trim()
+                    .parse()
+                    .map_err(|_| LeapFileError::Malformed(lineno))
+            };
+            if let Some(v) = line.strip_prefix("#$") {
+                updated = Some(number(v)?);
+                continue;
+            }
+            if let Some(v) = line.strip_prefix("#@") {
+                expires = Some(number(v)?);
+                continue;
+            }
+            if let Some(v) = line.strip_prefix("#h") {
+                let words: Result<Vec<u32>, _> = v
+                    .split_whitespace()
+                    .map(|w| u32::from_str_radix(w, 16))
+                    .collect();
+                hash = Some(words.map_err(|_| LeapFileError::Malformed(lineno))?);
+                continue;
+            }
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(LeapFileError::Malformed(lineno));
+            }
+            let at = from_ntp_seconds(number(parts[0])?).ok_or(LeapFileError::Malformed(lineno))?;
+            let offset: i16 = parts[1]
+                .parse()
+                .map_err(|_| LeapFileError::Malformed(lineno))?;
+            if entries.last().is_some_and(|(last, _)| *last >= at) {
+                return Err(LeapFileError::OutOfOrder(lineno));
+            }
+            entries.push((at, offset));
+            hashed.push_str(parts[0]);
+            hashed.push_str(parts[1]);
+        }
+        let updated = updated.ok_or(LeapFileError::MissingUpdate)?;
+        let expires = expires.ok_or(LeapFileError::MissingExpiry)?;
+        if let Some(words) = hash {
+            let digest = Sha1::new()
+                .chain_update(updated.to_string())
+                .chain_update(expires.to_string())
+                .chain_update(hashed)
+                .finalize();
+            let expected: Vec<u32> = digest
+                .chunks(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            if words != expected {
+                return Err(LeapFileError::BadHash);
+            }
+        }
+        Ok(LeapTable {
+            updated: from_ntp_seconds(updated).ok_or(LeapFileError::MissingUpdate)?,
+            expires: from_ntp_seconds(expires).ok_or(LeapFileError::MissingExpiry)?,
+            entries,
+        })
+    }
+
+    /// TAI minus UTC at `t`, in seconds; None outside of the period the table covers
+    pub fn tai_offset(&self, t: DateTime<Utc>) -> Option<i16> {
+        if t >= self.expires {
+            return None;
+        }
+        self.entries
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= t)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// GPS time minus UTC at `t`, in seconds, as the receivers report it
+    pub fn gps_offset(&self, t: DateTime<Utc>) -> Option<i8> {
+        let offset = self.tai_offset(t)? - GPS_TAI_OFFSET;
+        i8::try_from(offset).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn builtin_table() {
+        let t = LeapTable::builtin();
+        let at = |y, m, d| Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+        assert_eq!(t.tai_offset(at(1971, 1, 1)), None);
+        assert_eq!(t.tai_offset(at(1972, 1, 1)), Some(10));
+        assert_eq!(t.gps_offset(at(1980, 1, 6)), Some(0));
+        assert_eq!(
+            t.gps_offset(at(2017, 1, 1) - TimeDelta::seconds(1)),
+            Some(17)
+        );
+        assert_eq!(t.gps_offset(at(2017, 1, 1)), Some(18));
+        assert_eq!(t.gps_offset(t.expires - TimeDelta::seconds(1)), Some(18));
+        // it might not hold after that
+        assert_eq!(t.gps_offset(t.expires), None);
+    }
+
+    #[test]
+    #[ignore = "release check, run with --ignored: depends on today's date"]
+    fn builtin_is_current() {
+        // once this fails, replace leap-seconds.list with the current one from the IERS
+        let t = LeapTable::builtin();
+        assert!(
+            t.expires > crate::client::system_now(),
+            "the built-in leap-seconds.list expired on {}",
+            t.expires
+        );
+    }
+
+    #[test]
+    fn checks_the_file() {
+        let mut text = BUILTIN
+            .lines()
+            .map(|l| {
+                if l.starts_with("3692217600") {
+                    "3692217600\t38"
+                } else {
+                    l
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(LeapTable::parse(&text), Err(LeapFileError::BadHash));
+        // the hash is optional
+        text = text
+            .lines()
+            .filter(|l| !l.starts_with("#h"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let t = LeapTable::parse(&text).unwrap();
+        assert_eq!(t.tai_offset(t.expires - TimeDelta::seconds(1)), Some(38));
+
+        let compact = LeapTable::compact(BUILTIN);
+        assert!(compact.len() < 1024, "{}", compact.len());
+        assert_eq!(LeapTable::parse(&compact), Ok(LeapTable::builtin()));
+
+        let text = "#$ 3960835200\n#@ 3991593600\n3692217600 37\n3644697600 36\n";
+        assert_eq!(LeapTable::parse(text), Err(LeapFileError::OutOfOrder(4)));
+        let text = "#$ 3960835200\n3692217600 37\n";
+        assert_eq!(LeapTable::parse(text), Err(LeapFileError::MissingExpiry));
+        let text = "#$ 3960835200\n#@ 3991593600\n3692217600\n";
+        assert_eq!(LeapTable::parse(text), Err(LeapFileError::Malformed(3)));
+    }
+}
@@ -1,2 +1,16 @@
+pub mod auth;
+pub mod client;
+pub mod discipline;
+pub mod drift;
+pub mod leap;
+pub mod leapfile;
+pub mod nts;
+#[cfg(feature = "nts")]
+pub mod nts_ke;
+pub mod persist;
+pub mod pps;
 pub mod proto;
+pub mod ratelimit;
+pub mod restrict;
 pub mod server;
+pub mod source;
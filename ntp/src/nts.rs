@@ -0,0 +1,656 @@
+//! Network Time Security, RFC 8915
+use crate::proto::*;
+use aes_siv::siv::Aes128Siv;
+use aes_siv::KeyInit;
+use std::io::{self, Read};
+
+pub const NTS_KE_PORT: u16 = 4460;
+pub const NTS_KE_ALPN: &[u8] = b"ntske/1";
+/// TLS exporter label used to derive the NTP keys from the NTS-KE session
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+pub const NEXT_PROTOCOL_NTPV4: u16 = 0;
+/// The only AEAD algorithm implemented, and the one every NTS implementation must support
+pub const AEAD_AES_SIV_CMAC_256: u16 = 15;
+/// Cookies handed out per key exchange
+pub const COOKIES_PER_KE: usize = 8;
+/// NTS-KE messages are a handful of records; anything larger is not worth reading
+const KE_MESSAGE_MAX_LEN: usize = 4096;
+const UNIQUE_ID_MIN_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+/// key id, nonce, tag and both 32 byte keys
+pub const COOKIE_LEN: usize = 4 + NONCE_LEN + TAG_LEN + 2 * AesSivCmac256::KEY_LEN;
+/// Each query may ask for this many extra cookies with placeholders
+const MAX_PLACEHOLDERS: usize = 7;
+
+/// Direction byte of the exporter context
+pub const CLIENT_TO_SERVER: u8 = 0;
+pub const SERVER_TO_CLIENT: u8 = 1;
+
+/// Context passed to the TLS exporter, RFC 8915 section 5.1
+pub fn exporter_context(aead: u16, direction: u8) -> [u8; 5] {
+    let p = NEXT_PROTOCOL_NTPV4.to_be_bytes();
+    let a = aead.to_be_bytes();
+    [p[0], p[1], a[0], a[1], direction]
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    getrandom::getrandom(&mut buf).expect("No source of randomness");
+    buf
+}
+
+/// AEAD_AES_SIV_CMAC_256, RFC 5297, with the associated data and the nonce as the two S2V
+/// headers. The output is the 16 byte synthetic IV followed by the ciphertext.
+#[derive(Clone)]
+pub struct AesSivCmac256 {
+    key: [u8; 32],
+}
+
+impl AesSivCmac256 {
+    pub const KEY_LEN: usize = 32;
+
+    pub fn new(key: &[u8]) -> Option<AesSivCmac256> {
+        Some(AesSivCmac256 {
+            key: key.try_into().ok()?,
+        })
+    }
+
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    fn siv(&self) -> Aes128Siv {
+        Aes128Siv::new(&self.key.into())
+    }
+
+    fn seal_with(&self, headers: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+        // only fails for more headers than S2V takes
+        self.siv().encrypt(headers, plaintext).unwrap()
+    }
+
+    fn open_with(&self, headers: &[&[u8]], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        self.siv().decrypt(headers, ciphertext).ok()
+    }
+
+    pub fn seal(&self, nonce: &[u8], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        self.seal_with(&[ad, nonce], plaintext)
+    }
+
+    /// None if the ciphertext or the associated data were tampered with
+    pub fn open(&self, nonce: &[u8], ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        self.open_with(&[ad, nonce], ciphertext)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordType {
+    EndOfMessage,
+    NextProtocol,
+    Error,
+    Warning,
+    AeadAlgorithm,
+    NewCookie,
+    Server,
+    Port,
+    Unknown(u16),
+}
+
+impl From<u16> for RecordType {
+    fn from(t: u16) -> RecordType {
+        match t {
+            0 => RecordType::EndOfMessage,
+            1 => RecordType::NextProtocol,
+            2 => RecordType::Error,
+            3 => RecordType::Warning,
+            4 => RecordType::AeadAlgorithm,
+            5 => RecordType::NewCookie,
+            6 => RecordType::Server,
+            7 => RecordType::Port,
+            other => RecordType::Unknown(other),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(t: RecordType) -> u16 {
+        match t {
+            RecordType::EndOfMessage => 0,
+            RecordType::NextProtocol => 1,
+            RecordType::Error => 2,
+            RecordType::Warning => 3,
+            RecordType::AeadAlgorithm => 4,
+            RecordType::NewCookie => 5,
+            RecordType::Server => 6,
+            RecordType::Port => 7,
+            RecordType::Unknown(other) => other,
+        }
+    }
+}
+
+/// Error codes sent in an Error record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeError {
+    UnrecognizedCriticalRecord,
+    BadRequest,
+    InternalServerError,
+}
+
+impl From<KeError> for u16 {
+    fn from(e: KeError) -> u16 {
+        match e {
+            KeError::UnrecognizedCriticalRecord => 0,
+            KeError::BadRequest => 1,
+            KeError::InternalServerError => 2,
+        }
+    }
+}
+
+/// NTS-KE record, RFC 8915 section 4
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub critical: bool,
+    pub record_type: RecordType,
+    pub body: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(critical: bool, record_type: RecordType, body: &[u8]) -> Record {
+        Record {
+            critical,
+            record_type,
+            body: body.to_vec(),
+        }
+    }
+
+    pub fn end_of_message() -> Record {
+        Record::new(true, RecordType::EndOfMessage, &[])
+    }
+
+    pub fn error(e: KeError) -> Record {
+        Record::new(true, RecordType::Error, &u16::from(e).to_be_bytes())
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let t = u16::from(self.record_type) | if self.critical { 0x8000 } else { 0 };
+        let mut out = Vec::with_capacity(4 + self.body.len());
+        out.extend(t.to_be_bytes());
+        out.extend((self.body.len() as u16).to_be_bytes());
+        out.extend(&self.body);
+        out
+    }
+
+    /// Parses one record from the start of `buf`, returns it with its length; None if `buf`
+    /// does not hold a complete record
+    pub fn deserialize(buf: &[u8]) -> Option<(Record, usize)> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let t = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = 4 + u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let body = buf.get(4..len)?;
+        let record = Record {
+            critical: t & 0x8000 != 0,
+            record_type: RecordType::from(t & 0x7fff),
+            body: body.to_vec(),
+        };
+        Some((record, len))
+    }
+
+    /// The body as a list of u16, as used by the negotiation records
+    fn u16s(&self) -> Option<Vec<u16>> {
+        if self.body.len() & 1 != 0 {
+            return None;
+        }
+        Some(
+            self.body
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect(),
+        )
+    }
+}
+
+pub fn serialize_records(records: &[Record]) -> Vec<u8> {
+    records.iter().flat_map(|r| r.serialize()).collect()
+}
+
+/// Reads records up to and including End of Message
+pub fn read_message(r: &mut impl Read) -> io::Result<Vec<Record>> {
+    let mut buf = vec![];
+    let mut chunk = [0; 512];
+    let mut records = vec![];
+    loop {
+        while let Some((record, len)) = Record::deserialize(&buf) {
+            buf.drain(..len);
+            let end = record.record_type == RecordType::EndOfMessage;
+            records.push(record);
+            if end {
+                return Ok(records);
+            }
+        }
+        if buf.len() > KE_MESSAGE_MAX_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NTS-KE message too long",
+            ));
+        }
+        let n = r.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend(&chunk[..n]);
+    }
+}
+
+/// What the client and server agreed on during NTS-KE
+#[derive(Debug, PartialEq)]
+pub enum Negotiation {
+    /// The only protocol and algorithm offered by this server
+    Ntpv4AesSivCmac256,
+    /// The client offered nothing we support; the response says so with empty records
+    NoCommonProtocol,
+    NoCommonAead,
+}
+
+/// Checks the client's NTS-KE request, RFC 8915 section 4.1
+pub fn negotiate(request: &[Record]) -> Result<Negotiation, KeError> {
+    let mut protocols = None;
+    let mut aeads = None;
+    for r in request {
+        match r.record_type {
+            RecordType::NextProtocol if protocols.is_none() => {
+                protocols = Some(r.u16s().ok_or(KeError::BadRequest)?)
+            }
+            RecordType::AeadAlgorithm if aeads.is_none() => {
+                aeads = Some(r.u16s().ok_or(KeError::BadRequest)?)
+            }
+            RecordType::NextProtocol | RecordType::AeadAlgorithm => {
+                return Err(KeError::BadRequest)
+            }
+            // clients may only send errors and warnings in response to a server's records
+            RecordType::Error | RecordType::Warning | RecordType::NewCookie => {
+                return Err(KeError::BadRequest)
+            }
+            // requests for a specific server or port are ignored, this server is the only one
+            RecordType::EndOfMessage | RecordType::Server | RecordType::Port => (),
+            RecordType::Unknown(_) if r.critical => {
+                return Err(KeError::UnrecognizedCriticalRecord)
+            }
+            RecordType::Unknown(_) => (),
+        }
+    }
+    let protocols = protocols.ok_or(KeError::BadRequest)?;
+    if !protocols.contains(&NEXT_PROTOCOL_NTPV4) {
+        return Ok(Negotiation::NoCommonProtocol);
+    }
+    let aeads = aeads.ok_or(KeError::BadRequest)?;
+    if !aeads.contains(&AEAD_AES_SIV_CMAC_256) {
+        return Ok(Negotiation::NoCommonAead);
+    }
+    Ok(Negotiation::Ntpv4AesSivCmac256)
+}
+
+/// The response to a successful negotiation; `keys` are the ones derived from the TLS session
+pub fn ke_response(jar: &CookieJar, keys: &SessionKeys) -> Vec<Record> {
+    let mut records = vec![
+        Record::new(
+            true,
+            RecordType::NextProtocol,
+            &NEXT_PROTOCOL_NTPV4.to_be_bytes(),
+        ),
+        Record::new(
+            true,
+            RecordType::AeadAlgorithm,
+            &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+        ),
+    ];
+    for _ in 0..COOKIES_PER_KE {
+        records.push(Record::new(
+            false,
+            RecordType::NewCookie,
+            &jar.make_cookie(keys),
+        ));
+    }
+    records.push(Record::end_of_message());
+    records
+}
+
+/// The response to a request we can not serve
+pub fn ke_refusal(n: Result<Negotiation, KeError>) -> Vec<Record> {
+    let refusal = match n {
+        Ok(Negotiation::NoCommonProtocol) => Record::new(true, RecordType::NextProtocol, &[]),
+        Ok(Negotiation::NoCommonAead) => Record::new(true, RecordType::AeadAlgorithm, &[]),
+        Ok(Negotiation::Ntpv4AesSivCmac256) => Record::error(KeError::InternalServerError),
+        Err(e) => Record::error(e),
+    };
+    vec![refusal, Record::end_of_message()]
+}
+
+/// The keys for one client, negotiated during NTS-KE and carried in its cookies afterwards
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub c2s: AesSivCmac256,
+    pub s2c: AesSivCmac256,
+}
+
+/// Encrypts the session keys into cookies so the server does not need to keep per client
+/// state. The master key is random and only lives in memory: after a restart, clients
+/// have to go through NTS-KE again.
+#[derive(Clone)]
+pub struct CookieJar {
+    key_id: u32,
+    master: AesSivCmac256,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar {
+            key_id: u32::from_be_bytes(random()),
+            master: AesSivCmac256::new(&random::<32>()).unwrap(),
+        }
+    }
+
+    /// key id, nonce and the sealed keys; the algorithm is not stored, there is only one
+    pub fn make_cookie(&self, keys: &SessionKeys) -> Vec<u8> {
+        let id = self.key_id.to_be_bytes();
+        let nonce: [u8; NONCE_LEN] = random();
+        let mut plaintext = keys.c2s.key().to_vec();
+        plaintext.extend(keys.s2c.key());
+        let mut cookie = id.to_vec();
+        cookie.extend(nonce);
+        cookie.extend(self.master.seal(&nonce, &id, &plaintext));
+        cookie
+    }
+
+    pub fn open_cookie(&self, cookie: &[u8]) -> Option<SessionKeys> {
+        if cookie.len() != COOKIE_LEN || cookie[..4] != self.key_id.to_be_bytes() {
+            return None;
+        }
+        let (nonce, sealed) = cookie[4..].split_at(NONCE_LEN);
+        let keys = self.master.open(nonce, &cookie[..4], sealed)?;
+        let (c2s, s2c) = keys.split_at(AesSivCmac256::KEY_LEN);
+        Some(SessionKeys {
+            c2s: AesSivCmac256::new(c2s)?,
+            s2c: AesSivCmac256::new(s2c)?,
+        })
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// NTS Authenticator and Encrypted Extension Fields, sealing `plaintext` with a random nonce.
+/// `ad` is the packet up to this field.
+pub fn authenticator(aead: &AesSivCmac256, ad: &[u8], plaintext: &[u8]) -> ExtensionField {
+    let nonce: [u8; NONCE_LEN] = random();
+    let ciphertext = aead.seal(&nonce, ad, plaintext);
+    let mut value = vec![];
+    value.extend((nonce.len() as u16).to_be_bytes());
+    value.extend((ciphertext.len() as u16).to_be_bytes());
+    value.extend(nonce);
+    value.extend(&ciphertext);
+    value.resize((value.len() + 3) & !3, 0);
+    ExtensionField::new(ExtensionFieldType::NtsAuthenticator, &value)
+}
+
+/// Verifies an authenticator's value, returns the encrypted extension fields it carries
+pub fn open_authenticator(aead: &AesSivCmac256, ad: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    let nonce_len = u16::from_be_bytes([*value.first()?, *value.get(1)?]) as usize;
+    let ct_len = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]) as usize;
+    let nonce = value.get(4..4 + nonce_len)?;
+    let ct_start = 4 + ((nonce_len + 3) & !3);
+    let ciphertext = value.get(ct_start..ct_start + ct_len)?;
+    if nonce.is_empty() {
+        return None;
+    }
+    aead.open(nonce, ad, ciphertext)
+}
+
+/// Why an NTS query is not answered normally
+#[derive(Debug, PartialEq)]
+pub enum NtsError {
+    /// The NTS extension fields are missing, duplicated or out of place
+    Malformed,
+    /// The cookie could not be opened or the authenticator did not verify; this is answered
+    /// with an NTSN kiss-o'-death which echoes the unique identifier
+    Unauthenticated(Vec<u8>),
+}
+
+/// A query carries NTS extension fields if it has a cookie or an authenticator
+pub fn is_nts(q: &NTPMessage) -> bool {
+    q.extensions.iter().any(|e| {
+        matches!(
+            e.field_type,
+            ExtensionFieldType::NtsCookie | ExtensionFieldType::NtsAuthenticator
+        )
+    })
+}
+
+/// A validated NTS query, RFC 8915 section 5.7
+pub struct NtsQuery {
+    unique_id: Vec<u8>,
+    keys: SessionKeys,
+    /// Number of cookies to send back: one for the cookie used, one per placeholder
+    cookies: usize,
+}
+
+impl NtsQuery {
+    /// `datagram` is what `q` was parsed from, the authenticator is checked against it
+    pub fn parse(
+        jar: Option<&CookieJar>,
+        q: &NTPMessage,
+        datagram: &[u8],
+    ) -> Result<NtsQuery, NtsError> {
+        let of_type = |t| q.extensions.iter().filter(move |e| e.field_type == t);
+        let single = |t| {
+            let mut fields = of_type(t);
+            match (fields.next(), fields.next()) {
+                (Some(f), None) => Ok(f),
+                _ => Err(NtsError::Malformed),
+            }
+        };
+        let unique_id = single(ExtensionFieldType::UniqueIdentifier)?;
+        let cookie = single(ExtensionFieldType::NtsCookie)?;
+        let auth = single(ExtensionFieldType::NtsAuthenticator)?;
+        if unique_id.value.len() < UNIQUE_ID_MIN_LEN {
+            return Err(NtsError::Malformed);
+        }
+        // fields after the authenticator would not be authenticated
+        let auth_index = q.extensions.len() - 1;
+        if q.extensions[auth_index].field_type != ExtensionFieldType::NtsAuthenticator {
+            return Err(NtsError::Malformed);
+        }
+        let unauthenticated = || NtsError::Unauthenticated(unique_id.value.clone());
+        let keys = jar
+            .and_then(|j| j.open_cookie(&cookie.value))
+            .ok_or_else(unauthenticated)?;
+        open_authenticator(
+            &keys.c2s,
+            q.received_until(datagram, auth_index),
+            &auth.value,
+        )
+        .ok_or_else(unauthenticated)?;
+        // a placeholder has to be as large as the cookie which replaces it, so the response
+        // is never larger than the query
+        let placeholders = of_type(ExtensionFieldType::NtsCookiePlaceholder)
+            .filter(|p| p.value.len() >= COOKIE_LEN)
+            .count();
+        Ok(NtsQuery {
+            unique_id: unique_id.value.clone(),
+            keys,
+            cookies: 1 + placeholders.min(MAX_PLACEHOLDERS),
+        })
+    }
+
+    /// Appends the unique identifier and an authenticator carrying fresh cookies
+    pub fn seal(&self, jar: &CookieJar, answer: &mut NTPMessage) {
+        answer.extensions = vec![ExtensionField::new(
+            ExtensionFieldType::UniqueIdentifier,
+            &self.unique_id,
+        )];
+        let cookies: Vec<ExtensionField> = (0..self.cookies)
+            .map(|_| {
+                ExtensionField::new(ExtensionFieldType::NtsCookie, &jar.make_cookie(&self.keys))
+            })
+            .collect();
+        let plaintext = ExtensionField::serialize_all(&cookies);
+        // the authenticator covers the bytes as they go out, so take them from the answer
+        // with the authenticator already in place
+        answer.extensions.push(ExtensionField::new(
+            ExtensionFieldType::NtsAuthenticator,
+            &[],
+        ));
+        let sent = answer.serialize();
+        answer.extensions[1] =
+            authenticator(&self.keys.s2c, answer.received_until(&sent, 1), &plaintext);
+    }
+}
+
+/// Turns an answer into an NTSN kiss-o'-death: the client has to run NTS-KE again
+pub fn nak(answer: &mut NTPMessage, unique_id: &[u8]) {
+    answer.kiss_o_death(*b"NTSN");
+    answer.extensions = vec![ExtensionField::new(
+        ExtensionFieldType::UniqueIdentifier,
+        unique_id,
+    )];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn siv_rfc5297_vectors() {
+        // A.1, deterministic authenticated encryption
+        let siv = AesSivCmac256::new(&hex(
+            "fffefdfc fbfaf9f8 f7f6f5f4 f3f2f1f0 f0f1f2f3 f4f5f6f7 f8f9fafb fcfdfeff",
+        ))
+        .unwrap();
+        let ad = hex("10111213 14151617 18191a1b 1c1d1e1f 20212223 24252627");
+        let plaintext = hex("11223344 55667788 99aabbcc ddee");
+        let expected = hex("85632d07 c6e8f37f 950acd32 0a2ecc93 40c02b96 90c4dc04 daef7f6a fe5c");
+        assert_eq!(siv.seal_with(&[&ad], &plaintext), expected);
+        assert_eq!(siv.open_with(&[&ad], &expected).unwrap(), plaintext);
+
+        // A.2, nonce based authenticated encryption
+        let siv = AesSivCmac256::new(&hex(
+            "7f7e7d7c 7b7a7978 77767574 73727170 40414243 44454647 48494a4b 4c4d4e4f",
+        ))
+        .unwrap();
+        let ad1 = hex(
+            "00112233 44556677 8899aabb ccddeeff deaddada deaddada ffeeddcc bbaa9988 77665544 33221100",
+        );
+        let ad2 = hex("10203040 50607080 90a0");
+        let nonce = hex("09f91102 9d74e35b d84156c5 635688c0");
+        let plaintext = hex(
+            "74686973 20697320 736f6d65 20706c61 696e7465 78742074 6f20656e 63727970 74207573
+             696e6720 5349562d 414553",
+        );
+        let expected = hex(
+            "7bdb6e3b 432667eb 06f4d14b ff2fbd0f cb900f2f ddbe4043 26601965 c889bf17 dba77ceb
+             094fa663 b7a3f748 ba8af829 ea64ad54 4a272e9c 485b62a3 fd5c0d",
+        );
+        assert_eq!(siv.seal_with(&[&ad1, &ad2, &nonce], &plaintext), expected);
+        let mut tampered = expected.clone();
+        tampered[20] ^= 1;
+        assert!(siv.open_with(&[&ad1, &ad2, &nonce], &tampered).is_none());
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let r = Record::new(true, RecordType::NextProtocol, &[0, 0]);
+        let buf = r.serialize();
+        assert_eq!(buf, vec![0x80, 1, 0, 2, 0, 0]);
+        assert_eq!(Record::deserialize(&buf), Some((r, 6)));
+        assert_eq!(Record::deserialize(&buf[..5]), None);
+
+        let mut msg = serialize_records(&[
+            Record::new(false, RecordType::Unknown(0x1234), b"x"),
+            Record::end_of_message(),
+        ]);
+        msg.extend(b"trailing");
+        let records = read_message(&mut &msg[..]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].record_type, RecordType::Unknown(0x1234));
+        assert!(read_message(&mut &msg[..5]).is_err());
+    }
+
+    #[test]
+    fn negotiation() {
+        let proto = |p: &[u8]| Record::new(true, RecordType::NextProtocol, p);
+        let aead = |a: &[u8]| Record::new(false, RecordType::AeadAlgorithm, a);
+        let eom = Record::end_of_message();
+        assert_eq!(
+            negotiate(&[proto(&[0, 0]), aead(&[0, 1, 0, 15]), eom.clone()]),
+            Ok(Negotiation::Ntpv4AesSivCmac256)
+        );
+        assert_eq!(
+            negotiate(&[proto(&[0, 1]), eom.clone()]),
+            Ok(Negotiation::NoCommonProtocol)
+        );
+        assert_eq!(
+            negotiate(&[proto(&[0, 0]), aead(&[0, 17]), eom.clone()]),
+            Ok(Negotiation::NoCommonAead)
+        );
+        assert_eq!(
+            negotiate(&[aead(&[0, 15]), eom.clone()]),
+            Err(KeError::BadRequest)
+        );
+        assert_eq!(
+            negotiate(&[proto(&[0, 0]), proto(&[0, 0]), aead(&[0, 15]), eom.clone()]),
+            Err(KeError::BadRequest)
+        );
+        let unknown = Record::new(true, RecordType::Unknown(0x4000), &[]);
+        assert_eq!(
+            negotiate(&[unknown, proto(&[0, 0]), aead(&[0, 15]), eom]),
+            Err(KeError::UnrecognizedCriticalRecord)
+        );
+    }
+
+    #[test]
+    fn cookies() {
+        let jar = CookieJar::new();
+        let keys = SessionKeys {
+            c2s: AesSivCmac256::new(&[1; 32]).unwrap(),
+            s2c: AesSivCmac256::new(&[2; 32]).unwrap(),
+        };
+        let cookie = jar.make_cookie(&keys);
+        assert_eq!(cookie.len(), COOKIE_LEN);
+        assert_eq!(cookie.len() & 3, 0);
+        let opened = jar.open_cookie(&cookie).unwrap();
+        assert_eq!(opened.c2s.key(), &[1; 32]);
+        assert_eq!(opened.s2c.key(), &[2; 32]);
+        // cookies are not linkable
+        assert_ne!(cookie, jar.make_cookie(&keys));
+
+        let mut tampered = cookie.clone();
+        tampered[30] ^= 1;
+        assert!(jar.open_cookie(&tampered).is_none());
+        assert!(CookieJar::new().open_cookie(&cookie).is_none());
+    }
+
+    #[test]
+    fn authenticator_roundtrip() {
+        let aead = AesSivCmac256::new(&[3; 32]).unwrap();
+        let field = authenticator(&aead, b"header", b"fields");
+        assert_eq!(field.value.len() & 3, 0);
+        assert_eq!(
+            open_authenticator(&aead, b"header", &field.value).unwrap(),
+            b"fields"
+        );
+        assert!(open_authenticator(&aead, b"other", &field.value).is_none());
+        assert!(open_authenticator(&aead, b"header", &field.value[..10]).is_none());
+    }
+}
@@ -0,0 +1,92 @@
+//! NTS-KE server over TLS 1.3, RFC 8915 section 4
+use crate::nts::*;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A key exchange is a single round trip; clients that stall are dropped
+const KE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// TLS 1.3 only, with the `ntske/1` ALPN; the certificate chain and key are PEM encoded
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut &key_pem[..])?
+        .ok_or_else(|| invalid("No private key found"))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(invalid)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid)?;
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Both AEAD keys, from the TLS exporter
+fn export_keys(conn: &ServerConnection) -> io::Result<SessionKeys> {
+    let key = |direction| {
+        let context = exporter_context(AEAD_AES_SIV_CMAC_256, direction);
+        let k = conn
+            .export_keying_material([0; AesSivCmac256::KEY_LEN], EXPORTER_LABEL, Some(&context))
+            .map_err(invalid)?;
+        Ok::<_, io::Error>(AesSivCmac256::new(&k).unwrap())
+    };
+    Ok(SessionKeys {
+        c2s: key(CLIENT_TO_SERVER)?,
+        s2c: key(SERVER_TO_CLIENT)?,
+    })
+}
+
+/// Runs one key exchange: reads the client's records and answers with cookies from `jar`
+pub fn handle_client(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    jar: &CookieJar,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(KE_TIMEOUT))?;
+    stream.set_write_timeout(Some(KE_TIMEOUT))?;
+    let conn = ServerConnection::new(config).map_err(invalid)?;
+    let mut tls = StreamOwned::new(conn, stream);
+    // reading the request completes the handshake
+    let request = read_message(&mut tls)?;
+    // rustls refuses clients offering other protocols, but not clients offering none
+    if tls.conn.alpn_protocol() != Some(NTS_KE_ALPN) {
+        return Err(invalid("Client did not negotiate ntske/1"));
+    }
+    let response = match negotiate(&request) {
+        Ok(Negotiation::Ntpv4AesSivCmac256) => ke_response(jar, &export_keys(&tls.conn)?),
+        n => ke_refusal(n),
+    };
+    tls.write_all(&serialize_records(&response))?;
+    tls.conn.send_close_notify();
+    tls.flush()
+}
+
+/// Accepts key exchanges forever, each on its own thread
+pub fn serve(listener: TcpListener, config: Arc<ServerConfig>, jar: CookieJar) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                println!("NTS-KE accept failed: {}", e);
+                continue;
+            }
+        };
+        let config = config.clone();
+        let jar = jar.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = handle_client(stream, config, &jar) {
+                println!("NTS-KE with {:?} failed: {}", peer, e);
+            }
+        });
+    }
+}
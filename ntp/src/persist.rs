@@ -0,0 +1,163 @@
+//! Receiver state kept across reboots. After a cold start the receiver uses the leap second count
+//! in its firmware until it downloads the almanac; the count it last confirmed is a cheaper
+//! check than waiting for it.
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::HashMap;
+
+const LEAP_KEY: &str = "gps_leap";
+const LAST_GOOD_KEY: &str = "last_good";
+
+/// Small values that survive reboots, like the ESP's NVS
+pub trait KeyValue {
+    fn get(&self, key: &str) -> Option<String>;
+    fn set(&mut self, key: &str, value: &str);
+}
+
+impl KeyValue for HashMap<String, String> {
+    fn get(&self, key: &str) -> Option<String> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        self.insert(key.to_string(), value.to_string());
+    }
+}
+
+pub struct ReceiverState<S: KeyValue> {
+    store: S,
+    /// GPS time minus UTC, as last confirmed by the receiver
+    leap: Option<i8>,
+    /// Latest time the receiver gave with a confirmed leap second count; stored as seconds since
+    /// the Unix epoch
+    last_good: Option<DateTime<Utc>>,
+    /// Last time `last_good` was written; flash wears out
+    saved_at: Option<DateTime<Utc>>,
+    save_interval: TimeDelta,
+    /// How far before `last_good` time is still plausible; a week number rollover moves it back
+    /// by 1024 weeks
+    pub max_rollback: TimeDelta,
+}
+
+impl<S: KeyValue> ReceiverState<S> {
+    /// Values that do not parse are ignored
+    pub fn load(store: S) -> ReceiverState<S> {
+        let leap = store.get(LEAP_KEY).and_then(|v| v.parse().ok());
+        let last_good = store
+            .get(LAST_GOOD_KEY)
+            .and_then(|v| v.parse().ok())
+            .and_then(|secs| DateTime::from_timestamp(secs, 0));
+        ReceiverState {
+            store,
+            leap,
+            last_good,
+            saved_at: last_good,
+            save_interval: TimeDelta::hours(1),
+            max_rollback: TimeDelta::weeks(4),
+        }
+    }
+
+    pub fn leap_seconds(&self) -> Option<i8> {
+        self.leap
+    }
+
+    pub fn last_good(&self) -> Option<DateTime<Utc>> {
+        self.last_good
+    }
+
+    /// The count to convert with, given the receiver's `reported` count and whether the receiver
+    /// confirmed it. A confirmed count is saved; until there is one, only a count matching the
+    /// saved one is used.
+    pub fn check_leap(&mut self, reported: i8, confirmed: bool) -> Option<i8> {
+        if !confirmed {
+            return self.leap.filter(|l| *l == reported);
+        }
+        if self.leap != Some(reported) {
+            self.store.set(LEAP_KEY, &reported.to_string());
+            self.leap = Some(reported);
+        }
+        Some(reported)
+    }
+
+    /// Time from the receiver more than `max_rollback` before the last good one is not
+    /// plausible, e.g. after a GPS week number rollover
+    pub fn plausible(&self, t: DateTime<Utc>) -> bool {
+        match self.last_good {
+            Some(good) => t >= good - self.max_rollback,
+            None => true,
+        }
+    }
+
+    /// Drops the last good time, e.g. when it was saved from a receiver that was wrong; any time
+    /// is plausible again until the next good one
+    pub fn forget_good_time(&mut self) {
+        self.last_good = None;
+        self.saved_at = None;
+        self.store.set(LAST_GOOD_KEY, "");
+    }
+
+    /// Records `t` as good time from the receiver
+    pub fn good_time(&mut self, t: DateTime<Utc>) {
+        self.last_good = Some(t);
+        if self.saved_at.is_some_and(|s| t - s < self.save_interval) {
+            return;
+        }
+        self.store.set(LAST_GOOD_KEY, &t.timestamp().to_string());
+        self.saved_at = Some(t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn t0() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn seeds_from_the_last_run() {
+        let mut state = ReceiverState::load(HashMap::new());
+        // nothing to go by until the receiver confirms its count
+        assert_eq!(state.check_leap(15, false), None);
+        assert_eq!(state.check_leap(18, true), Some(18));
+        state.good_time(t0());
+        state.good_time(t0() + TimeDelta::minutes(10));
+
+        // a reboot, with a receiver still using its firmware's count
+        let mut state = ReceiverState::load(state.store);
+        assert_eq!(state.leap_seconds(), Some(18));
+        // saved at most once an hour
+        assert_eq!(state.last_good(), Some(t0()));
+        assert_eq!(state.check_leap(15, false), None);
+        assert_eq!(state.check_leap(18, false), Some(18));
+        assert!(!state.plausible(t0() - TimeDelta::weeks(1024)));
+        assert!(!state.plausible(t0() - TimeDelta::weeks(5)));
+        assert!(state.plausible(t0() - TimeDelta::weeks(3)));
+        assert!(state.plausible(t0()));
+        // until it confirms a different one
+        assert_eq!(state.check_leap(19, true), Some(19));
+        let mut state = ReceiverState::load(state.store);
+        assert_eq!(state.leap_seconds(), Some(19));
+
+        // a bad good time can be dropped, for good
+        state.forget_good_time();
+        assert!(state.plausible(t0() - TimeDelta::weeks(1024)));
+        let mut state = ReceiverState::load(state.store);
+        assert_eq!(state.last_good(), None);
+        state.good_time(t0());
+        let state = ReceiverState::load(state.store);
+        assert_eq!(state.last_good(), Some(t0()));
+    }
+
+    #[test]
+    fn ignores_garbage() {
+        let mut store = HashMap::new();
+        store.insert(LEAP_KEY.to_string(), "many".to_string());
+        store.insert(LAST_GOOD_KEY.to_string(), "yesterday".to_string());
+        let mut state = ReceiverState::load(store);
+        assert_eq!(state.leap_seconds(), None);
+        assert_eq!(state.last_good(), None);
+        assert_eq!(state.check_leap(18, false), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Pulse per second: pairs the local time captured at each pulse edge with the time the receiver
+//! says the pulse marks, and rejects the odd glitch
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Edges or labels waiting for their pair; anything older is stale
+const MAX_PENDING: usize = 4;
+/// Offsets needed before the median means anything
+const MIN_HISTORY: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PpsConfig {
+    /// An edge and a label pair when they are this close, going by the local clock; the serial
+    /// messages have to bring the local clock this close first
+    pub pair_window: Duration,
+    /// Offsets further than this from the median of the recent ones are rejected
+    pub outlier_threshold: Duration,
+    /// Number of recent offsets the median is taken over
+    pub history: usize,
+}
+
+impl Default for PpsConfig {
+    fn default() -> Self {
+        PpsConfig {
+            pair_window: Duration::from_millis(400),
+            outlier_threshold: Duration::from_millis(1),
+            history: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PpsSample {
+    /// Reference time of the pulse
+    pub label: DateTime<Utc>,
+    /// Local time of the edge
+    pub edge: DateTime<Utc>,
+    /// Reference time minus local time, at the edge
+    pub offset: TimeDelta,
+}
+
+/// The whole second closest to `t`; pulses are on the second, the time in a navigation solution
+/// is a few nanoseconds off
+pub fn nearest_second(t: DateTime<Utc>) -> DateTime<Utc> {
+    // only fails for times near the ends of what a DateTime holds
+    t.duration_round(TimeDelta::seconds(1)).unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct Pps {
+    config: PpsConfig,
+    edges: VecDeque<DateTime<Utc>>,
+    labels: VecDeque<DateTime<Utc>>,
+    /// Offsets of recent pairs, rejected ones included so a real change of offset is followed
+    recent: VecDeque<TimeDelta>,
+    rejected: u32,
+}
+
+impl Pps {
+    pub fn new(config: PpsConfig) -> Pps {
+        Pps {
+            config,
+            edges: VecDeque::with_capacity(MAX_PENDING),
+            labels: VecDeque::with_capacity(MAX_PENDING),
+            recent: VecDeque::with_capacity(config.history),
+            rejected: 0,
+        }
+    }
+
+    /// Pairs that were discarded as outliers
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    /// A rising edge, captured at local time `local`
+    pub fn edge(&mut self, local: DateTime<Utc>) -> Option<PpsSample> {
+        if self.edges.len() == MAX_PENDING {
+            self.edges.pop_front();
+        }
+        self.edges.push_back(local);
+        self.pair()
+    }
+
+    /// The reference time of a pulse; messages announcing the next pulse and messages about the
+    /// last one can both be fed, in any order
+    pub fn label(&mut self, pulse: DateTime<Utc>) -> Option<PpsSample> {
+        if self.labels.contains(&pulse) {
+            return None;
+        }
+        if self.labels.len() == MAX_PENDING {
+            self.labels.pop_front();
+        }
+        self.labels.push_back(pulse);
+        self.pair()
+    }
+
+    /// Pairs a label with the closest edge within the window; edges and labels older than the
+    /// pair can no longer pair, and are dropped
+    fn pair(&mut self) -> Option<PpsSample> {
+        let window = TimeDelta::from_std(self.config.pair_window).ok()?;
+        let (li, ei) = self.labels.iter().enumerate().find_map(|(li, label)| {
+            self.edges
+                .iter()
+                .enumerate()
+                .filter(|(_, edge)| (*label - **edge).abs() <= window)
+                .min_by_key(|(_, edge)| (*label - **edge).abs())
+                .map(|(ei, _)| (li, ei))
+        })?;
+        let label = self.labels[li];
+        let edge = self.edges[ei];
+        self.labels.drain(..=li);
+        self.edges.drain(..=ei);
+        self.check(PpsSample {
+            label,
+            edge,
+            offset: label - edge,
+        })
+    }
+
+    fn check(&mut self, s: PpsSample) -> Option<PpsSample> {
+        if self.recent.len() == self.config.history {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(s.offset);
+        if self.recent.len() < MIN_HISTORY {
+            return None;
+        }
+        let mut sorted: Vec<TimeDelta> = self.recent.iter().copied().collect();
+        sorted.sort();
+        let median = sorted[sorted.len() / 2];
+        let threshold = TimeDelta::from_std(self.config.outlier_threshold).ok()?;
+        if (s.offset - median).abs() > threshold {
+            self.rejected += 1;
+            return None;
+        }
+        Some(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn t0() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    fn ms(v: i64) -> TimeDelta {
+        TimeDelta::milliseconds(v)
+    }
+
+    #[test]
+    fn pairs_edges_with_labels() {
+        let mut pps = Pps::new(PpsConfig::default());
+        // the local clock is 37ms behind
+        let edge = |s: i64| t0() + TimeDelta::seconds(s) - ms(37);
+        let label = |s: i64| t0() + TimeDelta::seconds(s);
+        let mut samples = vec![];
+        for s in 0..10 {
+            // the announcement of the next pulse comes before its edge, the solution after it
+            samples.extend(pps.label(label(s)));
+            samples.extend(pps.edge(edge(s)));
+            samples.extend(pps.label(label(s)));
+        }
+        // the first pairs only fill the history
+        assert_eq!(samples.len(), 10 - (MIN_HISTORY - 1));
+        for s in samples {
+            assert_eq!(s.offset, ms(37));
+            assert_eq!(s.label - s.edge, ms(37));
+        }
+
+        // labels coming late pair all the same, a missing edge does not shift the pairing
+        let late = pps.edge(edge(10));
+        assert_eq!(late, None);
+        assert_eq!(pps.edge(edge(12)), None);
+        assert_eq!(pps.label(label(10)).unwrap().label, label(10));
+        assert_eq!(pps.label(label(11)), None);
+        assert_eq!(pps.label(label(12)).unwrap().label, label(12));
+    }
+
+    #[test]
+    fn rejects_glitches() {
+        let mut pps = Pps::new(PpsConfig::default());
+        for s in 0..5 {
+            pps.edge(t0() + TimeDelta::seconds(s));
+            pps.label(t0() + TimeDelta::seconds(s));
+        }
+        // a glitch close to the real edge loses to it
+        let s = TimeDelta::seconds(5);
+        pps.edge(t0() + s - ms(150));
+        pps.edge(t0() + s);
+        assert_eq!(pps.label(t0() + s).unwrap().offset, ms(0));
+        // a glitch on its own is an outlier
+        let s = TimeDelta::seconds(6);
+        pps.edge(t0() + s - ms(150));
+        assert_eq!(pps.label(t0() + s), None);
+        assert_eq!(pps.rejected(), 1);
+
+        // the clock was stepped, the new offset is followed once it is the median
+        let accepted: Vec<_> = (7..13)
+            .filter_map(|s| {
+                let s = TimeDelta::seconds(s);
+                pps.edge(t0() + s - ms(20));
+                pps.label(t0() + s)
+            })
+            .collect();
+        assert_eq!(accepted.len(), 5);
+        assert!(accepted.iter().all(|a| a.offset == ms(20)));
+        assert_eq!(pps.rejected(), 2);
+    }
+
+    #[test]
+    fn rounds_solutions() {
+        let t = t0() + TimeDelta::nanoseconds(-12);
+        assert_eq!(nearest_second(t), t0());
+        assert_eq!(nearest_second(t0() + ms(499)), t0());
+    }
+}
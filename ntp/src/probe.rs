@@ -0,0 +1,107 @@
+use chrono::TimeDelta;
+use ntp::client::{query, Sample};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+fn secs(d: TimeDelta) -> f64 {
+    d.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e9
+}
+
+/// min, mean, max and standard deviation
+fn stats(values: &[f64]) -> (f64, f64, f64, f64) {
+    let n = values.len() as f64;
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n;
+    (min, mean, max, var.sqrt())
+}
+
+/// `host`, with port 123 unless it already has one; a bare IPv6 address has no port
+fn server_addr(host: &str) -> String {
+    if host.parse::<SocketAddr>().is_ok() {
+        host.to_string()
+    } else if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        SocketAddr::new(ip, 123).to_string()
+    } else if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:123", host)
+    }
+}
+
+fn main() -> ExitCode {
+    let usage = "usage: ntp_probe HOST[:PORT] [COUNT] [INTERVAL_SECS]";
+    let args: Vec<String> = env::args().collect();
+    if !(2..=4).contains(&args.len()) {
+        println!("{}", usage);
+        return ExitCode::FAILURE;
+    }
+    let addr = server_addr(&args[1]);
+    let count: u32 = args.get(2).map_or(4, |c| c.parse().expect(usage));
+    // the server rate limits clients polling faster than this
+    let interval: f32 = args.get(3).map_or(2.0, |i| i.parse().expect(usage));
+
+    let mut samples: Vec<Sample> = vec![];
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(Duration::from_secs_f32(interval));
+        }
+        match query(addr.as_str(), Duration::from_secs(1)) {
+            Ok(s) => {
+                println!(
+                    "{} offset {:+.6}s delay {:.6}s stratum {} ref {:?} leap {:?}",
+                    addr,
+                    secs(s.offset),
+                    secs(s.delay),
+                    s.stratum,
+                    s.ref_id,
+                    s.leap
+                );
+                samples.push(s);
+            }
+            Err(e) => println!("{} no answer: {:?}", addr, e),
+        }
+    }
+
+    println!("{}/{} answered", samples.len(), count);
+    if samples.is_empty() {
+        return ExitCode::FAILURE;
+    }
+    let offsets: Vec<f64> = samples.iter().map(|s| secs(s.offset)).collect();
+    let delays: Vec<f64> = samples.iter().map(|s| secs(s.delay)).collect();
+    let (min, mean, max, sd) = stats(&offsets);
+    println!(
+        "offset min {:+.6}s mean {:+.6}s max {:+.6}s stddev {:.6}s",
+        min, mean, max, sd
+    );
+    let (min, mean, max, sd) = stats(&delays);
+    println!(
+        "delay  min {:.6}s mean {:.6}s max {:.6}s stddev {:.6}s",
+        min, mean, max, sd
+    );
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_addresses() {
+        assert_eq!(server_addr("::1"), "[::1]:123");
+        assert_eq!(server_addr("[::1]"), "[::1]:123");
+        assert_eq!(server_addr("[::1]:1123"), "[::1]:1123");
+        assert_eq!(server_addr("127.0.0.1"), "127.0.0.1:123");
+        assert_eq!(server_addr("127.0.0.1:1123"), "127.0.0.1:1123");
+        assert_eq!(server_addr("pool.ntp.org"), "pool.ntp.org:123");
+        assert_eq!(server_addr("pool.ntp.org:1123"), "pool.ntp.org:1123");
+    }
+}
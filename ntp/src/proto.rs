@@ -1,12 +1,23 @@
-use chrono::{DateTime, NaiveDate, Utc};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
 use std::{net::Ipv4Addr, time::Duration};
 
-pub const NTP_VERSION: u8 = 3;
+/// Highest version understood; clients are answered with the version they used
+pub const NTP_VERSION: u8 = 4;
 pub const NTP_MESSAGE_LEN: usize = 48;
 pub const NTP_STRATUM_ONE: u8 = 1;
 pub const NTP_STRATUM_UNSYNCHRONIZED: u8 = 16;
+/// Largest datagram accepted by the receive loops; room for extension fields
+pub const NTP_MAX_MESSAGE_LEN: usize = 1024;
+/// RFC 7822: an extension field is at least 16 bytes long, and a multiple of 4
+const EXTENSION_MIN_LEN: usize = 16;
+/// RFC 7822: the last extension field is at least 28 bytes long when no MAC follows it
+const EXTENSION_LAST_MIN_LEN: usize = 28;
+/// RFC 7822: anything longer than the largest MAC (key id + SHA-1 digest) is an extension field
+const MAC_MAX_LEN: usize = 24;
+/// Each era spans 2^32 seconds (~136 years); era 1 starts on 2036-02-07 06:28:16 UTC
+const NTP_ERA_SECONDS: i64 = 1 << 32;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub struct Fix32 {
     pub i: u16,
     pub f: u16,
@@ -24,6 +35,15 @@ impl Serialize for Fix32 {
         v
     }
 }
+impl Fix32 {
+    fn deserialize(buf: &[u8]) -> Fix32 {
+        Fix32 {
+            i: u16::from_be_bytes([buf[0], buf[1]]),
+            f: u16::from_be_bytes([buf[2], buf[3]]),
+        }
+    }
+}
+
 impl From<Fix32> for f32 {
     fn from(f: Fix32) -> f32 {
         f32::from(f.i) + f32::from(f.f) / f32::from(u16::MAX)
@@ -38,7 +58,7 @@ impl From<Duration> for Fix32 {
         // it's safe to << 12, as a u32, which is equivalent to * 4_000_000
 
         // the output .f goes up by 1 every ~15.2us
-        let micros = d.subsec_micros() * 1_000_000 / 15_258_789;
+        let micros = u64::from(d.subsec_micros()) * 1_000_000 / 15_258_789;
         Fix32 {
             i,
             f: micros as u16,
@@ -47,15 +67,19 @@ impl From<Duration> for Fix32 {
 }
 
 // This is basically Duration
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub struct NTPTimestamp {
-    /// seconds since NTP time zero; 1900-1-1 00:00:00 UTC.
+    /// seconds since the start of the current NTP era; era 0 started on 1900-1-1 00:00:00 UTC.
     int_part: u32,
-    /// nanoseconds
+    /// units of 2^-32 seconds (~233ps)
     frac_part: u32,
 }
 
 impl NTPTimestamp {
+    pub fn is_zero(&self) -> bool {
+        self.int_part == 0 && self.frac_part == 0
+    }
+
     fn deserialize(buf: &[u8]) -> NTPTimestamp {
         let int_part = u32::from_be_bytes(buf[0..4].try_into().unwrap());
         let frac_part = u32::from_be_bytes(buf[4..8].try_into().unwrap());
@@ -86,24 +110,69 @@ pub fn ntp_zero() -> DateTime<Utc> {
     )
 }
 
+/// Timestamps do not carry their era, so they are mapped to the 2^32 second window which starts
+/// at this date. This is 2^31 seconds after NTP zero, which is equivalent to the RFC 4330 rule of
+/// using the most significant bit to pick era 0 or era 1; valid from 1968 until 2104.
+pub fn ntp_pivot() -> DateTime<Utc> {
+    ntp_zero() + TimeDelta::seconds(NTP_ERA_SECONDS / 2)
+}
+
+/// Seconds since NTP zero, across eras
+fn ntp_seconds(dt: DateTime<Utc>) -> i64 {
+    dt.timestamp() - ntp_zero().timestamp()
+}
+
 impl From<DateTime<Utc>> for NTPTimestamp {
     fn from(dt: DateTime<Utc>) -> Self {
-        let delta = dt.signed_duration_since(ntp_zero());
-        let s = delta.num_seconds();
-        let n = delta.subsec_nanos();
+        let s = ntp_seconds(dt);
+        // A leap second (nanos >= 1s) is not representable; stay at the end of the previous second
+        let n = u64::from(dt.timestamp_subsec_nanos().min(999_999_999));
         NTPTimestamp {
-            int_part: s as u32,
-            frac_part: n as u32,
+            // the era number is dropped on the wire
+            int_part: s.rem_euclid(NTP_ERA_SECONDS) as u32,
+            frac_part: ((n << 32) / 1_000_000_000) as u32,
         }
     }
 }
 
+impl NTPTimestamp {
+    /// Maps the timestamp to the era in which it lands within [pivot, pivot + 2^32s)
+    pub fn to_datetime(&self, pivot: DateTime<Utc>) -> DateTime<Utc> {
+        let pivot_s = ntp_seconds(pivot);
+        let era_start = pivot_s - pivot_s.rem_euclid(NTP_ERA_SECONDS);
+        let mut s = era_start + i64::from(self.int_part);
+        if s < pivot_s {
+            s += NTP_ERA_SECONDS;
+        }
+        // round to the nearest nanosecond, so that converting back and forth is lossless
+        let n = ((u64::from(self.frac_part) * 1_000_000_000) + (1 << 31)) >> 32;
+        ntp_zero() + TimeDelta::seconds(s) + TimeDelta::nanoseconds(n as i64)
+    }
+}
+
+impl From<NTPTimestamp> for DateTime<Utc> {
+    fn from(t: NTPTimestamp) -> DateTime<Utc> {
+        t.to_datetime(ntp_pivot())
+    }
+}
+
+#[derive(PartialEq, Debug)]
 pub struct NTPFlags {
     pub l: LeapIndicator,
     pub v: VersionNumber,
     pub m: Mode,
 }
 
+impl From<u8> for NTPFlags {
+    fn from(b: u8) -> NTPFlags {
+        NTPFlags {
+            l: LeapIndicator::from(b >> 6),
+            v: VersionNumber((b >> 3) & 0b111),
+            m: Mode::from(b),
+        }
+    }
+}
+
 impl From<&NTPFlags> for u8 {
     fn from(n: &NTPFlags) -> u8 {
         let l: u8 = (&n.l).into();
@@ -114,9 +183,25 @@ impl From<&NTPFlags> for u8 {
     }
 }
 
+#[derive(PartialEq, Debug, Clone)]
 pub enum Reference {
     GPS,
     IPv4(Ipv4Addr),
+    /// Four ASCII characters, left justified and zero padded; used by stratum 0 (kiss codes)
+    /// and stratum 1 (reference clocks) peers
+    Ascii([u8; 4]),
+}
+
+impl Reference {
+    /// The meaning of the reference id depends on the stratum of the peer which sent it
+    fn deserialize(buf: &[u8], stratum: u8) -> Reference {
+        let b = [buf[0], buf[1], buf[2], buf[3]];
+        match stratum {
+            0 | 1 if b == [b'G', b'P', b'S', 0] => Reference::GPS,
+            0 | 1 => Reference::Ascii(b),
+            _ => Reference::IPv4(Ipv4Addr::from(b)),
+        }
+    }
 }
 
 trait Serialize {
@@ -128,6 +213,7 @@ impl Serialize for Reference {
         match self {
             Self::GPS => vec![b'G', b'P', b'S', 0],
             Self::IPv4(i) => i.octets().into(),
+            Self::Ascii(a) => a.to_vec(),
         }
     }
 }
@@ -167,8 +253,10 @@ impl From<&PeerPrecision> for u8 {
         p.0 as u8
     }
 }
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub struct VersionNumber(pub u8);
 
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum LeapIndicator {
     NoWarning,
     LastMinuteHas61Seconds,
@@ -190,6 +278,21 @@ impl From<&Mode> for u8 {
         }
     }
 }
+/// Only the 3 least significant bits are considered
+impl From<u8> for Mode {
+    fn from(b: u8) -> Mode {
+        match b & 0b111 {
+            0 => Mode::Unspecified,
+            1 => Mode::SymActive,
+            2 => Mode::SymPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::ControlMessage,
+            _ => Mode::Reserved,
+        }
+    }
+}
 impl From<&LeapIndicator> for u8 {
     fn from(l: &LeapIndicator) -> u8 {
         match l {
@@ -200,6 +303,18 @@ impl From<&LeapIndicator> for u8 {
         }
     }
 }
+/// Only the 2 least significant bits are considered
+impl From<u8> for LeapIndicator {
+    fn from(b: u8) -> LeapIndicator {
+        match b & 0b11 {
+            0b00 => LeapIndicator::NoWarning,
+            0b01 => LeapIndicator::LastMinuteHas61Seconds,
+            0b10 => LeapIndicator::LastMinuteHas59Seconds,
+            _ => LeapIndicator::Alarm,
+        }
+    }
+}
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum Mode {
     Unspecified,
     SymActive,
@@ -211,6 +326,178 @@ pub enum Mode {
     Reserved,
 }
 
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum ExtensionFieldType {
+    /// RFC 8915
+    UniqueIdentifier,
+    /// RFC 8915
+    NtsCookie,
+    /// RFC 8915
+    NtsCookiePlaceholder,
+    /// RFC 8915
+    NtsAuthenticator,
+    Unknown(u16),
+}
+
+impl From<u16> for ExtensionFieldType {
+    fn from(t: u16) -> ExtensionFieldType {
+        match t {
+            0x0104 => ExtensionFieldType::UniqueIdentifier,
+            0x0204 => ExtensionFieldType::NtsCookie,
+            0x0304 => ExtensionFieldType::NtsCookiePlaceholder,
+            0x0404 => ExtensionFieldType::NtsAuthenticator,
+            other => ExtensionFieldType::Unknown(other),
+        }
+    }
+}
+
+impl From<ExtensionFieldType> for u16 {
+    fn from(t: ExtensionFieldType) -> u16 {
+        match t {
+            ExtensionFieldType::UniqueIdentifier => 0x0104,
+            ExtensionFieldType::NtsCookie => 0x0204,
+            ExtensionFieldType::NtsCookiePlaceholder => 0x0304,
+            ExtensionFieldType::NtsAuthenticator => 0x0404,
+            ExtensionFieldType::Unknown(other) => other,
+        }
+    }
+}
+
+/// RFC 7822 extension field
+#[derive(PartialEq, Debug, Clone)]
+pub struct ExtensionField {
+    pub field_type: ExtensionFieldType,
+    /// When received, this includes the padding; when sent, it is zero padded to a multiple of
+    /// 4 bytes and to the minimum field length
+    pub value: Vec<u8>,
+}
+
+impl ExtensionField {
+    pub fn new(field_type: ExtensionFieldType, value: &[u8]) -> ExtensionField {
+        ExtensionField {
+            field_type,
+            value: value.to_vec(),
+        }
+    }
+
+    /// Parses one field from the start of `buf`, returns it with its length on the wire
+    fn deserialize(buf: &[u8]) -> Result<(ExtensionField, usize), ParseError> {
+        let field_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]);
+        let ulen = len as usize;
+        if ulen < EXTENSION_MIN_LEN || ulen & 3 != 0 || ulen > buf.len() {
+            return Err(ParseError::BadExtensionLength(len));
+        }
+        let field = ExtensionField {
+            field_type: ExtensionFieldType::from(field_type),
+            value: buf[4..ulen].to_vec(),
+        };
+        Ok((field, ulen))
+    }
+
+    /// Fields packed back to back, as carried encrypted inside an NTS authenticator
+    pub fn serialize_all(fields: &[ExtensionField]) -> Vec<u8> {
+        fields
+            .iter()
+            .flat_map(|f| f.serialize(EXTENSION_MIN_LEN))
+            .collect()
+    }
+
+    pub fn deserialize_all(mut buf: &[u8]) -> Result<Vec<ExtensionField>, ParseError> {
+        let mut fields = vec![];
+        while !buf.is_empty() {
+            if buf.len() < EXTENSION_MIN_LEN {
+                return Err(ParseError::TrailingData(buf.len()));
+            }
+            let (field, len) = ExtensionField::deserialize(buf)?;
+            fields.push(field);
+            buf = &buf[len..];
+        }
+        Ok(fields)
+    }
+
+    fn serialize(&self, min_len: usize) -> Vec<u8> {
+        // round up to a multiple of 4
+        let len = ((4 + self.value.len() + 3) & !3).max(min_len);
+        let mut out = Vec::with_capacity(len);
+        out.extend(u16::from(self.field_type).to_be_bytes());
+        out.extend((len as u16).to_be_bytes());
+        out.extend(&self.value);
+        out.resize(len, 0);
+        out
+    }
+}
+
+/// RFC 5905 message authentication code, trails the header and extension fields
+#[derive(PartialEq, Debug, Clone)]
+pub struct Mac {
+    pub key_id: u32,
+    /// Empty for a crypto-NAK
+    pub digest: Vec<u8>,
+}
+
+impl Mac {
+    /// Sent in reply to a query which failed authentication
+    pub fn crypto_nak() -> Mac {
+        Mac {
+            key_id: 0,
+            digest: vec![],
+        }
+    }
+
+    pub fn is_crypto_nak(&self) -> bool {
+        self.key_id == 0 && self.digest.is_empty()
+    }
+
+    fn deserialize(buf: &[u8]) -> Mac {
+        Mac {
+            key_id: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            digest: buf[4..].to_vec(),
+        }
+    }
+}
+
+impl Serialize for Mac {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.digest.len());
+        out.extend(self.key_id.to_be_bytes());
+        out.extend(&self.digest);
+        out
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ParseError {
+    /// The packet is shorter than the NTP header; contains the received length
+    ShortPacket(usize),
+    /// Only versions 1 through 4 are understood
+    BadVersion(u8),
+    /// Control messages and reserved modes use a different packet format
+    UnsupportedMode(Mode),
+    /// RFC 5905 specifies that packets with a zero transmit timestamp are bogus
+    ZeroTransmitTimestamp,
+    /// An extension field declared this length, which is too short, not a multiple of 4 or
+    /// goes past the end of the packet
+    BadExtensionLength(u16),
+    /// Bytes after the header and extension fields which can't be parsed
+    TrailingData(usize),
+}
+
+impl ParseError {
+    /// Short, stable name; useful as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::ShortPacket(_) => "short_packet",
+            ParseError::BadVersion(_) => "bad_version",
+            ParseError::UnsupportedMode(_) => "unsupported_mode",
+            ParseError::ZeroTransmitTimestamp => "zero_transmit_timestamp",
+            ParseError::BadExtensionLength(_) => "bad_extension_length",
+            ParseError::TrailingData(_) => "trailing_data",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
 pub struct NTPMessage {
     pub flags: NTPFlags,
     pub peer_stratum: u8,
@@ -225,26 +512,122 @@ pub struct NTPMessage {
     pub origin_tstamp: NTPTimestamp,
     pub rcv_tstamp: NTPTimestamp,
     pub transmit_tstamp: NTPTimestamp,
+    pub extensions: Vec<ExtensionField>,
+    pub mac: Option<Mac>,
 }
-pub struct NTPQuery {
-    pub peer_polling_interval: u8,
-    pub transmit_tstamp: NTPTimestamp,
-}
-
-impl NTPQuery {
-    pub fn deserialize(buf: &[u8]) -> Option<NTPQuery> {
-        if buf.len() != NTP_MESSAGE_LEN {
-            return None;
+impl NTPMessage {
+    /// Parses the 48 byte NTP header and the extension fields that follow it
+    pub fn deserialize(buf: &[u8]) -> Result<NTPMessage, ParseError> {
+        if buf.len() < NTP_MESSAGE_LEN {
+            return Err(ParseError::ShortPacket(buf.len()));
         }
-        Some(NTPQuery {
+        let mut flags = NTPFlags::from(buf[0]);
+        if !(1..=NTP_VERSION).contains(&flags.v.0) {
+            return Err(ParseError::BadVersion(flags.v.0));
+        }
+        // NTPv1 has no mode field, its clients leave it zero
+        if flags.v.0 == 1 && flags.m == Mode::Unspecified {
+            flags.m = Mode::Client;
+        }
+        match flags.m {
+            Mode::Unspecified | Mode::ControlMessage | Mode::Reserved => {
+                return Err(ParseError::UnsupportedMode(flags.m))
+            }
+            _ => (),
+        }
+        let transmit_tstamp = NTPTimestamp::deserialize(&buf[40..48]);
+        if transmit_tstamp.is_zero() {
+            return Err(ParseError::ZeroTransmitTimestamp);
+        }
+        let (extensions, mac) = Self::deserialize_extensions(&buf[NTP_MESSAGE_LEN..])?;
+        let peer_stratum = buf[1];
+        Ok(NTPMessage {
+            flags,
+            peer_stratum,
             peer_polling_interval: buf[2],
-            transmit_tstamp: NTPTimestamp::deserialize(&buf[40..48]),
+            peer_clock_precision: PeerPrecision(buf[3] as i8),
+            root_delay: Fix32::deserialize(&buf[4..8]),
+            root_dispersion: Fix32::deserialize(&buf[8..12]),
+            ref_id: Reference::deserialize(&buf[12..16], peer_stratum),
+            ref_tstamp: NTPTimestamp::deserialize(&buf[16..24]),
+            origin_tstamp: NTPTimestamp::deserialize(&buf[24..32]),
+            rcv_tstamp: NTPTimestamp::deserialize(&buf[32..40]),
+            transmit_tstamp,
+            extensions,
+            mac,
         })
     }
-}
 
-impl NTPMessage {
+    /// Extension fields, followed by an optional MAC
+    fn deserialize_extensions(
+        mut buf: &[u8],
+    ) -> Result<(Vec<ExtensionField>, Option<Mac>), ParseError> {
+        let mut extensions = vec![];
+        let mut last_len = EXTENSION_LAST_MIN_LEN;
+        while buf.len() > MAC_MAX_LEN {
+            let (field, len) = ExtensionField::deserialize(buf)?;
+            extensions.push(field);
+            last_len = len;
+            buf = &buf[len..];
+        }
+        match buf.len() {
+            0 => {
+                if last_len < EXTENSION_LAST_MIN_LEN {
+                    return Err(ParseError::BadExtensionLength(last_len as u16));
+                }
+                Ok((extensions, None))
+            }
+            // crypto-NAK, 128 bit digest (MD5, AES-CMAC) or 160 bit digest (SHA-1)
+            4 | 20 | 24 => Ok((extensions, Some(Mac::deserialize(buf)))),
+            len => Err(ParseError::TrailingData(len)),
+        }
+    }
+
+    /// Turns an answer into a kiss-o'-death (RFC 5905 section 7.4) carrying `code`, dropping
+    /// the extension fields and the MAC
+    pub fn kiss_o_death(&mut self, code: [u8; 4]) {
+        self.flags.l = LeapIndicator::Alarm;
+        self.peer_stratum = 0;
+        self.ref_id = Reference::Ascii(code);
+        self.extensions = vec![];
+        self.mac = None;
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
+        let mut message = self.serialize_authenticated();
+        if let Some(mac) = &self.mac {
+            message.extend(mac.serialize());
+        }
+        message
+    }
+
+    /// Everything the MAC is computed over: the header and the extension fields
+    pub fn serialize_authenticated(&self) -> Vec<u8> {
+        self.serialize_until(self.extensions.len())
+    }
+
+    /// What the MAC covers in `datagram`, which this message was parsed from
+    pub fn received_authenticated<'a>(&self, datagram: &'a [u8]) -> &'a [u8] {
+        let mac_len = self.mac.as_ref().map_or(0, |m| 4 + m.digest.len());
+        &datagram[..datagram.len().saturating_sub(mac_len)]
+    }
+
+    /// The header and the extension fields before index `extension` in `datagram`, which this
+    /// message was parsed from; an NTS authenticator at that index covers this
+    pub fn received_until<'a>(&self, datagram: &'a [u8], extension: usize) -> &'a [u8] {
+        let mut end = NTP_MESSAGE_LEN;
+        for _ in 0..extension.min(self.extensions.len()) {
+            let Some(len) = datagram.get(end + 2..end + 4) else {
+                break;
+            };
+            end += usize::from(u16::from_be_bytes([len[0], len[1]]));
+        }
+        &datagram[..end.min(datagram.len())]
+    }
+
+    /// The header and the extension fields before index `extension`; an NTS authenticator at
+    /// that index is computed over this
+    pub fn serialize_until(&self, extension: usize) -> Vec<u8> {
         let mut message = Vec::with_capacity(NTP_MESSAGE_LEN);
         message.push((&self.flags).into());
         message.push(self.peer_stratum);
@@ -257,6 +640,15 @@ impl NTPMessage {
         message.extend(self.origin_tstamp.serialize());
         message.extend(self.rcv_tstamp.serialize());
         message.extend(self.transmit_tstamp.serialize());
+        let extension = extension.min(self.extensions.len());
+        for (i, ext) in self.extensions[..extension].iter().enumerate() {
+            let min_len = if i == self.extensions.len() - 1 && self.mac.is_none() {
+                EXTENSION_LAST_MIN_LEN
+            } else {
+                EXTENSION_MIN_LEN
+            };
+            message.extend(ext.serialize(min_len));
+        }
         message
     }
 }
@@ -309,6 +701,63 @@ mod tests {
         )
     }
 
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        DateTime::<Utc>::from_naive_utc_and_offset(
+            NaiveDate::from_ymd_opt(y, mo, d)
+                .unwrap()
+                .and_hms_opt(h, mi, s)
+                .unwrap(),
+            Utc,
+        )
+    }
+
+    #[test]
+    fn test_timestamp_fraction() {
+        let ts = NTPTimestamp::from(ntp_zero() + TimeDelta::milliseconds(500));
+        assert_eq!(ts.frac_part, 0x8000_0000);
+        let ts = NTPTimestamp::from(ntp_zero() + TimeDelta::milliseconds(250));
+        assert_eq!(ts.frac_part, 0x4000_0000);
+        let ts = NTPTimestamp::from(ntp_zero() + TimeDelta::nanoseconds(999_999_999));
+        assert_eq!(ts.frac_part, 0xffff_fffb);
+    }
+
+    #[test]
+    fn test_timestamp_eras() {
+        let era1 = utc(2036, 2, 7, 6, 28, 16);
+        let ts = NTPTimestamp::from(era1);
+        assert_eq!(ts.int_part, 0);
+        assert_eq!(DateTime::<Utc>::from(ts), era1);
+
+        let before = era1 - TimeDelta::seconds(1);
+        assert_eq!(NTPTimestamp::from(before).int_part, u32::MAX);
+        assert_eq!(DateTime::<Utc>::from(NTPTimestamp::from(before)), before);
+    }
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        for dt in [
+            utc(1970, 1, 1, 0, 0, 0),
+            utc(2004, 9, 27, 3, 16, 10) + TimeDelta::nanoseconds(123_456_789),
+            utc(2024, 2, 29, 23, 59, 59) + TimeDelta::nanoseconds(999_999_999),
+            utc(2040, 6, 1, 12, 0, 0) + TimeDelta::nanoseconds(1),
+            utc(2100, 1, 1, 0, 0, 0),
+        ] {
+            assert_eq!(DateTime::<Utc>::from(NTPTimestamp::from(dt)), dt);
+        }
+    }
+
+    #[test]
+    fn test_timestamp_custom_pivot() {
+        let dt = utc(1950, 1, 1, 0, 0, 0);
+        let ts = NTPTimestamp::from(dt);
+        // with the default pivot, this lands in era 1
+        assert_eq!(
+            DateTime::<Utc>::from(ts),
+            dt + TimeDelta::seconds(NTP_ERA_SECONDS)
+        );
+        assert_eq!(ts.to_datetime(ntp_zero()), dt);
+    }
+
     #[test]
     fn test_peer_precision_duration() {
         assert_eq!(PeerPrecision::from(Duration::from_nanos(1)).0, -30);
@@ -355,6 +804,245 @@ mod tests {
         let got = Fix32::from(Duration::from_micros(16));
         let expected = Fix32 { i: 0, f: 1 };
         assert_eq!(expected, got);
+
+        let got = Fix32::from(Duration::from_millis(1500));
+        let expected = Fix32 { i: 1, f: 32768 };
+        assert_eq!(expected, got);
+    }
+
+    fn sample_message() -> NTPMessage {
+        let ts = DateTime::<Utc>::from_naive_utc_and_offset(
+            NaiveDate::from_ymd_opt(2004, 9, 27)
+                .unwrap()
+                .and_hms_opt(3, 16, 10)
+                .unwrap(),
+            Utc,
+        );
+        NTPMessage {
+            flags: NTPFlags {
+                v: VersionNumber(4),
+                l: LeapIndicator::LastMinuteHas61Seconds,
+                m: Mode::Server,
+            },
+            peer_stratum: 2,
+            peer_polling_interval: 6,
+            peer_clock_precision: PeerPrecision(-20),
+            root_delay: Fix32 { i: 1, f: 0x1234 },
+            root_dispersion: Fix32 { i: 0, f: 0x14 },
+            ref_id: Reference::IPv4(Ipv4Addr::new(10, 0, 0, 1)),
+            ref_tstamp: NTPTimestamp::from(ts),
+            origin_tstamp: NTPTimestamp {
+                int_part: 1,
+                frac_part: 2,
+            },
+            rcv_tstamp: NTPTimestamp {
+                int_part: 3,
+                frac_part: 4,
+            },
+            transmit_tstamp: NTPTimestamp {
+                int_part: 5,
+                frac_part: 6,
+            },
+            extensions: vec![],
+            mac: None,
+        }
+    }
+
+    #[test]
+    fn deserialize_flags() {
+        let flags = NTPFlags::from(0xd9);
+        assert_eq!(flags.l, LeapIndicator::Alarm);
+        assert_eq!(flags.v, VersionNumber(3));
+        assert_eq!(flags.m, Mode::SymActive);
+    }
+
+    #[test]
+    fn test_deserialize_ref_id() {
+        let gps = [b'G', b'P', b'S', 0];
+        assert_eq!(Reference::deserialize(&gps, 1), Reference::GPS);
+        assert_eq!(
+            Reference::deserialize(b"RATE", 0),
+            Reference::Ascii(*b"RATE")
+        );
+        assert_eq!(
+            Reference::deserialize(&gps, 2),
+            Reference::IPv4(Ipv4Addr::new(b'G', b'P', b'S', 0))
+        );
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let m = sample_message();
+        let buf = m.serialize();
+        assert_eq!(buf.len(), NTP_MESSAGE_LEN);
+        assert_eq!(NTPMessage::deserialize(&buf), Ok(m));
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        let buf = sample_message().serialize();
+        assert_eq!(
+            NTPMessage::deserialize(&buf[..47]),
+            Err(ParseError::ShortPacket(47))
+        );
+
+        let mut bad = buf.clone();
+        bad[0] = 0b00_101_011;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::BadVersion(5))
+        );
+        bad[0] = 0b00_000_011;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::BadVersion(0))
+        );
+
+        let mut bad = buf.clone();
+        bad[0] = 0b00_100_110;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::UnsupportedMode(Mode::ControlMessage))
+        );
+        bad[0] = 0b00_011_000;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::UnsupportedMode(Mode::Unspecified))
+        );
+        // an NTPv1 client
+        bad[0] = 0b00_001_000;
+        let q = NTPMessage::deserialize(&bad).unwrap();
+        assert_eq!(q.flags.v, VersionNumber(1));
+        assert_eq!(q.flags.m, Mode::Client);
+
+        let mut bad = buf.clone();
+        bad[40..48].copy_from_slice(&[0; 8]);
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::ZeroTransmitTimestamp)
+        );
+    }
+
+    #[test]
+    fn extension_padding() {
+        let mut m = sample_message();
+        m.extensions = vec![
+            ExtensionField::new(ExtensionFieldType::UniqueIdentifier, &[1; 5]),
+            ExtensionField::new(ExtensionFieldType::Unknown(0x2005), &[2; 3]),
+        ];
+        let buf = m.serialize();
+        // 4 byte header + 5 bytes padded to 16; last one is padded up to 28
+        assert_eq!(buf.len(), NTP_MESSAGE_LEN + 16 + 28);
+        #[rustfmt::skip]
+        assert_eq!(
+            buf[NTP_MESSAGE_LEN..NTP_MESSAGE_LEN + 20],
+            [
+                0x01, 0x04, 0, 16, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0,
+                0x20, 0x05, 0, 28,
+            ]
+        );
+
+        let parsed = NTPMessage::deserialize(&buf).unwrap();
+        assert_eq!(parsed.extensions.len(), 2);
+        assert_eq!(
+            parsed.extensions[0].field_type,
+            ExtensionFieldType::UniqueIdentifier
+        );
+        assert_eq!(parsed.extensions[0].value[..5], [1; 5]);
+        assert_eq!(
+            parsed.extensions[1].field_type,
+            ExtensionFieldType::Unknown(0x2005)
+        );
+        assert_eq!(parsed.extensions[1].value.len(), 24);
+        // once padded, fields round trip exactly
+        assert_eq!(parsed.serialize(), buf);
+
+        assert_eq!(m.serialize_until(1), buf[..NTP_MESSAGE_LEN + 16]);
+        assert_eq!(m.serialize_until(5), buf);
+        assert_eq!(parsed.received_until(&buf, 5), buf);
+    }
+
+    #[test]
+    fn extension_length_errors() {
+        let mut m = sample_message();
+        m.extensions = vec![ExtensionField::new(ExtensionFieldType::NtsCookie, &[0; 40])];
+        let buf = m.serialize();
+
+        let mut bad = buf.clone();
+        bad[NTP_MESSAGE_LEN + 3] = 45;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::BadExtensionLength(45))
+        );
+        bad[NTP_MESSAGE_LEN + 3] = 48;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::BadExtensionLength(48))
+        );
+        bad[NTP_MESSAGE_LEN + 3] = 12;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::BadExtensionLength(12))
+        );
+
+        // a 16 byte field can't be last, without a MAC
+        let mut bad = buf[..NTP_MESSAGE_LEN + 16].to_vec();
+        bad[NTP_MESSAGE_LEN + 3] = 16;
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::TrailingData(16))
+        );
+        let mut bad = buf[..NTP_MESSAGE_LEN + 16 + 28].to_vec();
+        bad[NTP_MESSAGE_LEN + 3] = 16;
+        bad[NTP_MESSAGE_LEN + 16 + 3] = 28;
+        assert!(NTPMessage::deserialize(&bad).is_ok());
+
+        let mut bad = buf.clone();
+        bad.extend([0; 3]);
+        assert_eq!(
+            NTPMessage::deserialize(&bad),
+            Err(ParseError::TrailingData(3))
+        );
+    }
+
+    #[test]
+    fn mac_roundtrip() {
+        let mut m = sample_message();
+        m.mac = Some(Mac {
+            key_id: 7,
+            digest: vec![0xaa; 20],
+        });
+        let buf = m.serialize();
+        assert_eq!(buf.len(), NTP_MESSAGE_LEN + 24);
+        assert_eq!(buf[NTP_MESSAGE_LEN..NTP_MESSAGE_LEN + 4], [0, 0, 0, 7]);
+        assert_eq!(NTPMessage::deserialize(&buf), Ok(m));
+
+        let mut m = sample_message();
+        m.mac = Some(Mac::crypto_nak());
+        let buf = m.serialize();
+        assert_eq!(buf.len(), NTP_MESSAGE_LEN + 4);
+        let parsed = NTPMessage::deserialize(&buf).unwrap();
+        assert!(parsed.mac.unwrap().is_crypto_nak());
+    }
+
+    #[test]
+    fn mac_after_extension() {
+        let mut m = sample_message();
+        m.extensions = vec![ExtensionField::new(
+            ExtensionFieldType::Unknown(0x2005),
+            &[1; 4],
+        )];
+        m.mac = Some(Mac {
+            key_id: 1,
+            digest: vec![0xbb; 16],
+        });
+        let buf = m.serialize();
+        // with a MAC following it, the last extension field only needs 16 bytes
+        assert_eq!(buf.len(), NTP_MESSAGE_LEN + 16 + 20);
+        let parsed = NTPMessage::deserialize(&buf).unwrap();
+        assert_eq!(parsed.extensions.len(), 1);
+        assert_eq!(parsed.mac, m.mac);
+        assert_eq!(parsed.serialize(), buf);
     }
 
     #[test]
@@ -382,6 +1070,8 @@ mod tests {
             origin_tstamp: NTPTimestamp::from(ts),
             rcv_tstamp: NTPTimestamp::from(ts),
             transmit_tstamp: NTPTimestamp::from(ts),
+            extensions: vec![],
+            mac: None,
         };
 
         #[rustfmt::skip]
@@ -0,0 +1,224 @@
+//! Per client rate limiting, answered with RATE kiss-o'-death packets (RFC 5905 section 7.4)
+use crate::restrict::{mask, unmap};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// A client can sustain one query per `interval` indefinitely
+    pub interval: Duration,
+    /// Queries a client can send back to back, like clients do when starting up
+    pub burst: u32,
+    /// Clients over their budget get at most one kiss-o'-death per `kod_interval`, any other
+    /// query is dropped without an answer
+    pub kod_interval: Duration,
+    /// Clients within the same prefix share a bucket
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Upper bound on the number of buckets kept in memory
+    pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            interval: Duration::from_secs(2),
+            burst: 8,
+            kod_interval: Duration::from_secs(8),
+            ipv4_prefix: 32,
+            // a single host usually gets a whole /64
+            ipv6_prefix: 64,
+            max_clients: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Answer with a RATE kiss-o'-death
+    Kod,
+    Drop,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f32,
+    updated: Instant,
+    last_kod: Option<Instant>,
+}
+
+/// Token buckets keyed by client prefix
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Number of buckets in use
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Makes room for a new bucket. Buckets idle for long enough to be full again are the same
+    /// as new ones, so they go first; if none are, the least recently used one goes.
+    fn evict(&mut self, now: Instant) {
+        let refill = self.config.interval * self.config.burst;
+        self.clients
+            .retain(|_, b| now.saturating_duration_since(b.updated) < refill);
+        if self.clients.len() < self.config.max_clients {
+            return;
+        }
+        let oldest = self
+            .clients
+            .iter()
+            .min_by_key(|(_, b)| b.updated)
+            .map(|(k, _)| *k);
+        if let Some(k) = oldest {
+            self.clients.remove(&k);
+        }
+    }
+
+    /// Accounts for one query from `addr`, received at `now`
+    pub fn check(&mut self, addr: IpAddr, now: Instant) -> Decision {
+        let c = self.config;
+        let addr = unmap(addr);
+        let prefix = if addr.is_ipv4() {
+            c.ipv4_prefix
+        } else {
+            c.ipv6_prefix
+        };
+        let key = mask(addr, prefix);
+        if !self.clients.contains_key(&key) && self.clients.len() >= c.max_clients {
+            self.evict(now);
+        }
+        let b = self.clients.entry(key).or_insert(Bucket {
+            tokens: c.burst as f32,
+            updated: now,
+            last_kod: None,
+        });
+        let elapsed = now.saturating_duration_since(b.updated);
+        b.tokens =
+            (b.tokens + elapsed.as_secs_f32() / c.interval.as_secs_f32()).min(c.burst as f32);
+        b.updated = now;
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            return Decision::Allow;
+        }
+        match b.last_kod {
+            Some(t) if now.saturating_duration_since(t) < c.kod_interval => Decision::Drop,
+            _ => {
+                b.last_kod = Some(now);
+                Decision::Kod
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 1, d))
+    }
+
+    #[test]
+    fn burst_kod_then_drop() {
+        let mut r = RateLimiter::new(RateLimitConfig::default());
+        let t0 = Instant::now();
+        for _ in 0..8 {
+            assert_eq!(r.check(v4(1), t0), Decision::Allow);
+        }
+        assert_eq!(r.check(v4(1), t0), Decision::Kod);
+        assert_eq!(r.check(v4(1), t0), Decision::Drop);
+        // other clients are unaffected
+        assert_eq!(r.check(v4(2), t0), Decision::Allow);
+
+        // one token every 2s
+        let t = t0 + Duration::from_secs(2);
+        assert_eq!(r.check(v4(1), t), Decision::Allow);
+        assert_eq!(r.check(v4(1), t), Decision::Drop);
+        // 6s later, 3 more tokens; the last kiss-o'-death is old enough to send another
+        let t = t0 + Duration::from_secs(8);
+        for _ in 0..3 {
+            assert_eq!(r.check(v4(1), t), Decision::Allow);
+        }
+        assert_eq!(r.check(v4(1), t), Decision::Kod);
+    }
+
+    #[test]
+    fn polite_clients_are_always_answered() {
+        let mut r = RateLimiter::new(RateLimitConfig::default());
+        let t0 = Instant::now();
+        for i in 0..100 {
+            let t = t0 + Duration::from_secs(2 * i);
+            assert_eq!(r.check(v4(1), t), Decision::Allow);
+        }
+    }
+
+    #[test]
+    fn prefixes_share_buckets() {
+        let mut r = RateLimiter::new(RateLimitConfig {
+            burst: 1,
+            ipv4_prefix: 24,
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+        assert_eq!(r.check(v4(1), t0), Decision::Allow);
+        assert_eq!(r.check(v4(2), t0), Decision::Kod);
+        assert_eq!(
+            r.check(IpAddr::V4(Ipv4Addr::new(192, 168, 2, 1)), t0),
+            Decision::Allow
+        );
+
+        let a = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let b = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 1, 2, 3, 4));
+        let c = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 1));
+        assert_eq!(r.check(a, t0), Decision::Allow);
+        assert_eq!(r.check(b, t0), Decision::Kod);
+        assert_eq!(r.check(c, t0), Decision::Allow);
+
+        // IPv4 clients on an IPv6 socket are bucketed by their IPv4 prefix
+        let mapped = |c: u8, d: u8| IpAddr::V6(Ipv4Addr::new(192, 168, c, d).to_ipv6_mapped());
+        assert_eq!(r.check(mapped(3, 1), t0), Decision::Allow);
+        assert_eq!(r.check(mapped(4, 1), t0), Decision::Allow);
+        assert_eq!(
+            r.check(IpAddr::V4(Ipv4Addr::new(192, 168, 3, 2)), t0),
+            Decision::Kod
+        );
+        assert_eq!(r.check(mapped(1, 9), t0), Decision::Drop);
+    }
+
+    #[test]
+    fn bounded_table() {
+        let mut r = RateLimiter::new(RateLimitConfig {
+            max_clients: 4,
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+        for i in 0..=255 {
+            let t = t0 + Duration::from_millis(i as u64);
+            assert_eq!(r.check(v4(i), t), Decision::Allow);
+            assert!(r.len() <= 4);
+        }
+        // the most recent clients are still tracked
+        for _ in 0..7 {
+            r.check(v4(255), t0 + Duration::from_secs(1));
+        }
+        assert_eq!(r.check(v4(255), t0 + Duration::from_secs(1)), Decision::Kod);
+    }
+}
@@ -0,0 +1,233 @@
+//! Access control for the NTP listener, in the spirit of ntpd's `restrict`
+use std::fmt;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Serve,
+    /// Drop packets without an answer
+    Ignore,
+    /// Answer with a DENY kiss-o'-death
+    Kod,
+    /// Serve time, but not control queries; this server never answers control queries, so in
+    /// practice it is the same as `Serve`
+    NoQuery,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Serve => "serve",
+            Action::Ignore => "ignore",
+            Action::Kod => "kod",
+            Action::NoQuery => "noquery",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Action> {
+        match s {
+            "serve" => Some(Action::Serve),
+            "ignore" => Some(Action::Ignore),
+            "kod" => Some(Action::Kod),
+            "noquery" => Some(Action::NoQuery),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RuleError {
+    /// Line number of a line that does not look like `restrict address action`
+    Malformed(usize),
+    /// Not an address, or a prefix longer than the address
+    BadAddress(usize),
+    UnknownAction(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Only the first `prefix` bits are set
+    pub network: IpAddr,
+    pub prefix: u8,
+    pub action: Action,
+}
+
+/// IPv4 clients on an IPv6 socket show up as IPv4-mapped addresses
+pub(crate) fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Keeps the first `prefix` bits of `addr`
+pub(crate) fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(a) => {
+            let m = u32::MAX
+                .checked_shl(32 - prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4((u32::from(a) & m).into())
+        }
+        IpAddr::V6(a) => {
+            let m = u128::MAX
+                .checked_shl(128 - prefix.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(a) & m).into())
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, addr: IpAddr) -> bool {
+        addr.is_ipv4() == self.network.is_ipv4() && mask(addr, self.prefix) == self.network
+    }
+}
+
+/// The rule with the longest matching prefix wins; addresses no rule matches get the default
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restrictions {
+    pub default: Action,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Restrictions {
+    fn default() -> Self {
+        Restrictions {
+            default: Action::Serve,
+            rules: vec![],
+        }
+    }
+}
+
+impl Restrictions {
+    pub fn new() -> Restrictions {
+        Restrictions::default()
+    }
+
+    pub fn action(&self, addr: IpAddr) -> Action {
+        let addr = unmap(addr);
+        self.rules
+            .iter()
+            .filter(|r| r.matches(addr))
+            .max_by_key(|r| r.prefix)
+            .map_or(self.default, |r| r.action)
+    }
+
+    /// One rule per line, `restrict <default | address[/prefix]> <serve | ignore | kod | noquery>`,
+    /// with `#` comments. Without a prefix the rule is for a single address.
+    pub fn parse(text: &str) -> Result<Restrictions, RuleError> {
+        let mut r = Restrictions::new();
+        for (i, line) in text.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 || parts[0] != "restrict" {
+                return Err(RuleError::Malformed(lineno));
+            }
+            let action = Action::parse(parts[2]).ok_or(RuleError::UnknownAction(lineno))?;
+            if parts[1] == "default" {
+                r.default = action;
+                continue;
+            }
+            let (addr, prefix) = match parts[1].split_once('/') {
+                Some((a, p)) => (a, Some(p)),
+                None => (parts[1], None),
+            };
+            let addr: IpAddr = addr.parse().map_err(|_| RuleError::BadAddress(lineno))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(p) => p.parse().map_err(|_| RuleError::BadAddress(lineno))?,
+                None => max,
+            };
+            if prefix > max {
+                return Err(RuleError::BadAddress(lineno));
+            }
+            r.rules.push(Rule {
+                network: mask(addr, prefix),
+                prefix,
+                action,
+            });
+        }
+        Ok(r)
+    }
+}
+
+/// In the format `parse` reads
+impl fmt::Display for Restrictions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "restrict default {}", self.default.name())?;
+        for r in &self.rules {
+            writeln!(f, "restrict {}/{} {}", r.network, r.prefix, r.action.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "
+# serve the LAN, tell everyone else to go away
+restrict default kod
+restrict 192.168.0.0/16 serve
+restrict 192.168.66.0/24 ignore  # the guest network
+restrict 192.168.66.10 noquery
+restrict 2001:db8::/32 serve
+";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let r = Restrictions::parse(RULES).unwrap();
+        assert_eq!(r.action(ip("8.8.8.8")), Action::Kod);
+        assert_eq!(r.action(ip("192.168.1.1")), Action::Serve);
+        assert_eq!(r.action(ip("192.168.66.1")), Action::Ignore);
+        assert_eq!(r.action(ip("192.168.66.10")), Action::NoQuery);
+        assert_eq!(r.action(ip("2001:db8::1")), Action::Serve);
+        assert_eq!(r.action(ip("2001:db9::1")), Action::Kod);
+        assert_eq!(r.action(ip("::ffff:192.168.66.1")), Action::Ignore);
+        // an IPv6 rule never matches IPv4 addresses, even if the bits do
+        let r = Restrictions::parse("restrict ::/0 ignore").unwrap();
+        assert_eq!(r.action(ip("10.0.0.1")), Action::Serve);
+        assert_eq!(r.action(ip("fe80::1")), Action::Ignore);
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let r = Restrictions::parse(RULES).unwrap();
+        assert_eq!(Restrictions::parse(&r.to_string()).unwrap(), r);
+        assert_eq!(Restrictions::new().to_string(), "restrict default serve\n");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Restrictions::parse("restrict 10.0.0.0/8").unwrap_err(),
+            RuleError::Malformed(1)
+        );
+        assert_eq!(
+            Restrictions::parse("\nallow 10.0.0.0/8 serve").unwrap_err(),
+            RuleError::Malformed(2)
+        );
+        assert_eq!(
+            Restrictions::parse("restrict 10.0.0.0/33 serve").unwrap_err(),
+            RuleError::BadAddress(1)
+        );
+        assert_eq!(
+            Restrictions::parse("restrict example.com serve").unwrap_err(),
+            RuleError::BadAddress(1)
+        );
+        assert_eq!(
+            Restrictions::parse("restrict default nomodify").unwrap_err(),
+            RuleError::UnknownAction(1)
+        );
+    }
+}
@@ -1,49 +1,389 @@
+use crate::auth::KeyTable;
+use crate::leap::{LeapMode, LeapSecond};
+use crate::nts::{self, CookieJar, NtsError, NtsQuery};
 use crate::proto::*;
-use chrono::{DateTime, Utc};
+use crate::restrict::Restrictions;
+use crate::source::{self, SourceUpdate, Sources, TimeSource};
+use chrono::{DateTime, TimeDelta, Utc};
 use std::time::Duration;
+
+/// How the server degrades once reference time updates stop arriving
+#[derive(Debug, Clone, Copy)]
+pub struct HoldoverConfig {
+    /// Assumed frequency error of the local clock; root dispersion grows by this much per second
+    /// since the last update. RFC 5905 uses 15ppm.
+    pub drift_ppm: f32,
+    /// Updates older than this put the server in holdover
+    pub holdover_after: Duration,
+    /// Updates older than this make the server advertise `degraded_stratum`
+    pub degrade_after: Duration,
+    pub degraded_stratum: u8,
+    /// Updates older than this make the server announce itself as unsynchronized
+    pub alarm_after: Duration,
+}
+
+impl Default for HoldoverConfig {
+    fn default() -> Self {
+        HoldoverConfig {
+            drift_ppm: 15.0,
+            holdover_after: Duration::from_secs(16),
+            degrade_after: Duration::from_secs(60 * 60),
+            degraded_stratum: 2,
+            alarm_after: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncState {
+    /// No reference time was ever received
+    Unsynchronized,
+    Locked,
+    /// Recent updates are missing, still serving as stratum 1
+    Holdover,
+    /// Serving with a worse stratum
+    Degraded,
+    /// The last update is too old to be trusted
+    Alarm,
+}
+
+impl SyncState {
+    /// Short, stable name; useful as a metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncState::Unsynchronized => "unsynchronized",
+            SyncState::Locked => "locked",
+            SyncState::Holdover => "holdover",
+            SyncState::Degraded => "degraded",
+            SyncState::Alarm => "alarm",
+        }
+    }
+}
+
+/// Why a well-formed packet was not answered
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// Only client (mode 3) queries are answered; anything else, including our own responses
+    /// reflected back, could set up a reply loop between servers
+    UnexpectedMode(Mode),
+    /// The NTS extension fields are missing, duplicated or out of place
+    MalformedNts,
+}
+
+impl Rejection {
+    /// Short, stable name; useful as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::UnexpectedMode(Mode::SymActive) => "mode_symmetric_active",
+            Rejection::UnexpectedMode(Mode::SymPassive) => "mode_symmetric_passive",
+            Rejection::UnexpectedMode(Mode::Server) => "mode_server",
+            Rejection::UnexpectedMode(Mode::Broadcast) => "mode_broadcast",
+            Rejection::UnexpectedMode(_) => "mode_other",
+            Rejection::MalformedNts => "nts_malformed",
+        }
+    }
+}
+
+/// Number of clock reads done by `measure_precision`
+const PRECISION_SAMPLES: usize = 128;
+
+/// Reads the clock repeatedly and returns the smallest non-zero step between two readings; this
+/// is the resolution of the clock, or the time it takes to read it, whichever is larger.
+pub fn measure_precision(clock: impl Fn() -> DateTime<Utc>) -> Duration {
+    let mut best = Duration::MAX;
+    let mut prev = clock();
+    for _ in 0..PRECISION_SAMPLES {
+        let t = clock();
+        if let Ok(step) = (t - prev).to_std() {
+            if step > Duration::ZERO {
+                best = best.min(step);
+            }
+        }
+        prev = t;
+    }
+    if best == Duration::MAX {
+        return Duration::from_micros(1);
+    }
+    best
+}
+
 pub struct GPSServer {
     pub reftime: Option<DateTime<Utc>>,
+    /// Error estimate of the reference time, as reported by the receiver
+    pub reference_error: Duration,
+    /// Resolution of the local clock used to timestamp packets, see `measure_precision`
+    pub clock_precision: Duration,
+    pub holdover: HoldoverConfig,
+    /// Keys for queries carrying a MAC
+    pub keys: KeyTable,
+    /// Opens the cookies handed out during NTS-KE; NTS queries are NAKed without it
+    pub cookies: Option<CookieJar>,
+    /// Checked by the UDP loops before answering
+    pub restrictions: Restrictions,
+    /// GPS, and whatever other sources are fed with `update_source`
+    pub sources: Sources,
+    /// Announced on the day of the event; kept until the local clock is corrected
+    pub leap: Option<LeapSecond>,
+    pub leap_mode: LeapMode,
 }
 
 impl GPSServer {
     pub fn new() -> GPSServer {
-        GPSServer { reftime: None }
+        GPSServer::with_holdover(HoldoverConfig::default())
+    }
+
+    pub fn with_holdover(holdover: HoldoverConfig) -> GPSServer {
+        GPSServer {
+            reftime: None,
+            reference_error: Duration::ZERO,
+            clock_precision: Duration::from_micros(1),
+            holdover,
+            keys: KeyTable::new(),
+            cookies: None,
+            restrictions: Restrictions::new(),
+            sources: Sources::new(),
+            leap: None,
+            leap_mode: LeapMode::Step,
+        }
     }
 
-    pub fn update_reference_time(&mut self, dt: DateTime<Utc>) {
+    pub fn set_keys(&mut self, keys: KeyTable) {
+        self.keys = keys;
+    }
+
+    pub fn set_restrictions(&mut self, restrictions: Restrictions) {
+        self.restrictions = restrictions;
+    }
+
+    /// Has to be the jar the NTS-KE server makes cookies with
+    pub fn set_cookie_jar(&mut self, cookies: CookieJar) {
+        self.cookies = Some(cookies);
+    }
+
+    /// The response is signed with the key the query used; queries that fail authentication,
+    /// including those using unknown keys, get a crypto-NAK
+    fn authenticate(&self, q: &NTPMessage, datagram: &[u8], mac: &Mac, answer: &NTPMessage) -> Mac {
+        match self.keys.get(mac.key_id) {
+            Some(key) if key.verify(q.received_authenticated(datagram), &mac.digest) => Mac {
+                key_id: mac.key_id,
+                digest: key.digest(&answer.serialize_authenticated()),
+            },
+            _ => Mac::crypto_nak(),
+        }
+    }
+
+    pub fn set_clock_precision(&mut self, precision: Duration) {
+        self.clock_precision = precision;
+    }
+
+    /// `error` is the receiver's estimate of how far `dt` is from true time
+    pub fn update_reference_time(&mut self, dt: DateTime<Utc>, error: Duration) {
         self.reftime = Some(dt);
+        self.reference_error = error;
+        // the local clock is steered to GPS time, see `update_source` for sources that do not
+        self.sources.update(SourceUpdate {
+            source: TimeSource::Gps,
+            at: dt,
+            offset: TimeDelta::zero(),
+            error,
+        });
+    }
+
+    /// The next leap second, as announced by the receiver. Only `leap_applied` clears it: once
+    /// the event passed, receivers stop announcing it before the local clock may be corrected.
+    /// A later event waits until the pending one is applied.
+    pub fn set_leap_second(&mut self, leap: LeapSecond) {
+        match self.leap {
+            Some(pending) if pending.passed(leap.at) && pending != leap => (),
+            _ => self.leap = Some(leap),
+        }
+    }
+
+    /// A receiver's count down at local time `now`, see `LeapSecond::from_countdown`; counts
+    /// without an upcoming event leave the pending one alone
+    pub fn leap_countdown(&mut self, now: DateTime<Utc>, seconds: i64, change: i8) {
+        if let Some(leap) = LeapSecond::from_countdown(now, seconds, change) {
+            self.set_leap_second(leap);
+        }
+    }
+
+    pub fn set_leap_mode(&mut self, mode: LeapMode) {
+        self.leap_mode = mode;
+    }
+
+    /// The leap second the local clock has to be corrected for by `now`, if any; once it is, call
+    /// `leap_applied`
+    pub fn leap_due(&self, now: DateTime<Utc>) -> Option<LeapSecond> {
+        self.leap.filter(|l| l.due(now, self.leap_mode))
+    }
+
+    /// The local clock was corrected for the leap second, timestamps are served as they are again
+    pub fn leap_applied(&mut self) {
+        self.leap = None;
+    }
+
+    /// UTC at local time `t`: between the leap second and the local clock being corrected, the
+    /// clock is off by the leap. Offsets to the local clock are to be measured against this.
+    pub fn leap_corrected(&self, t: DateTime<Utc>) -> DateTime<Utc> {
+        match self.leap {
+            Some(leap) if leap.passed(t) => t + leap.correction(),
+            _ => t,
+        }
+    }
+
+    /// Local time at UTC `t`, for a clock following UTC that is only corrected for the leap
+    /// second once it is due; the inverse of `leap_corrected`
+    pub fn leap_uncorrected(&self, t: DateTime<Utc>) -> DateTime<Utc> {
+        match self.leap {
+            // UTC does not repeat an inserted second
+            Some(leap) if t >= leap.midnight() => t - leap.correction(),
+            _ => t,
+        }
+    }
+
+    /// The time served for local time `t`
+    fn served(&self, t: DateTime<Utc>) -> DateTime<Utc> {
+        match (self.leap, self.leap_mode) {
+            (Some(leap), LeapMode::Smear { window }) => t + leap.smear(t, window),
+            _ => self.leap_corrected(t),
+        }
+    }
+
+    /// A reading of a source other than GPS, e.g. an upstream server or the RTC
+    pub fn update_source(&mut self, update: SourceUpdate) {
+        self.sources.update(update);
+    }
+
+    /// Lower is better: GPS while it is locked or in holdover, upstream servers by stratum, a
+    /// degraded GPS, and the RTC as the last resort
+    fn rank(&self, u: &SourceUpdate, now: DateTime<Utc>) -> u32 {
+        match u.source {
+            TimeSource::Gps => match self.state(now) {
+                SyncState::Locked | SyncState::Holdover => 0,
+                _ => 1000,
+            },
+            TimeSource::Ntp { stratum, .. } => 100 + stratum as u32,
+            TimeSource::Rtc => 2000,
+        }
+    }
+
+    /// The source answers are based on; None once every source is too old, or upstream servers
+    /// are unsynchronized themselves
+    pub fn selected(&self, now: DateTime<Utc>) -> Option<SourceUpdate> {
+        let candidates: Vec<SourceUpdate> = self
+            .sources
+            .iter()
+            .filter(|u| (now - u.at).to_std().unwrap_or(Duration::ZERO) < self.holdover.alarm_after)
+            .filter(|u| u.source.stratum() < NTP_STRATUM_UNSYNCHRONIZED)
+            .copied()
+            .collect();
+        source::select(&candidates, now, self.holdover.drift_ppm, |u| {
+            self.rank(u, now)
+        })
+    }
+
+    /// Time elapsed since the last reference time update; None if there never was one
+    pub fn reference_age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.reftime
+            .map(|r| (now - r).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    pub fn state(&self, now: DateTime<Utc>) -> SyncState {
+        let age = match self.reference_age(now) {
+            Some(age) => age,
+            None => return SyncState::Unsynchronized,
+        };
+        let h = &self.holdover;
+        if age >= h.alarm_after {
+            SyncState::Alarm
+        } else if age >= h.degrade_after {
+            SyncState::Degraded
+        } else if age >= h.holdover_after {
+            SyncState::Holdover
+        } else {
+            SyncState::Locked
+        }
     }
 
+    /// Maximum error relative to the selected source: the source's error estimate, the
+    /// resolution of the local clock and the drift since the last update
+    pub fn root_dispersion(&self, now: DateTime<Utc>) -> Duration {
+        let distance = match self.selected(now) {
+            Some(u) => u.distance(now, self.holdover.drift_ppm),
+            None => self.reference_error,
+        };
+        distance + self.clock_precision
+    }
+
+    /// `datagram` is what `q` was parsed from; authentication is checked against it
     pub fn answer_query(
         &self,
-        q: NTPQuery,
+        q: &NTPMessage,
+        datagram: &[u8],
         received_at: DateTime<Utc>,
         now: DateTime<Utc>,
-    ) -> NTPMessage {
-        NTPMessage {
-            flags: NTPFlags {
-                l: match self.reftime {
-                    Some(_) => LeapIndicator::NoWarning,
-                    None => LeapIndicator::Alarm,
+    ) -> Result<NTPMessage, Rejection> {
+        if q.flags.m != Mode::Client {
+            return Err(Rejection::UnexpectedMode(q.flags.m));
+        }
+        let state = self.state(now);
+        let selected = self.selected(now);
+        let warning = match (self.leap, self.leap_mode) {
+            (Some(leap), LeapMode::Step) => leap.indicator(now),
+            _ => LeapIndicator::NoWarning,
+        };
+        let (leap, stratum, ref_id) = match selected.map(|u| u.source) {
+            Some(TimeSource::Gps) => match state {
+                SyncState::Degraded => (
+                    warning,
+                    self.holdover.degraded_stratum,
+                    Reference::Ascii(*b"HOLD"),
+                ),
+                SyncState::Locked => (warning, NTP_STRATUM_ONE, Reference::GPS),
+                _ => (warning, NTP_STRATUM_ONE, Reference::Ascii(*b"HOLD")),
+            },
+            Some(source) => (warning, source.stratum(), source.reference()),
+            None => (
+                LeapIndicator::Alarm,
+                NTP_STRATUM_UNSYNCHRONIZED,
+                match self.reftime {
+                    None => Reference::Ascii(*b"INIT"),
+                    Some(_) => Reference::Ascii(*b"HOLD"),
                 },
-                v: VersionNumber(NTP_VERSION),
+            ),
+        };
+        let ref_time = selected.map(|u| u.at).or(self.reftime);
+        let mut answer = NTPMessage {
+            flags: NTPFlags {
+                l: leap,
+                v: q.flags.v,
                 m: Mode::Server,
             },
-            peer_stratum: if self.reftime.is_some() {
-                NTP_STRATUM_ONE
-            } else {
-                NTP_STRATUM_UNSYNCHRONIZED
-            },
+            peer_stratum: stratum,
             peer_polling_interval: 4, //q.peer_polling_interval,
             root_delay: Fix32 { i: 0, f: 0 },
-            peer_clock_precision: PeerPrecision::from(Duration::from_micros(1)),
-            root_dispersion: Fix32 { i: 0, f: 0 },
-            ref_id: Reference::GPS,
-            ref_tstamp: NTPTimestamp::from(self.reftime.unwrap_or(ntp_zero())),
+            peer_clock_precision: PeerPrecision::from(self.clock_precision),
+            root_dispersion: Fix32::from(self.root_dispersion(now)),
+            ref_id,
+            ref_tstamp: NTPTimestamp::from(ref_time.unwrap_or(ntp_zero())),
             origin_tstamp: q.transmit_tstamp,
-            rcv_tstamp: NTPTimestamp::from(received_at),
-            transmit_tstamp: NTPTimestamp::from(now),
+            rcv_tstamp: NTPTimestamp::from(self.served(received_at)),
+            transmit_tstamp: NTPTimestamp::from(self.served(now)),
+            // unknown extension fields in the query are ignored
+            extensions: vec![],
+            mac: None,
+        };
+        if nts::is_nts(q) {
+            match NtsQuery::parse(self.cookies.as_ref(), q, datagram) {
+                // a valid query implies a jar
+                Ok(nq) => nq.seal(self.cookies.as_ref().unwrap(), &mut answer),
+                Err(NtsError::Unauthenticated(unique_id)) => nts::nak(&mut answer, &unique_id),
+                Err(NtsError::Malformed) => return Err(Rejection::MalformedNts),
+            }
+        } else if let Some(mac) = &q.mac {
+            answer.mac = Some(self.authenticate(q, datagram, mac, &answer));
         }
+        Ok(answer)
     }
 }
 
@@ -52,3 +392,543 @@ impl Default for GPSServer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Algorithm, Key};
+    use crate::nts::{AesSivCmac256, SessionKeys, COOKIE_LEN};
+    use chrono::{NaiveDate, TimeDelta};
+    use std::net::Ipv4Addr;
+
+    fn t0() -> DateTime<Utc> {
+        DateTime::<Utc>::from_naive_utc_and_offset(
+            NaiveDate::from_ymd_opt(2024, 3, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            Utc,
+        )
+    }
+
+    fn query_with(version: u8, mode: Mode) -> NTPMessage {
+        let mut buf = vec![0; NTP_MESSAGE_LEN];
+        buf[0] = (version << 3) | u8::from(&mode);
+        buf[47] = 1;
+        NTPMessage::deserialize(&buf).unwrap()
+    }
+
+    fn query() -> NTPMessage {
+        query_with(4, Mode::Client)
+    }
+
+    #[test]
+    fn holdover_states() {
+        let mut s = GPSServer::new();
+        assert_eq!(s.state(t0()), SyncState::Unsynchronized);
+        s.update_reference_time(t0(), Duration::ZERO);
+
+        let at = |secs| t0() + TimeDelta::seconds(secs);
+        assert_eq!(s.state(at(-1)), SyncState::Locked);
+        assert_eq!(s.state(at(15)), SyncState::Locked);
+        assert_eq!(s.state(at(16)), SyncState::Holdover);
+        assert_eq!(s.state(at(60 * 60)), SyncState::Degraded);
+        assert_eq!(s.state(at(12 * 60 * 60)), SyncState::Alarm);
+
+        s.update_reference_time(at(12 * 60 * 60), Duration::ZERO);
+        assert_eq!(s.state(at(12 * 60 * 60)), SyncState::Locked);
+    }
+
+    #[test]
+    fn holdover_answers() {
+        let mut s = GPSServer::new();
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.flags.l, LeapIndicator::Alarm);
+        assert_eq!(a.peer_stratum, NTP_STRATUM_UNSYNCHRONIZED);
+        assert_eq!(a.ref_id, Reference::Ascii(*b"INIT"));
+
+        s.set_clock_precision(Duration::ZERO);
+        s.update_reference_time(t0(), Duration::ZERO);
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+        assert_eq!(a.peer_stratum, NTP_STRATUM_ONE);
+        assert_eq!(a.ref_id, Reference::GPS);
+        assert_eq!(a.root_dispersion, Fix32 { i: 0, f: 0 });
+
+        // 1000s at 15ppm is 15ms of dispersion
+        let now = t0() + TimeDelta::seconds(1000);
+        let a = s
+            .answer_query(&query(), &query().serialize(), now, now)
+            .unwrap();
+        assert_eq!(a.peer_stratum, NTP_STRATUM_ONE);
+        assert_eq!(a.ref_id, Reference::Ascii(*b"HOLD"));
+        assert!((f32::from(a.root_dispersion) - 0.015).abs() < 0.000_1);
+
+        let now = t0() + TimeDelta::hours(2);
+        let a = s
+            .answer_query(&query(), &query().serialize(), now, now)
+            .unwrap();
+        assert_eq!(a.peer_stratum, 2);
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+
+        let now = t0() + TimeDelta::hours(13);
+        let a = s
+            .answer_query(&query(), &query().serialize(), now, now)
+            .unwrap();
+        assert_eq!(a.peer_stratum, NTP_STRATUM_UNSYNCHRONIZED);
+        assert_eq!(a.flags.l, LeapIndicator::Alarm);
+    }
+
+    #[test]
+    fn dispersion_from_accuracy() {
+        let mut s = GPSServer::new();
+        s.set_clock_precision(Duration::from_micros(4));
+        s.update_reference_time(t0(), Duration::from_micros(500));
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.peer_clock_precision, PeerPrecision(-18));
+        assert_eq!(a.root_dispersion, Fix32::from(Duration::from_micros(504)));
+
+        // 100s at 15ppm adds 1.5ms
+        let now = t0() + TimeDelta::seconds(100);
+        assert_eq!(s.root_dispersion(now), Duration::from_micros(2004));
+    }
+
+    #[test]
+    fn precision_of_stepping_clock() {
+        let ticks = std::cell::Cell::new(0);
+        let clock = || {
+            // every other read sees the clock advance by 10us
+            ticks.set(ticks.get() + 1);
+            t0() + TimeDelta::microseconds(10 * (ticks.get() / 2))
+        };
+        assert_eq!(measure_precision(clock), Duration::from_micros(10));
+        assert_eq!(measure_precision(t0), Duration::from_micros(1));
+    }
+
+    #[test]
+    fn echoes_client_version() {
+        let s = GPSServer::new();
+        for v in 1..=4 {
+            let a = s
+                .answer_query(
+                    &query_with(v, Mode::Client),
+                    &query_with(v, Mode::Client).serialize(),
+                    t0(),
+                    t0(),
+                )
+                .unwrap();
+            assert_eq!(a.flags.v, VersionNumber(v));
+            assert_eq!(a.flags.m, Mode::Server);
+        }
+    }
+
+    #[test]
+    fn rejects_non_client_modes() {
+        let s = GPSServer::new();
+        for m in [
+            Mode::SymActive,
+            Mode::SymPassive,
+            Mode::Server,
+            Mode::Broadcast,
+        ] {
+            let r = s.answer_query(&query_with(4, m), &query_with(4, m).serialize(), t0(), t0());
+            assert_eq!(r, Err(Rejection::UnexpectedMode(m)));
+        }
+        // a response, reflected back at the server
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        let reflected = NTPMessage::deserialize(&a.serialize()).unwrap();
+        assert_eq!(
+            s.answer_query(&reflected, &reflected.serialize(), t0(), t0())
+                .unwrap_err()
+                .reason(),
+            "mode_server"
+        );
+    }
+
+    #[test]
+    fn authenticated_answers() {
+        let mut s = GPSServer::new();
+        s.set_keys(KeyTable::parse("1 MD5 secret\n2 SHA1 other").unwrap());
+        let key = Key {
+            algorithm: Algorithm::Md5,
+            secret: b"secret".to_vec(),
+        };
+
+        let mut q = query();
+        q.mac = Some(Mac {
+            key_id: 1,
+            digest: key.digest(&q.serialize_authenticated()),
+        });
+        let a = s.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        let mac = a.mac.clone().unwrap();
+        // the MAC is checked against the bytes received
+        let mut tampered = q.serialize();
+        tampered[46] ^= 1;
+        let nak = s.answer_query(&q, &tampered, t0(), t0()).unwrap();
+        assert!(nak.mac.unwrap().is_crypto_nak());
+        assert_eq!(mac.key_id, 1);
+        assert!(key.verify(&a.serialize_authenticated(), &mac.digest));
+        // the serialized response carries the MAC
+        let parsed = NTPMessage::deserialize(&a.serialize()).unwrap();
+        assert_eq!(parsed.mac, Some(mac));
+
+        // signed with the wrong key
+        q.mac = Some(Mac {
+            key_id: 2,
+            digest: vec![0; 20],
+        });
+        let a = s.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        assert!(a.mac.unwrap().is_crypto_nak());
+
+        // unknown key
+        q.mac = Some(Mac {
+            key_id: 3,
+            digest: vec![0; 16],
+        });
+        let a = s.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        assert!(a.mac.unwrap().is_crypto_nak());
+
+        // unauthenticated queries get unauthenticated answers
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.mac, None);
+    }
+
+    #[test]
+    fn nts_answers() {
+        let mut s = GPSServer::new();
+        let jar = CookieJar::new();
+        let keys = SessionKeys {
+            c2s: AesSivCmac256::new(&[1; 32]).unwrap(),
+            s2c: AesSivCmac256::new(&[2; 32]).unwrap(),
+        };
+        let nts_query = |cookie: &[u8]| {
+            let mut q = query();
+            q.extensions = vec![
+                ExtensionField::new(ExtensionFieldType::UniqueIdentifier, &[7; 32]),
+                ExtensionField::new(ExtensionFieldType::NtsCookie, cookie),
+                ExtensionField::new(ExtensionFieldType::NtsCookiePlaceholder, &[0; COOKIE_LEN]),
+            ];
+            let ad = q.serialize_until(3);
+            q.extensions.push(nts::authenticator(&keys.c2s, &ad, &[]));
+            NTPMessage::deserialize(&q.serialize()).unwrap()
+        };
+        let q = nts_query(&jar.make_cookie(&keys));
+
+        // without a jar nothing can be decrypted
+        let a = s.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        assert_eq!(a.ref_id, Reference::Ascii(*b"NTSN"));
+
+        s.set_cookie_jar(jar.clone());
+        s.update_reference_time(t0(), Duration::ZERO);
+        let a = s.answer_query(&q, &q.serialize(), t0(), t0()).unwrap();
+        let a = NTPMessage::deserialize(&a.serialize()).unwrap();
+        assert_eq!(a.peer_stratum, NTP_STRATUM_ONE);
+        assert_eq!(a.extensions.len(), 2);
+        assert_eq!(a.extensions[0].value, vec![7; 32]);
+        let plaintext =
+            nts::open_authenticator(&keys.s2c, &a.serialize_until(1), &a.extensions[1].value)
+                .unwrap();
+        // one cookie for the one used, one for the placeholder
+        let cookies = ExtensionField::deserialize_all(&plaintext).unwrap();
+        assert_eq!(cookies.len(), 2);
+        for c in cookies {
+            assert_eq!(c.field_type, ExtensionFieldType::NtsCookie);
+            assert_eq!(jar.open_cookie(&c.value).unwrap().s2c.key(), &[2; 32]);
+        }
+
+        // a tampered header fails authentication
+        let mut tampered = q.serialize();
+        tampered[46] ^= 1;
+        let a = s
+            .answer_query(
+                &NTPMessage::deserialize(&tampered).unwrap(),
+                &tampered,
+                t0(),
+                t0(),
+            )
+            .unwrap();
+        assert_eq!(a.peer_stratum, 0);
+        assert_eq!(a.ref_id, Reference::Ascii(*b"NTSN"));
+        assert_eq!(a.extensions.len(), 1);
+
+        // the authenticator covers the received bytes, not the message as parsed
+        let a = s.answer_query(&q, &tampered, t0(), t0()).unwrap();
+        assert_eq!(a.ref_id, Reference::Ascii(*b"NTSN"));
+
+        // unknown cookie
+        let a = s
+            .answer_query(
+                &nts_query(&[0; COOKIE_LEN]),
+                &nts_query(&[0; COOKIE_LEN]).serialize(),
+                t0(),
+                t0(),
+            )
+            .unwrap();
+        assert_eq!(a.ref_id, Reference::Ascii(*b"NTSN"));
+
+        // no authenticator
+        let mut q = q;
+        q.extensions.pop();
+        assert_eq!(
+            s.answer_query(&q, &q.serialize(), t0(), t0()),
+            Err(Rejection::MalformedNts)
+        );
+    }
+
+    #[test]
+    fn upstream_fallback() {
+        let mut s = GPSServer::new();
+        let upstream = Ipv4Addr::new(192, 0, 2, 1);
+        let ntp = |at, offset_ms| SourceUpdate {
+            source: TimeSource::Ntp {
+                addr: upstream.into(),
+                stratum: 2,
+            },
+            at,
+            offset: TimeDelta::milliseconds(offset_ms),
+            error: Duration::from_millis(5),
+        };
+        s.update_source(ntp(t0(), 1));
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.peer_stratum, 3);
+        assert_eq!(a.ref_id, Reference::IPv4(upstream));
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+
+        // a healthy receiver is preferred
+        s.update_reference_time(t0(), Duration::from_micros(1));
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.peer_stratum, NTP_STRATUM_ONE);
+        assert_eq!(a.ref_id, Reference::GPS);
+
+        // until it stops updating for long enough to degrade
+        let later = t0() + TimeDelta::hours(2);
+        s.update_source(ntp(later, 1));
+        let a = s
+            .answer_query(&query(), &query().serialize(), later, later)
+            .unwrap();
+        assert_eq!(a.peer_stratum, 3);
+        assert_eq!(a.ref_id, Reference::IPv4(upstream));
+        assert_eq!(
+            s.root_dispersion(later),
+            Duration::from_millis(5) + s.clock_precision
+        );
+
+        // the RTC is the last resort
+        s.update_source(SourceUpdate {
+            source: TimeSource::Rtc,
+            at: later,
+            offset: TimeDelta::zero(),
+            error: Duration::from_secs(1),
+        });
+        let much_later = later + TimeDelta::hours(13);
+        s.update_source(SourceUpdate {
+            at: much_later,
+            ..*s.sources.get(&TimeSource::Rtc).unwrap()
+        });
+        let a = s
+            .answer_query(&query(), &query().serialize(), much_later, much_later)
+            .unwrap();
+        assert_eq!(a.peer_stratum, source::RTC_STRATUM);
+        assert_eq!(a.ref_id, Reference::Ascii(*b"LOCL"));
+
+        // unsynchronized upstream servers are not followed
+        let mut s = GPSServer::new();
+        s.update_source(SourceUpdate {
+            source: TimeSource::Ntp {
+                addr: upstream.into(),
+                stratum: NTP_STRATUM_UNSYNCHRONIZED,
+            },
+            ..ntp(t0(), 0)
+        });
+        let a = s
+            .answer_query(&query(), &query().serialize(), t0(), t0())
+            .unwrap();
+        assert_eq!(a.peer_stratum, NTP_STRATUM_UNSYNCHRONIZED);
+    }
+
+    #[test]
+    fn leap_second_day() {
+        use crate::leap::{LeapDirection, LeapSecond};
+        let midnight = DateTime::<Utc>::from_naive_utc_and_offset(
+            NaiveDate::from_ymd_opt(2017, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            Utc,
+        );
+        let at = |ms| midnight + TimeDelta::milliseconds(ms);
+        let mut s = GPSServer::new();
+        s.set_leap_second(LeapSecond::new(midnight, LeapDirection::Insert));
+        let answer = |s: &mut GPSServer, t| {
+            s.update_reference_time(t, Duration::ZERO);
+            s.answer_query(&query(), &query().serialize(), t, t)
+                .unwrap()
+        };
+
+        // not announced the day before
+        let a = answer(&mut s, at(-25 * 60 * 60 * 1000));
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+        let a = answer(&mut s, at(-60 * 1000));
+        assert_eq!(a.flags.l, LeapIndicator::LastMinuteHas61Seconds);
+        let a = answer(&mut s, at(-500));
+        assert_eq!(a.flags.l, LeapIndicator::LastMinuteHas61Seconds);
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(-500)));
+
+        // the local clock has not been corrected yet: 23:59:60 is served as a second 23:59:59
+        let a = answer(&mut s, at(500));
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(-500)));
+        assert_eq!(a.rcv_tstamp, NTPTimestamp::from(at(-500)));
+
+        // the receiver's count down is over by now, the pending second stays until applied
+        s.leap_countdown(at(500), -1, 1);
+        s.leap_countdown(at(500), 0, 0);
+        assert_eq!(
+            s.leap_due(at(500)),
+            Some(LeapSecond::new(midnight, LeapDirection::Insert))
+        );
+        let a = answer(&mut s, at(1500));
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(500)));
+        // nor does the next event replace it
+        s.leap_countdown(at(1500), 180 * 24 * 60 * 60, 1);
+        assert_eq!(
+            s.leap_due(at(1500)),
+            Some(LeapSecond::new(midnight, LeapDirection::Insert))
+        );
+        // a clock following UTC runs a second ahead until it is corrected
+        assert_eq!(s.leap_uncorrected(at(-500)), at(-500));
+        assert_eq!(s.leap_uncorrected(at(500)), at(1500));
+        assert_eq!(s.leap_corrected(at(1500)), at(500));
+
+        // once it is, timestamps are served as they are
+        s.leap_applied();
+        let a = answer(&mut s, at(-500));
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(-500)));
+        let a = answer(&mut s, at(500));
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(500)));
+
+        // a deleted second: 23:59:59 is skipped
+        s.set_leap_second(LeapSecond::new(midnight, LeapDirection::Delete));
+        let a = answer(&mut s, at(-1500));
+        assert_eq!(a.flags.l, LeapIndicator::LastMinuteHas59Seconds);
+        let a = answer(&mut s, at(-500));
+        assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+        assert_eq!(a.transmit_tstamp, NTPTimestamp::from(at(500)));
+
+        // no warning while unsynchronized
+        let mut s = GPSServer::new();
+        s.set_leap_second(LeapSecond::new(midnight, LeapDirection::Insert));
+        let a = s
+            .answer_query(&query(), &query().serialize(), at(-500), at(-500))
+            .unwrap();
+        assert_eq!(a.flags.l, LeapIndicator::Alarm);
+    }
+
+    #[test]
+    fn leap_smear() {
+        use crate::leap::{LeapDirection, LeapMode, LeapSecond};
+        let midnight = DateTime::<Utc>::from_naive_utc_and_offset(
+            NaiveDate::from_ymd_opt(2017, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            Utc,
+        );
+        let mut s = GPSServer::new();
+        s.set_leap_mode(LeapMode::Smear {
+            window: Duration::from_secs(24 * 60 * 60),
+        });
+
+        // local time, not corrected for the leap, every 10 minutes from 14h before midnight
+        let step = TimeDelta::minutes(10);
+        let mut served = vec![];
+        for i in 0..(28 * 6) {
+            let t = midnight - TimeDelta::hours(14) + step * i;
+            // NAV-TIMELS keeps coming, counting past the event after midnight
+            s.leap_countdown(t, (midnight - t).num_seconds(), 1);
+            s.update_reference_time(s.leap_corrected(t), Duration::ZERO);
+            assert_eq!(
+                s.leap_due(t).is_some(),
+                t >= midnight + TimeDelta::hours(12)
+            );
+            let a = s
+                .answer_query(&query(), &query().serialize(), t, t)
+                .unwrap();
+            assert_eq!(a.flags.l, LeapIndicator::NoWarning);
+            served.push((t, a.transmit_tstamp));
+        }
+        // still pending 14h after midnight, the count down notwithstanding
+        assert_eq!(
+            s.leap,
+            Some(LeapSecond::new(midnight, LeapDirection::Insert))
+        );
+        let offset_at = |hours: i64| {
+            let t = midnight + TimeDelta::hours(hours);
+            let (_, ts) = served.iter().find(|(at, _)| *at == t).unwrap();
+            (*ts, t)
+        };
+        // noon to noon, half way through at midnight
+        for (hours, ms) in [
+            (-14, 0),
+            (-12, 0),
+            (-6, -250),
+            (0, -500),
+            (6, -750),
+            (12, -1000),
+        ] {
+            let (ts, t) = offset_at(hours);
+            assert_eq!(
+                ts,
+                NTPTimestamp::from(t + TimeDelta::milliseconds(ms)),
+                "{}",
+                hours
+            );
+        }
+        // served time never goes backwards, nor jumps
+        let slowed = step - TimeDelta::seconds(1) / (24 * 6);
+        for w in served.windows(2) {
+            let (_, a) = w[0];
+            let (t, b) = w[1];
+            let elapsed = DateTime::<Utc>::from(b) - DateTime::<Utc>::from(a);
+            let expected =
+                if t <= midnight - TimeDelta::hours(12) || t > midnight + TimeDelta::hours(12) {
+                    step
+                } else {
+                    slowed
+                };
+            assert!(
+                (elapsed - expected).abs() < TimeDelta::microseconds(1),
+                "{} {}",
+                t,
+                elapsed
+            );
+        }
+
+        // the clock is corrected at the end of the window, served time carries on as it was
+        let t = midnight + TimeDelta::hours(13);
+        let before = s
+            .answer_query(&query(), &query().serialize(), t, t)
+            .unwrap();
+        s.leap_applied();
+        let t = t - TimeDelta::seconds(1);
+        let after = s
+            .answer_query(&query(), &query().serialize(), t, t)
+            .unwrap();
+        assert_eq!(before.transmit_tstamp, after.transmit_tstamp);
+    }
+}
@@ -0,0 +1,286 @@
+//! Time sources, and the selection of the one the server follows
+use crate::proto::{Reference, NTP_STRATUM_ONE};
+use chrono::{DateTime, TimeDelta, Utc};
+use md5::{Digest, Md5};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+/// Stratum advertised while following the RTC, the same as ntpd's local clock driver
+pub const RTC_STRATUM: u8 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    Gps,
+    /// An upstream NTP server, with the stratum it advertised
+    Ntp {
+        addr: IpAddr,
+        stratum: u8,
+    },
+    /// The receiver's battery backed clock, which it keeps time with until it has a fix;
+    /// better than nothing after a reboot without one
+    Rtc,
+}
+
+impl TimeSource {
+    /// Updates for the same source replace each other
+    fn same(&self, other: &TimeSource) -> bool {
+        match (self, other) {
+            (TimeSource::Ntp { addr: a, .. }, TimeSource::Ntp { addr: b, .. }) => a == b,
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+
+    /// Short, stable name; useful as a metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimeSource::Gps => "gps",
+            TimeSource::Ntp { .. } => "ntp",
+            TimeSource::Rtc => "rtc",
+        }
+    }
+
+    /// Stratum advertised while following this source
+    pub fn stratum(&self) -> u8 {
+        match self {
+            TimeSource::Gps => NTP_STRATUM_ONE,
+            TimeSource::Ntp { stratum, .. } => stratum.saturating_add(1),
+            TimeSource::Rtc => RTC_STRATUM,
+        }
+    }
+
+    /// Reference id advertised while following this source. IPv6 servers are identified by
+    /// the first four bytes of the MD5 of their address, RFC 5905 section 7.3
+    pub fn reference(&self) -> Reference {
+        match self {
+            TimeSource::Gps => Reference::GPS,
+            TimeSource::Ntp {
+                addr: IpAddr::V4(a),
+                ..
+            } => Reference::IPv4(*a),
+            TimeSource::Ntp {
+                addr: IpAddr::V6(a),
+                ..
+            } => {
+                let h = Md5::digest(a.octets());
+                Reference::IPv4(Ipv4Addr::new(h[0], h[1], h[2], h[3]))
+            }
+            TimeSource::Rtc => Reference::Ascii(*b"LOCL"),
+        }
+    }
+}
+
+/// The last reading of a source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceUpdate {
+    pub source: TimeSource,
+    /// Local time of the reading
+    pub at: DateTime<Utc>,
+    /// Source time minus local time, at `at`
+    pub offset: TimeDelta,
+    /// Maximum error of the reading: the source's own error estimate, plus half the round trip
+    /// for network sources
+    pub error: Duration,
+}
+
+impl SourceUpdate {
+    /// The error grows by `drift_ppm` for every second since the reading
+    pub fn distance(&self, now: DateTime<Utc>, drift_ppm: f32) -> Duration {
+        let age = (now - self.at).to_std().unwrap_or(Duration::ZERO);
+        self.error + Duration::from_secs_f32(age.as_secs_f32() * drift_ppm / 1_000_000.0)
+    }
+
+    /// The interval the true time offset lies in
+    fn interval(&self, now: DateTime<Utc>, drift_ppm: f32) -> (TimeDelta, TimeDelta) {
+        // error estimates are far below the ~292 million years a TimeDelta holds
+        let d = TimeDelta::from_std(self.distance(now, drift_ppm)).unwrap();
+        (self.offset - d, self.offset + d)
+    }
+}
+
+/// The latest update from each source
+#[derive(Debug, Default, Clone)]
+pub struct Sources {
+    updates: Vec<SourceUpdate>,
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        Sources::default()
+    }
+
+    pub fn update(&mut self, u: SourceUpdate) {
+        match self.updates.iter_mut().find(|o| o.source.same(&u.source)) {
+            Some(old) => *old = u,
+            None => self.updates.push(u),
+        }
+    }
+
+    pub fn get(&self, source: &TimeSource) -> Option<&SourceUpdate> {
+        self.updates.iter().find(|u| u.source.same(source))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SourceUpdate> {
+        self.updates.iter()
+    }
+}
+
+/// Marzullo's algorithm, as used by RFC 5905 clock select: finds the smallest number of
+/// falsetickers `f` for which `n - f` intervals share a point, and returns the indices of the
+/// intervals overlapping that intersection. Empty if there is no majority.
+pub fn truechimers(intervals: &[(TimeDelta, TimeDelta)]) -> Vec<usize> {
+    let n = intervals.len();
+    let mut edges: Vec<(TimeDelta, i32)> = intervals
+        .iter()
+        .flat_map(|(lo, hi)| [(*lo, -1), (*hi, 1)])
+        .collect();
+    // lower edges go first on ties, so touching intervals overlap
+    edges.sort();
+    let mut f = 0;
+    while 2 * f < n {
+        let needed = (n - f) as i32;
+        let mut count = 0;
+        let low = edges.iter().find_map(|(v, t)| {
+            count -= t;
+            (count >= needed).then_some(*v)
+        });
+        count = 0;
+        let high = edges.iter().rev().find_map(|(v, t)| {
+            count += t;
+            (count >= needed).then_some(*v)
+        });
+        if let (Some(low), Some(high)) = (low, high) {
+            if low <= high {
+                return intervals
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (lo, hi))| *lo <= high && *hi >= low)
+                    .map(|(i, _)| i)
+                    .collect();
+            }
+        }
+        f += 1;
+    }
+    vec![]
+}
+
+/// Picks the source to follow among the truechimers of `candidates`; `rank` orders the
+/// sources, lowest first, ties go to the smallest distance. With fewer than three candidates
+/// disagreeing there is no majority, the best ranked candidate is followed anyway.
+pub fn select(
+    candidates: &[SourceUpdate],
+    now: DateTime<Utc>,
+    drift_ppm: f32,
+    rank: impl Fn(&SourceUpdate) -> u32,
+) -> Option<SourceUpdate> {
+    let intervals: Vec<_> = candidates
+        .iter()
+        .map(|c| c.interval(now, drift_ppm))
+        .collect();
+    let mut chimers = truechimers(&intervals);
+    if chimers.is_empty() {
+        chimers = (0..candidates.len()).collect();
+    }
+    chimers
+        .into_iter()
+        .map(|i| candidates[i])
+        .min_by_key(|c| (rank(c), c.distance(now, drift_ppm)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ms(v: i64) -> TimeDelta {
+        TimeDelta::milliseconds(v)
+    }
+
+    #[test]
+    fn marzullo() {
+        // three agree around +1ms, one is far off
+        let intervals = [
+            (ms(-2), ms(4)),
+            (ms(0), ms(2)),
+            (ms(1), ms(3)),
+            (ms(50), ms(60)),
+        ];
+        assert_eq!(truechimers(&intervals), vec![0, 1, 2]);
+        // all agree
+        assert_eq!(truechimers(&intervals[..3]), vec![0, 1, 2]);
+        // touching intervals overlap
+        assert_eq!(truechimers(&[(ms(0), ms(1)), (ms(1), ms(2))]), vec![0, 1]);
+        // two disagreeing sources, no majority
+        assert!(truechimers(&[(ms(0), ms(1)), (ms(5), ms(6))]).is_empty());
+        assert!(truechimers(&[]).is_empty());
+    }
+
+    #[test]
+    fn selection() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let update = |source, offset, error| SourceUpdate {
+            source,
+            at: now,
+            offset: ms(offset),
+            error: Duration::from_millis(error),
+        };
+        let ntp = |last: u8, stratum| TimeSource::Ntp {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)),
+            stratum,
+        };
+        let rank = |u: &SourceUpdate| match u.source {
+            TimeSource::Gps => 0,
+            TimeSource::Ntp { .. } => 1,
+            TimeSource::Rtc => 2,
+        };
+        let gps = update(TimeSource::Gps, 0, 1);
+        let a = update(ntp(1, 2), 2, 5);
+        let b = update(ntp(2, 1), 1, 5);
+        let rtc = update(TimeSource::Rtc, 500, 100);
+        assert_eq!(select(&[rtc, a, gps, b], now, 15.0, rank), Some(gps));
+        // the better stratum wins among equally ranked sources... unless it is a falseticker
+        let ntp_rank = |u: &SourceUpdate| u.source.stratum() as u32;
+        assert_eq!(select(&[a, b], now, 15.0, ntp_rank), Some(b));
+        let bad = update(ntp(2, 1), 100, 5);
+        assert_eq!(select(&[a, bad, gps], now, 15.0, ntp_rank), Some(gps));
+        assert_eq!(select(&[a, bad, gps, rtc], now, 15.0, rank), Some(gps));
+        // GPS is lost
+        assert_eq!(select(&[rtc, a], now, 15.0, rank), Some(a));
+        assert_eq!(select(&[rtc], now, 15.0, rank), Some(rtc));
+        assert_eq!(select(&[], now, 15.0, rank), None);
+    }
+
+    #[test]
+    fn stratum_and_reference() {
+        let v4 = TimeSource::Ntp {
+            addr: "192.0.2.1".parse().unwrap(),
+            stratum: 2,
+        };
+        assert_eq!(v4.stratum(), 3);
+        assert_eq!(v4.reference(), Reference::IPv4(Ipv4Addr::new(192, 0, 2, 1)));
+        let v6 = TimeSource::Ntp {
+            addr: "2001:db8::1".parse().unwrap(),
+            stratum: 1,
+        };
+        assert!(matches!(v6.reference(), Reference::IPv4(_)));
+        assert_eq!(TimeSource::Gps.reference(), Reference::GPS);
+        assert_eq!(TimeSource::Rtc.stratum(), RTC_STRATUM);
+    }
+
+    #[test]
+    fn updates_replace_by_source() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut s = Sources::new();
+        let addr = "192.0.2.1".parse().unwrap();
+        for stratum in [2, 3] {
+            s.update(SourceUpdate {
+                source: TimeSource::Ntp { addr, stratum },
+                at: now,
+                offset: ms(0),
+                error: Duration::ZERO,
+            });
+        }
+        assert_eq!(s.iter().count(), 1);
+        let u = s.get(&TimeSource::Ntp { addr, stratum: 0 }).unwrap();
+        assert_eq!(u.source.stratum(), 4);
+    }
+}
@@ -0,0 +1,126 @@
+#![cfg(feature = "nts")]
+use chrono::{TimeZone, Utc};
+use ntp::nts::*;
+use ntp::nts_ke::{serve, server_config};
+use ntp::proto::*;
+use ntp::server::GPSServer;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn key_exchange(
+    addr: std::net::SocketAddr,
+    roots: RootCertStore,
+    request: &[Record],
+) -> (Vec<Record>, SessionKeys) {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+    let conn = ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let mut tls = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+    tls.write_all(&serialize_records(request)).unwrap();
+    let response = read_message(&mut tls).unwrap();
+    let key = |direction| {
+        let context = exporter_context(AEAD_AES_SIV_CMAC_256, direction);
+        let k = tls
+            .conn
+            .export_keying_material([0; 32], EXPORTER_LABEL, Some(&context))
+            .unwrap();
+        AesSivCmac256::new(&k).unwrap()
+    };
+    let keys = SessionKeys {
+        c2s: key(CLIENT_TO_SERVER),
+        s2c: key(SERVER_TO_CLIENT),
+    };
+    (response, keys)
+}
+
+#[test]
+fn nts_end_to_end() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let config = server_config(
+        cert.cert.pem().as_bytes(),
+        cert.key_pair.serialize_pem().as_bytes(),
+    )
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let jar = CookieJar::new();
+    let jar2 = jar.clone();
+    thread::spawn(move || serve(listener, config, jar2));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.cert.der().clone()).unwrap();
+    let request = [
+        Record::new(true, RecordType::NextProtocol, &[0, 0]),
+        Record::new(true, RecordType::AeadAlgorithm, &[0, 15]),
+        Record::end_of_message(),
+    ];
+    let (response, keys) = key_exchange(addr, roots.clone(), &request);
+    assert_eq!(response[0].body, vec![0, 0]);
+    assert_eq!(response[1].body, vec![0, 15]);
+    let cookies: Vec<&Record> = response
+        .iter()
+        .filter(|r| r.record_type == RecordType::NewCookie)
+        .collect();
+    assert_eq!(cookies.len(), COOKIES_PER_KE);
+
+    let t0 = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+    let mut srv = GPSServer::new();
+    srv.set_cookie_jar(jar);
+    srv.update_reference_time(t0, Duration::ZERO);
+
+    let mut query = vec![0x23];
+    query.resize(NTP_MESSAGE_LEN, 0);
+    query[47] = 1;
+    let mut q = NTPMessage::deserialize(&query).unwrap();
+    q.extensions = vec![
+        ExtensionField::new(ExtensionFieldType::UniqueIdentifier, &[9; 32]),
+        ExtensionField::new(ExtensionFieldType::NtsCookie, &cookies[0].body),
+    ];
+    let ad = q.serialize_until(2);
+    q.extensions.push(authenticator(&keys.c2s, &ad, &[]));
+    let q = NTPMessage::deserialize(&q.serialize()).unwrap();
+
+    let a = srv.answer_query(&q, &q.serialize(), t0, t0).unwrap();
+    let a = NTPMessage::deserialize(&a.serialize()).unwrap();
+    assert_eq!(a.peer_stratum, NTP_STRATUM_ONE);
+    assert_eq!(a.origin_tstamp, q.transmit_tstamp);
+    assert_eq!(a.extensions[0].value, vec![9; 32]);
+    let plaintext =
+        open_authenticator(&keys.s2c, &a.serialize_until(1), &a.extensions[1].value).unwrap();
+    let fresh = ExtensionField::deserialize_all(&plaintext).unwrap();
+    assert_eq!(fresh.len(), 1);
+    assert_ne!(fresh[0].value, cookies[0].body);
+
+    // keys from another session do not match the cookie
+    let (_, other) = key_exchange(addr, roots.clone(), &request);
+    let mut q = q;
+    q.extensions.pop();
+    let ad = q.serialize_until(2);
+    q.extensions.push(authenticator(&other.c2s, &ad, &[]));
+    let a = srv.answer_query(&q, &q.serialize(), t0, t0).unwrap();
+    assert_eq!(a.ref_id, Reference::Ascii(*b"NTSN"));
+
+    // only AES-SIV-CMAC-256 is supported
+    let request = [
+        Record::new(true, RecordType::NextProtocol, &[0, 0]),
+        Record::new(true, RecordType::AeadAlgorithm, &[0, 17]),
+        Record::end_of_message(),
+    ];
+    let (response, _) = key_exchange(addr, roots, &request);
+    assert_eq!(
+        response,
+        vec![
+            Record::new(true, RecordType::AeadAlgorithm, &[]),
+            Record::end_of_message()
+        ]
+    );
+}
@@ -1,94 +1,255 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use ntp::auth::KeyTable;
+use ntp::client::system_now;
+use ntp::leap::LeapMode;
+use ntp::leapfile::LeapTable;
 use ntp::proto::*;
-use ntp::server::GPSServer;
+use ntp::ratelimit::{Decision, RateLimitConfig, RateLimiter};
+use ntp::restrict::{Action, Restrictions};
+use ntp::server::{measure_precision, GPSServer, SyncState};
 use std::collections::VecDeque;
 use std::env;
 use std::io;
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use ubx::config::AckTracker;
 use ubx::helpers::disable_nmea;
+use ubx::latency::{backdate, SerialLatency};
 use ubx::proto::*;
 use ubx::proto_cfg::*;
 use ubx::proto_nav::*;
 
+/// The receiver's rate after a reset
+const BAUD: u32 = 9600;
+/// The rate to switch to unless UBX_BAUD says otherwise; the faster the link, the less a frame's
+/// transmission delays it
+const FAST_BAUD: u32 = 115200;
+/// How long to wait for the receiver to acknowledge configuration, and how often to resend it
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(1);
+const CONFIG_RETRIES: u32 = 3;
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Expected 1 argument, serial device (ie: /dev/ttyUSB1)");
+    if !(2..=4).contains(&args.len()) {
+        panic!("Expected serial device (ie: /dev/ttyUSB1), optionally an ntpd keys file and a restrict file");
+    }
+    let port = serial2::SerialPort::open(args[1].clone(), BAUD).unwrap();
+    let mut latency = SerialLatency::new(BAUD);
+    if let Ok(us) = env::var("UBX_FIXED_LATENCY_US") {
+        latency.fixed = Duration::from_micros(us.parse().expect("Bad UBX_FIXED_LATENCY_US"));
     }
-    let port = serial2::SerialPort::open(args[1].clone(), 9600).unwrap();
+
+    let fast_baud = match env::var("UBX_BAUD") {
+        Ok(b) => b.parse().expect("Bad UBX_BAUD"),
+        Err(_) => FAST_BAUD,
+    };
+    // the writer switches the rate, the reader needs it to date bytes
+    let baud = AtomicU32::new(BAUD);
 
     let si = SerialIterator {
         buf: VecDeque::new(),
         port: &port,
+        baud: &baud,
     };
-    let buf = CfgMsg {
-        c: Class::Navigation,
-        id: 0x8,
-        rate: Duration::from_millis(1000),
-    }
-    .serialize_request();
-    port.write_all(&disable_nmea(9600)).unwrap();
-    port.write(&buf).unwrap();
+    let acks = AckTracker::new();
 
     let buf = TimeGPS::frame();
-    let m_srv = Arc::new(Mutex::new(GPSServer::new()));
+    let mut leap_table = LeapTable::builtin();
+    if let Ok(path) = env::var("LEAP_SECONDS_LIST") {
+        let text = std::fs::read_to_string(path).unwrap();
+        let table = LeapTable::parse(&text).expect("Could not parse leap seconds list");
+        // an outdated file does not replace a newer built-in list
+        if table.expires > leap_table.expires {
+            leap_table = table;
+        }
+    }
+    let mut srv = GPSServer::new();
+    srv.set_clock_precision(measure_precision(system_now));
+    if let Ok(hours) = env::var("LEAP_SMEAR_HOURS") {
+        let hours: u64 = hours.parse().expect("Bad LEAP_SMEAR_HOURS");
+        srv.set_leap_mode(LeapMode::Smear {
+            window: Duration::from_secs(hours * 60 * 60),
+        });
+    }
+    if let Some(path) = args.get(2) {
+        let keys = std::fs::read_to_string(path).unwrap();
+        srv.set_keys(KeyTable::parse(&keys).expect("Could not parse keys"));
+    }
+    if let Some(path) = args.get(3) {
+        let rules = std::fs::read_to_string(path).unwrap();
+        srv.set_restrictions(Restrictions::parse(&rules).expect("Could not parse restrictions"));
+    }
+    let m_srv = Arc::new(Mutex::new(srv));
     let m_srv2 = m_srv.clone();
+    let gps_clock = GpsClock::default();
     std::thread::scope(|s| {
-        s.spawn(|| handle_ntp_queries(m_srv));
-        s.spawn(|| loop {
-            port.write(&buf).unwrap();
-            std::thread::sleep(Duration::from_secs(2));
+        s.spawn(|| handle_ntp_queries(m_srv, &gps_clock));
+        s.spawn(|| {
+            // answers come in through the loop below
+            let mut control = port.try_clone().unwrap();
+            let set_baud = |b| {
+                port.flush()?;
+                let mut settings = control.get_configuration()?;
+                settings.set_baud_rate(b)?;
+                control.set_configuration(&settings)?;
+                baud.store(b, Ordering::Relaxed);
+                Ok(())
+            };
+            let res = acks.switch_baud(
+                |b| port.write_all(b),
+                set_baud,
+                BAUD,
+                fast_baud,
+                CONFIG_TIMEOUT,
+                CONFIG_RETRIES,
+            );
+            println!("switching to {} baud: {:?}", fast_baud, res);
+            let res = acks.send_config(
+                |b| port.write_all(b),
+                &disable_nmea(baud.load(Ordering::Relaxed)),
+                CONFIG_TIMEOUT,
+                CONFIG_RETRIES,
+            );
+            println!("disabling NMEA: {:?}", res);
+            let rate = CfgRate {
+                measurement: Duration::from_secs(1),
+                measurements_per_solution: 1,
+                time_ref: TimeRef::GPS,
+            };
+            let res = acks.send_config(
+                |b| port.write_all(b),
+                &rate.packet(),
+                CONFIG_TIMEOUT,
+                CONFIG_RETRIES,
+            );
+            println!("setting the navigation rate: {:?}", res);
+            loop {
+                port.write(&buf).unwrap();
+                port.write(&TimeLS::frame()).unwrap();
+                std::thread::sleep(Duration::from_secs(2));
+            }
         });
         for p in PacketIterator::new(si.into_iter()) {
-            let pp = ParsedPacket::from(p);
+            latency.baud = baud.load(Ordering::Relaxed);
+            let age = latency.age(&p, Instant::now());
+            let pp = match ParsedPacket::try_from(p) {
+                Ok(pp) => pp,
+                Err(e) => {
+                    println!("bad packet {:?}", e);
+                    continue;
+                }
+            };
             println!("pp {:?}", pp);
             match pp {
                 ParsedPacket::Configuration(_) => println!("cfg {:?}", pp),
                 ParsedPacket::Navigation(n) => match n {
                     NavPacket::TimeGPS(t) => {
-                        let dt = Option::<DateTime<Utc>>::from(t);
+                        // the time in the frame is `age` old by now
+                        let dt = t
+                            .utc(&leap_table)
+                            .map(|dt| dt + TimeDelta::from_std(age).unwrap());
                         println!("dt {:?}", dt);
-                        if dt.is_some() {
-                            m_srv2.lock().unwrap().update_reference_time(dt.unwrap());
+                        if let Some(dt) = dt {
+                            let mut srv = m_srv2.lock().unwrap();
+                            // the GPS clock takes the leap second once it is due, until then
+                            // it keeps the timescale from before the event
+                            if let Some(leap) = srv.leap_due(srv.leap_uncorrected(dt)) {
+                                srv.leap_applied();
+                                println!("applied leap second {:?}", leap);
+                            }
+                            gps_clock.set(srv.leap_uncorrected(dt), Instant::now());
+                            srv.update_reference_time(dt, t.accuracy);
                         }
                     }
                     NavPacket::SVInfo(t) => {}
                     NavPacket::TimeUTC(t) => {}
+                    NavPacket::LeapSecond(t) => {
+                        if let Some((seconds, change)) = t.countdown() {
+                            let now = gps_clock.now().unwrap_or_else(system_now);
+                            let mut srv = m_srv2.lock().unwrap();
+                            srv.leap_countdown(now, seconds, change);
+                            println!("leap {:?}", srv.leap);
+                        }
+                    }
                     NavPacket::Status(s) => {}
                 },
-                ParsedPacket::Nack => println!("sad nack"),
+                ParsedPacket::Timing(t) => println!("tim {:?}", t),
+                ParsedPacket::Ack(a) => acks.record(a),
+                ParsedPacket::Unknown(p) => println!("unknown {:?}", p),
             };
         }
     });
 }
 
-fn handle_ntp_queries(s: Arc<Mutex<GPSServer>>) -> std::io::Result<()> {
+/// The host clock is not disciplined to GPS, so time is served from the last GPS time and how
+/// long ago it arrived. This is the local clock for `GPSServer`: it is stepped for a leap second
+/// when the server says it is due, by following GPS again.
+#[derive(Default)]
+struct GpsClock {
+    last: Mutex<Option<(DateTime<Utc>, Instant)>>,
+}
+
+impl GpsClock {
+    fn set(&self, t: DateTime<Utc>, at: Instant) {
+        *self.last.lock().unwrap() = Some((t, at));
+    }
+
+    fn now(&self) -> Option<DateTime<Utc>> {
+        let (t, at) = (*self.last.lock().unwrap())?;
+        Some(t + TimeDelta::from_std(at.elapsed()).ok()?)
+    }
+}
+
+fn handle_ntp_queries(s: Arc<Mutex<GPSServer>>, clock: &GpsClock) -> std::io::Result<()> {
     let socket = UdpSocket::bind("127.0.0.1:123")?;
+    let mut buf = vec![0; NTP_MAX_MESSAGE_LEN];
+    let mut limiter = RateLimiter::new(RateLimitConfig::default());
     loop {
-        let mut buf = [0; 128];
         let (amt, src) = socket.recv_from(&mut buf)?;
-        if amt != NTP_MESSAGE_LEN {
-            println!("bad, len was {}: {:?}", amt, &buf);
+        println!("pkt from {:?}", src);
+        let action = s.lock().unwrap().restrictions.action(src.ip());
+        if action == Action::Ignore {
+            println!("ignoring {:?}", src);
             continue;
         }
-        println!("pkt from {:?}", src);
-        let buf = &mut buf[..NTP_MESSAGE_LEN];
-        let q = NTPQuery::deserialize(buf).unwrap();
+        let decision = limiter.check(src.ip(), Instant::now());
+        if decision == Decision::Drop {
+            println!("rate limiting {:?}, dropping packet", src);
+            continue;
+        }
+        let q = match NTPMessage::deserialize(&buf[..amt]) {
+            Ok(q) => q,
+            Err(e) => {
+                println!("rejected packet from {:?}: {:?}", src, e);
+                continue;
+            }
+        };
         let a = {
             let gps = s.lock().unwrap();
-            match gps.reftime {
-                Some(now) => Some(gps.answer_query(q, now, now)),
-                None => None,
+            match clock.now().map(|now| (now, gps.state(now))) {
+                None | Some((_, SyncState::Unsynchronized)) => None,
+                Some((now, state)) => {
+                    println!("server is {}", state.name());
+                    Some(gps.answer_query(&q, &buf[..amt], now, now))
+                }
             }
         };
 
         match a {
-            Some(answer) => {
+            Some(Ok(mut answer)) => {
+                if action == Action::Kod {
+                    answer.kiss_o_death(*b"DENY");
+                } else if decision == Decision::Kod {
+                    answer.kiss_o_death(*b"RATE");
+                }
                 let outbuf = answer.serialize();
                 socket.send_to(&outbuf, src)?;
             }
+            Some(Err(r)) => {
+                println!("not answering {:?}: {:?}", src, r);
+            }
             None => {
                 println!("GPS server not in sync, dropping packet");
             }
@@ -96,26 +257,32 @@ fn handle_ntp_queries(s: Arc<Mutex<GPSServer>>) -> std::io::Result<()> {
     }
 }
 
+/// Yields each byte with when it arrived, going by when the read returned and the baud rate
 pub struct SerialIterator<'a> {
-    buf: VecDeque<u8>,
+    buf: VecDeque<(u8, Instant)>,
     port: &'a serial2::SerialPort,
+    baud: &'a AtomicU32,
 }
 
 impl<'a> Iterator for SerialIterator<'a> {
-    type Item = u8;
-    fn next(&mut self) -> Option<u8> {
+    type Item = (u8, Instant);
+    fn next(&mut self) -> Option<(u8, Instant)> {
         if self.buf.len() > 0 {
             let y = self.buf.pop_front();
             return y;
         }
-        let mut inbuf = vec![0, 128];
+        let mut inbuf = vec![0; 128];
         loop {
             {
                 let p = self.port;
                 match p.read(inbuf.as_mut_slice()) {
                     Ok(t) => {
                         if t > 0 {
-                            self.buf.extend(&inbuf[..t]);
+                            self.buf.extend(backdate(
+                                &inbuf[..t],
+                                Instant::now(),
+                                self.baud.load(Ordering::Relaxed),
+                            ));
                             break;
                         }
                     }
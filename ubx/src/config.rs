@@ -0,0 +1,282 @@
+//! Configuration that is confirmed: the receiver answers every CFG message with an ACK-ACK or
+//! ACK-NAK, which arrives on the same stream as everything else. Whoever reads the stream hands
+//! the answers to an `AckTracker`, which the sender waits on.
+use crate::helpers::disable_nmea;
+use crate::proto::{Class, Packet, Poll};
+use crate::proto_ack::AckPacket;
+use crate::proto_cfg::CfgRate;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Answers nobody waited for are dropped past this many
+const MAX_UNCLAIMED: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError<E> {
+    /// The receiver answered with ACK-NAK
+    Rejected,
+    /// No answer after all the retries
+    NoAnswer,
+    /// Writing to the receiver failed
+    Write(E),
+}
+
+#[derive(Debug, Default)]
+pub struct AckTracker {
+    answers: Mutex<VecDeque<AckPacket>>,
+    arrived: Condvar,
+}
+
+impl AckTracker {
+    pub fn new() -> AckTracker {
+        AckTracker::default()
+    }
+
+    /// Called by the reader for each answer it sees
+    pub fn record(&self, ack: AckPacket) {
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() == MAX_UNCLAIMED {
+            answers.pop_front();
+        }
+        answers.push_back(ack);
+        self.arrived.notify_all();
+    }
+
+    /// Waits up to `timeout` for the answer to a message of `class` and `id`, and takes it
+    pub fn wait(&self, class: Class, id: u8, timeout: Duration) -> Option<AckPacket> {
+        let deadline = Instant::now() + timeout;
+        let mut answers = self.answers.lock().unwrap();
+        loop {
+            if let Some(i) = answers.iter().position(|a| a.answers(class, id)) {
+                return answers.remove(i);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+            answers = self.arrived.wait_timeout(answers, left).unwrap().0;
+        }
+    }
+
+    /// Drops answers to an earlier `class`/`id` message, they would be taken for answers to the
+    /// next one
+    fn forget(&self, class: Class, id: u8) {
+        self.answers
+            .lock()
+            .unwrap()
+            .retain(|a| !a.answers(class, id));
+    }
+
+    /// Sends `p` with `write` until the receiver answers, at most `retries` more times, waiting
+    /// `timeout` for each answer. An ACK-NAK is not retried.
+    pub fn send_config<E>(
+        &self,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+        p: &Packet,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<(), ConfigError<E>> {
+        self.forget(p.class, p.id);
+        let buf = p.serialize();
+        for _ in 0..=retries {
+            write(&buf).map_err(ConfigError::Write)?;
+            match self.wait(p.class, p.id, timeout) {
+                Some(AckPacket::Ack { .. }) => return Ok(()),
+                Some(AckPacket::Nak { .. }) => return Err(ConfigError::Rejected),
+                None => continue,
+            }
+        }
+        Err(ConfigError::NoAnswer)
+    }
+
+    /// Moves the link from `from` to `to` baud: sends CFG-PRT, which also leaves the port UBX
+    /// only, and has `set_baud` switch the host once what was written went out. The link counts
+    /// as up once a poll is answered at the new rate. On an error the link is back at `from`.
+    pub fn switch_baud<E>(
+        &self,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+        mut set_baud: impl FnMut(u32) -> Result<(), E>,
+        from: u32,
+        to: u32,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<(), ConfigError<E>> {
+        let probe = CfgRate::poll_packet();
+        // the receiver keeps its rate when only the host restarted
+        if self.send_config(&mut write, &probe, timeout, 0).is_err() {
+            set_baud(to).map_err(ConfigError::Write)?;
+            if self.send_config(&mut write, &probe, timeout, 0).is_ok() {
+                return Ok(());
+            }
+            set_baud(from).map_err(ConfigError::Write)?;
+        }
+        self.move_receiver(&mut write, to, timeout)?;
+        set_baud(to).map_err(ConfigError::Write)?;
+        if self
+            .send_config(&mut write, &probe, timeout, retries)
+            .is_ok()
+        {
+            return Ok(());
+        }
+        // the receiver may have switched without the host hearing from it
+        self.move_receiver(&mut write, from, timeout)?;
+        set_baud(from).map_err(ConfigError::Write)?;
+        self.send_config(&mut write, &probe, timeout, retries)?;
+        Err(ConfigError::NoAnswer)
+    }
+
+    fn move_receiver<E>(
+        &self,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+        baud: u32,
+        timeout: Duration,
+    ) -> Result<(), ConfigError<E>> {
+        let p = disable_nmea(baud);
+        self.forget(p.class, p.id);
+        write(&p.serialize()).map_err(ConfigError::Write)?;
+        // the answer goes out while the receiver switches and is usually lost; this only gives
+        // it time to switch
+        self.wait(p.class, p.id, timeout);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn cfg_prt() -> Packet {
+        Packet {
+            class: Class::ConfigInput,
+            id: 0x00,
+            payload: vec![0; 20],
+            received_at: None,
+        }
+    }
+
+    #[test]
+    fn waits_for_the_matching_answer() {
+        let acks = AckTracker::new();
+        let timeout = Duration::from_secs(5);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                // traffic for another message does not count
+                acks.record(AckPacket::Ack {
+                    class: Class::ConfigInput,
+                    id: 0x01,
+                });
+                std::thread::sleep(Duration::from_millis(20));
+                acks.record(AckPacket::Ack {
+                    class: Class::ConfigInput,
+                    id: 0x00,
+                });
+            });
+            assert_eq!(
+                acks.send_config(|_| Ok::<(), ()>(()), &cfg_prt(), timeout, 0),
+                Ok(())
+            );
+        });
+        // the unrelated answer is still there for whoever sent that message
+        assert!(acks
+            .wait(Class::ConfigInput, 0x01, Duration::ZERO)
+            .is_some());
+
+        acks.record(AckPacket::Nak {
+            class: Class::ConfigInput,
+            id: 0x00,
+        });
+        // a stale answer is not taken for this one
+        let writes = Cell::new(0);
+        let write = |_: &[u8]| {
+            writes.set(writes.get() + 1);
+            Ok::<(), ()>(())
+        };
+        let short = Duration::from_millis(10);
+        assert_eq!(
+            acks.send_config(write, &cfg_prt(), short, 2),
+            Err(ConfigError::NoAnswer)
+        );
+        assert_eq!(writes.get(), 3);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                acks.record(AckPacket::Nak {
+                    class: Class::ConfigInput,
+                    id: 0x00,
+                });
+            });
+            assert_eq!(
+                acks.send_config(|_| Ok::<(), ()>(()), &cfg_prt(), timeout, 3),
+                Err(ConfigError::Rejected)
+            );
+        });
+        assert_eq!(
+            acks.send_config(|_| Err("unplugged"), &cfg_prt(), timeout, 3),
+            Err(ConfigError::Write("unplugged"))
+        );
+    }
+
+    /// A receiver that hears the host only at the same baud rate, and answers everything but
+    /// CFG-PRT, whose answer is lost in the switch
+    struct Receiver<'a> {
+        acks: &'a AckTracker,
+        host: Cell<u32>,
+        baud: Cell<u32>,
+        switches: bool,
+    }
+
+    impl Receiver<'_> {
+        fn switch(&self, from: u32, to: u32) -> Result<(), ConfigError<()>> {
+            let write = |b: &[u8]| {
+                let p = Packet::deserialize(b).unwrap();
+                if self.host.get() != self.baud.get() {
+                    return Ok(());
+                }
+                match p.id {
+                    0x00 if self.switches => {
+                        self.baud
+                            .set(u32::from_le_bytes(p.payload[8..12].try_into().unwrap()));
+                    }
+                    0x00 => {}
+                    id => self.acks.record(AckPacket::Ack { class: p.class, id }),
+                }
+                Ok(())
+            };
+            let set_baud = |b| {
+                self.host.set(b);
+                Ok(())
+            };
+            let timeout = Duration::from_millis(10);
+            self.acks.switch_baud(write, set_baud, from, to, timeout, 1)
+        }
+    }
+
+    #[test]
+    fn switches_baud() {
+        let acks = AckTracker::new();
+        let receiver = |host, baud, switches| Receiver {
+            acks: &acks,
+            host: Cell::new(host),
+            baud: Cell::new(baud),
+            switches,
+        };
+
+        let r = receiver(9600, 9600, true);
+        assert_eq!(r.switch(9600, 115200), Ok(()));
+        assert_eq!((r.host.get(), r.baud.get()), (115200, 115200));
+
+        // after a host restart
+        let r = receiver(9600, 115200, true);
+        assert_eq!(r.switch(9600, 115200), Ok(()));
+        assert_eq!((r.host.get(), r.baud.get()), (115200, 115200));
+
+        // a receiver that stays put is still reachable afterwards
+        let r = receiver(9600, 9600, false);
+        assert_eq!(r.switch(9600, 115200), Err(ConfigError::NoAnswer));
+        assert_eq!((r.host.get(), r.baud.get()), (9600, 9600));
+    }
+}
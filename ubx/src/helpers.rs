@@ -1,7 +1,9 @@
 use crate::proto::{Class, Packet, Serialize};
 use crate::proto_cfg::*;
+use chrono::{DateTime, NaiveDate, Utc};
 
-pub fn disable_nmea(baudrate: u32) -> Vec<u8> {
+/// CFG-PRT for UART1: UBX only, in and out
+pub fn disable_nmea(baudrate: u32) -> Packet {
     let pc = Port {
         port_mode: PortMode::UART(UartCfg {
             baudrate,
@@ -11,14 +13,24 @@ pub fn disable_nmea(baudrate: u32) -> Vec<u8> {
         proto_in: PortProto::UBX,
         proto_out: PortProto::UBX,
     };
-    let buf = pc.serialize();
-    let p = Packet {
+    Packet {
         class: Class::ConfigInput,
         id: 0x0,
-        payload: buf,
-    };
-    p.serialize()
+        payload: pc.serialize(),
+        received_at: None,
+    }
+}
+/// Start of GPS time, https://www.gps.gov/technical/icwg/IS-GPS-200G.pdf, page 39
+pub(crate) fn gps_epoch() -> DateTime<Utc> {
+    DateTime::<Utc>::from_naive_utc_and_offset(
+        NaiveDate::from_ymd_opt(1980, 1, 6)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap(),
+        Utc,
+    )
 }
+
 pub(crate) fn buf_to_2u8(buf: &[u8]) -> [u8; 2] {
     [buf[0], buf[1]]
 }
@@ -0,0 +1,91 @@
+//! How old the receiver's time is by the time it is used: a frame is timestamped when its first
+//! byte arrived, and serial ports only hand over bytes in bursts
+use crate::proto::Packet;
+use std::time::{Duration, Instant};
+
+/// 8N1: a start bit, 8 data bits and a stop bit
+const BITS_PER_BYTE: u32 = 10;
+
+/// Time it takes to send `bytes` at `baud`
+pub fn transmission_time(bytes: usize, baud: u32) -> Duration {
+    Duration::from_secs(u64::from(BITS_PER_BYTE) * bytes as u64) / baud
+}
+
+/// Timestamps each byte of `buf`, assuming they arrived back to back and the last one arrived at
+/// `last_at`
+pub fn backdate(
+    buf: &[u8],
+    last_at: Instant,
+    baud: u32,
+) -> impl Iterator<Item = (u8, Instant)> + '_ {
+    let n = buf.len();
+    buf.iter()
+        .enumerate()
+        .map(move |(i, b)| (*b, last_at - transmission_time(n - 1 - i, baud)))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SerialLatency {
+    pub baud: u32,
+    /// Delay between the time in a frame and the frame starting to go out, on the receiver's
+    /// side; can be measured against PPS or an upstream server
+    pub fixed: Duration,
+}
+
+impl SerialLatency {
+    pub fn new(baud: u32) -> SerialLatency {
+        SerialLatency {
+            baud,
+            fixed: Duration::ZERO,
+        }
+    }
+
+    /// How much time passed, at `now`, since the time in `packet`. Without a timestamp the packet
+    /// is assumed to have just finished arriving.
+    pub fn age(&self, packet: &Packet, now: Instant) -> Duration {
+        let since_first_byte = match packet.received_at {
+            Some(at) => now.saturating_duration_since(at),
+            None => transmission_time(packet.len_with_frame(), self.baud),
+        };
+        self.fixed + since_first_byte
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Class;
+
+    #[test]
+    fn baud_model() {
+        // a NAV-TIMEGPS frame is 24 bytes
+        assert_eq!(transmission_time(24, 9600), Duration::from_millis(25));
+        assert_eq!(transmission_time(1, 115_200), Duration::from_nanos(86_805));
+
+        let t = Instant::now();
+        let bytes: Vec<_> = backdate(&[1, 2, 3], t, 9600).collect();
+        let byte = |n| transmission_time(n, 9600);
+        assert_eq!(bytes, vec![(1, t - byte(2)), (2, t - byte(1)), (3, t)]);
+    }
+
+    #[test]
+    fn packet_age() {
+        let t = Instant::now();
+        let mut p = Packet {
+            class: Class::Navigation,
+            id: 0x20,
+            payload: vec![0; 16],
+            received_at: None,
+        };
+        let l = SerialLatency {
+            baud: 9600,
+            fixed: Duration::from_millis(3),
+        };
+        assert_eq!(l.age(&p, t), Duration::from_millis(28));
+        p.received_at = Some(t);
+        assert_eq!(
+            l.age(&p, t + Duration::from_millis(40)),
+            Duration::from_millis(43)
+        );
+    }
+}
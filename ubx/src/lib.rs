@@ -1,4 +1,8 @@
+pub mod config;
 pub mod helpers;
+pub mod latency;
 pub mod proto;
+pub mod proto_ack;
 pub mod proto_cfg;
 pub mod proto_nav;
+pub mod proto_tim;
@@ -1,7 +1,10 @@
 use std::collections::VecDeque;
+use std::time::Instant;
 
+use crate::proto_ack::AckPacket;
 use crate::proto_cfg::CfgPacket;
 use crate::proto_nav::NavPacket;
+use crate::proto_tim::TimPacket;
 
 pub trait Serialize {
     fn serialize(&self) -> Vec<u8>;
@@ -10,6 +13,14 @@ pub trait Poll {
     fn class() -> Class;
     fn id() -> u8;
     fn polling_payload() -> Vec<u8>;
+    fn poll_packet() -> Packet {
+        Packet {
+            class: Self::class(),
+            id: Self::id(),
+            payload: Self::polling_payload(),
+            received_at: None,
+        }
+    }
 }
 
 pub trait Frame {
@@ -18,12 +29,7 @@ pub trait Frame {
 
 impl<T: Poll> Frame for T {
     fn frame() -> Vec<u8> {
-        Packet {
-            class: Self::class().into(),
-            id: Self::id(),
-            payload: Self::polling_payload(),
-        }
-        .serialize()
+        Self::poll_packet().serialize()
     }
 }
 
@@ -31,15 +37,47 @@ impl<T: Poll> Frame for T {
 pub enum ParsedPacket {
     Navigation(NavPacket),
     Configuration(CfgPacket),
-    Nack,
+    Timing(TimPacket),
+    Ack(AckPacket),
+    /// A message this crate does not decode, e.g. INF or MON
+    Unknown(Packet),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// Not a message this crate decodes; `ParsedPacket` returns these as `Unknown`
+    UnknownMessage { class: Class, id: u8 },
+    /// The payload is shorter than the message needs
+    TooShort { needed: usize, got: usize },
 }
 
-impl From<Packet> for ParsedPacket {
-    fn from(p: Packet) -> ParsedPacket {
-        match p.class {
-            Class::Navigation => ParsedPacket::Navigation(NavPacket::from(p)),
-            Class::AckNack => ParsedPacket::Nack,
-            _ => panic!("what do {:?}", p),
+/// Fails unless `buf` has at least `needed` bytes
+pub(crate) fn check_len(buf: &[u8], needed: usize) -> Result<(), DecodeError> {
+    if buf.len() < needed {
+        return Err(DecodeError::TooShort {
+            needed,
+            got: buf.len(),
+        });
+    }
+    Ok(())
+}
+
+impl TryFrom<Packet> for ParsedPacket {
+    type Error = DecodeError;
+    fn try_from(p: Packet) -> Result<ParsedPacket, DecodeError> {
+        let parsed = match p.class {
+            Class::Navigation => NavPacket::try_from(&p).map(ParsedPacket::Navigation),
+            Class::AckNack => AckPacket::try_from(&p).map(ParsedPacket::Ack),
+            Class::Timing => TimPacket::try_from(&p).map(ParsedPacket::Timing),
+            Class::ConfigInput => CfgPacket::try_from(&p).map(ParsedPacket::Configuration),
+            _ => Err(DecodeError::UnknownMessage {
+                class: p.class,
+                id: p.id,
+            }),
+        };
+        match parsed {
+            Err(DecodeError::UnknownMessage { .. }) => Ok(ParsedPacket::Unknown(p)),
+            parsed => parsed,
         }
     }
 }
@@ -55,6 +93,8 @@ pub enum Class {
     AssistNowAid,
     Timing,
     Reserved3,
+    /// A class this crate has no name for, e.g. ESF, LOG, SEC or HNR
+    Other(u8),
 }
 
 impl From<Class> for u8 {
@@ -69,24 +109,24 @@ impl From<Class> for u8 {
             Class::Monitoring => 0xA,
             Class::AssistNowAid => 0xB,
             Class::Timing => 0xD,
+            Class::Other(c) => c,
         }
     }
 }
 
-impl TryFrom<u8> for Class {
-    type Error = ();
-    fn try_from(u: u8) -> Result<Self, ()> {
+impl From<u8> for Class {
+    fn from(u: u8) -> Self {
         match u {
-            0x1 => Ok(Class::Navigation),
-            0x2 => Ok(Class::ReceiverManager),
-            0x3 => Ok(Class::Reserved3),
-            0x4 => Ok(Class::Information),
-            0x5 => Ok(Class::AckNack),
-            0x6 => Ok(Class::ConfigInput),
-            0xA => Ok(Class::Monitoring),
-            0xB => Ok(Class::AssistNowAid),
-            0xD => Ok(Class::Timing),
-            _ => Err(()),
+            0x1 => Class::Navigation,
+            0x2 => Class::ReceiverManager,
+            0x3 => Class::Reserved3,
+            0x4 => Class::Information,
+            0x5 => Class::AckNack,
+            0x6 => Class::ConfigInput,
+            0xA => Class::Monitoring,
+            0xB => Class::AssistNowAid,
+            0xD => Class::Timing,
+            c => Class::Other(c),
         }
     }
 }
@@ -96,6 +136,8 @@ pub struct Packet {
     pub class: Class,
     pub id: u8,
     pub payload: Vec<u8>,
+    /// When the first sync byte arrived, if the byte source timestamps its bytes
+    pub received_at: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -103,7 +145,6 @@ pub enum BadDeserialization {
     IncompleteRead,
     BadChecksum,
     BadMagic,
-    Unsupported(u8),
 }
 
 impl Packet {
@@ -114,7 +155,7 @@ impl Packet {
         Packet::from_iter(&mut buf.into_iter().copied())
     }
 
-    fn len_with_frame(&self) -> usize {
+    pub fn len_with_frame(&self) -> usize {
         self.payload.len() + Self::MIN_PKT_LEN
     }
     pub fn serialize(&self) -> Vec<u8> {
@@ -174,12 +215,11 @@ impl Packet {
             return Err(BadDeserialization::BadChecksum);
         }
 
-        let class =
-            Class::try_from(class_u8).map_err(|_| BadDeserialization::Unsupported(class_u8))?;
         Ok(Packet {
-            class,
+            class: Class::from(class_u8),
             id,
             payload: b[6..b.len()].into(),
+            received_at: None,
         })
     }
 }
@@ -195,17 +235,42 @@ fn checksum(buf: &[u8]) -> (u8, u8) {
     (ck_a, ck_b)
 }
 
-pub struct PacketIterator<I: Iterator<Item = u8>> {
+/// A byte read from the receiver. Sources which know when each byte arrived yield
+/// `(u8, Instant)`, which `PacketIterator` turns into `Packet::received_at`
+pub trait StreamByte {
+    fn byte(&self) -> u8;
+    fn arrived_at(&self) -> Option<Instant>;
+}
+
+impl StreamByte for u8 {
+    fn byte(&self) -> u8 {
+        *self
+    }
+    fn arrived_at(&self) -> Option<Instant> {
+        None
+    }
+}
+
+impl StreamByte for (u8, Instant) {
+    fn byte(&self) -> u8 {
+        self.0
+    }
+    fn arrived_at(&self) -> Option<Instant> {
+        Some(self.1)
+    }
+}
+
+pub struct PacketIterator<I: Iterator> {
     stream: I,
     consecutive_inc: u8,
-    buf: VecDeque<u8>,
+    buf: VecDeque<(u8, Option<Instant>)>,
 }
 
-impl<I: Iterator<Item = u8>> PacketIterator<I> {
-    pub fn new(i: I) -> PacketIterator<I>
-    where
-        I: Iterator<Item = u8>,
-    {
+impl<I: Iterator> PacketIterator<I>
+where
+    I::Item: StreamByte,
+{
+    pub fn new(i: I) -> PacketIterator<I> {
         let b = VecDeque::with_capacity(128);
         PacketIterator {
             stream: i,
@@ -213,15 +278,25 @@ impl<I: Iterator<Item = u8>> PacketIterator<I> {
             consecutive_inc: 0,
         }
     }
+
+    fn read_byte(&mut self) -> Option<()> {
+        let b = self.stream.next()?;
+        self.buf.push_back((b.byte(), b.arrived_at()));
+        Some(())
+    }
 }
-impl<I: Iterator<Item = u8>> Iterator for PacketIterator<I> {
+
+impl<I: Iterator> Iterator for PacketIterator<I>
+where
+    I::Item: StreamByte,
+{
     type Item = Packet;
     fn next(&mut self) -> Option<Packet> {
         loop {
             if self.buf.len() == 0 {
-                self.buf.push_back(self.stream.next()?);
+                self.read_byte()?;
             }
-            let r = Packet::from_iter(&mut self.buf.iter().copied());
+            let r = Packet::from_iter(&mut self.buf.iter().map(|(b, _)| *b));
             match r {
                 Err(BadDeserialization::BadChecksum) => {
                     self.consecutive_inc = 0;
@@ -235,7 +310,7 @@ impl<I: Iterator<Item = u8>> Iterator for PacketIterator<I> {
                     // This is terrible for performance, but
                     // simple iterators will block when reading, instead of returning None
                     // which means the batch optimization does not work
-                    self.buf.push_back(self.stream.next()?);
+                    self.read_byte()?;
                     /*
                     // if we trigger IncompleteRead twice in a row,
                     // the upstream iterator returned None twice; so it's empty
@@ -260,13 +335,9 @@ impl<I: Iterator<Item = u8>> Iterator for PacketIterator<I> {
                     }
                     */
                 }
-                Err(BadDeserialization::Unsupported(class)) => {
-                    self.consecutive_inc = 0;
-                    self.buf.pop_front();
-                    println!("Eeek! {}", class);
-                }
-                Ok(p) => {
+                Ok(mut p) => {
                     self.consecutive_inc = 0;
+                    p.received_at = self.buf.front().and_then(|(_, at)| *at);
                     drop(self.buf.drain(..(p.len_with_frame())));
                     return Some(p);
                 }
@@ -328,6 +399,35 @@ mod tests {
         assert_eq!(count, 2);
     }
     #[test]
+    fn from_iterator_other_classes() {
+        // ESF-STATUS, a class without a name here, between two NAV-TIMEGPS
+        let esf = Packet {
+            class: Class::Other(0x10),
+            id: 0x10,
+            payload: vec![1, 2, 3],
+            received_at: None,
+        }
+        .serialize();
+        let nav = [
+            0xb5, 0x62, 0x01, 0x20, 0x10, 0x00, 0xce, 0x74, 0x3e, 0x04, 0x88, 0xcc, 0xfa, 0xff,
+            0x81, 0x07, 0x11, 0x07, 0x2c, 0x33, 0x31, 0x01, 0x33, 0x25,
+        ];
+        let buf: Vec<u8> = [&nav[..], &esf, &nav].concat();
+        let packets: Vec<Packet> = PacketIterator::new(buf.into_iter()).collect();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[1].class, Class::Other(0x10));
+        assert_eq!(packets[1].payload, vec![1, 2, 3]);
+        let p = packets.into_iter().nth(1).unwrap();
+        assert!(matches!(
+            ParsedPacket::try_from(p),
+            Ok(ParsedPacket::Unknown(Packet {
+                class: Class::Other(0x10),
+                id: 0x10,
+                ..
+            }))
+        ));
+    }
+    #[test]
     fn from_iterator_leading_garbage() {
         let buf = vec![
             0xaa, 0xaa, 0xbb, /* <-- 3 'noise' elements */
@@ -347,6 +447,31 @@ mod tests {
         }
         assert_eq!(count, 2);
     }
+    #[test]
+    fn from_iterator_timestamps() {
+        let frame = [
+            0xb5, 0x62, 0x01, 0x20, 0x10, 0x00, 0xce, 0x74, 0x3e, 0x04, 0x88, 0xcc, 0xfa, 0xff,
+            0x81, 0x07, 0x11, 0x07, 0x2c, 0x33, 0x31, 0x01, 0x33, 0x25,
+        ];
+        let t0 = Instant::now();
+        let at = |i: usize| t0 + std::time::Duration::from_millis(i as u64);
+        // garbage, then two frames
+        let bytes: Vec<(u8, Instant)> = [0xaa, 0xb5]
+            .iter()
+            .chain(frame.iter())
+            .chain(frame.iter())
+            .enumerate()
+            .map(|(i, b)| (*b, at(i)))
+            .collect();
+        let received: Vec<_> = PacketIterator::new(bytes.into_iter())
+            .map(|p| p.received_at)
+            .collect();
+        assert_eq!(received, vec![Some(at(2)), Some(at(2 + frame.len()))]);
+
+        let p = PacketIterator::new(frame.into_iter()).next().unwrap();
+        assert_eq!(p.received_at, None);
+    }
+
     #[test]
     fn from_iterator_incomplete_payload() {
         let buf = vec![
@@ -361,4 +486,50 @@ mod tests {
         }
         assert_eq!(count, 0);
     }
+
+    #[test]
+    fn unknown_and_short_packets() {
+        // MON-VER
+        let p = Packet {
+            class: Class::Monitoring,
+            id: 0x04,
+            payload: vec![0; 40],
+            received_at: None,
+        };
+        match ParsedPacket::try_from(p) {
+            Ok(ParsedPacket::Unknown(p)) => assert_eq!(p.id, 0x04),
+            r => panic!("{:?}", r),
+        }
+        let p = Packet {
+            class: Class::Navigation,
+            id: 0x20,
+            payload: vec![0; 10],
+            received_at: None,
+        };
+        assert_eq!(
+            ParsedPacket::try_from(p).unwrap_err(),
+            DecodeError::TooShort {
+                needed: 16,
+                got: 10
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_anything_without_panicking() {
+        for class in (0..=u8::MAX).map(Class::from) {
+            for id in 0..=u8::MAX {
+                for len in 0..64 {
+                    // a byte pattern with a large SV count
+                    let payload = (0..len).map(|i| (i * 37) as u8 | 0x80).collect();
+                    let _ = ParsedPacket::try_from(Packet {
+                        class,
+                        id,
+                        payload,
+                        received_at: None,
+                    });
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,78 @@
+use crate::proto::*;
+
+/// ACK-ACK and ACK-NAK, the receiver's answer to a configuration message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckPacket {
+    Ack { class: Class, id: u8 },
+    Nak { class: Class, id: u8 },
+}
+
+impl AckPacket {
+    /// Whether this answers a message of `class` and `id`
+    pub fn answers(&self, class: Class, id: u8) -> bool {
+        match *self {
+            AckPacket::Ack { class: c, id: i } | AckPacket::Nak { class: c, id: i } => {
+                c == class && i == id
+            }
+        }
+    }
+}
+
+impl TryFrom<&Packet> for AckPacket {
+    type Error = DecodeError;
+    fn try_from(p: &Packet) -> Result<AckPacket, DecodeError> {
+        let unknown = DecodeError::UnknownMessage {
+            class: p.class,
+            id: p.id,
+        };
+        if p.id > 0x01 {
+            return Err(unknown);
+        }
+        check_len(&p.payload, 2)?;
+        let class = Class::from(p.payload[0]);
+        let id = p.payload[1];
+        Ok(match p.id {
+            0x01 => AckPacket::Ack { class, id },
+            _ => AckPacket::Nak { class, id },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_acks() {
+        // ACK-ACK for CFG-PRT
+        let buf = [0xb5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x00, 0x0e, 0x37];
+        let p = Packet::deserialize(&buf).unwrap();
+        let ack = match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Ack(a) => a,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(
+            ack,
+            AckPacket::Ack {
+                class: Class::ConfigInput,
+                id: 0x00
+            }
+        );
+        assert!(ack.answers(Class::ConfigInput, 0x00));
+        assert!(!ack.answers(Class::ConfigInput, 0x01));
+
+        let p = Packet {
+            class: Class::AckNack,
+            id: 0x00,
+            payload: vec![0x06, 0x01],
+            received_at: None,
+        };
+        assert_eq!(
+            AckPacket::try_from(&p),
+            Ok(AckPacket::Nak {
+                class: Class::ConfigInput,
+                id: 0x01
+            })
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::helpers::{buf_to_2u8, buf_to_4u8};
 use crate::proto::*;
 use std::time::Duration;
 
@@ -5,6 +6,23 @@ use std::time::Duration;
 pub enum CfgPacket {
     Msg(CfgMsg),
     Port(Port),
+    Rate(CfgRate),
+    Tp5(CfgTp5),
+}
+
+impl TryFrom<&Packet> for CfgPacket {
+    type Error = DecodeError;
+    fn try_from(p: &Packet) -> Result<CfgPacket, DecodeError> {
+        match p.id {
+            CfgMsg::ID => CfgMsg::try_from(&p.payload[..]).map(CfgPacket::Msg),
+            CfgRate::ID => CfgRate::try_from(&p.payload[..]).map(CfgPacket::Rate),
+            CfgTp5::ID => CfgTp5::try_from(&p.payload[..]).map(CfgPacket::Tp5),
+            _ => Err(DecodeError::UnknownMessage {
+                class: p.class,
+                id: p.id,
+            }),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -16,12 +34,45 @@ pub enum PortProto {
 }
 #[derive(Debug)]
 pub enum PortMode {
+    /// UART1
     UART(UartCfg),
+    USB,
+    SPI(SpiCfg),
+    /// I2C
+    DDC(DdcCfg),
 }
 impl PortMode {}
+/// Data bits, parity and stop bits
 #[derive(Debug)]
 pub enum UartMode {
     Mode8N1,
+    Mode8E1,
+    Mode8O1,
+    Mode8N2,
+    Mode7N1,
+    Mode7E1,
+    Mode7O1,
+}
+impl UartMode {
+    /// The mode bitfield; bit 4 is reserved but set for older receivers
+    fn bits(&self) -> u16 {
+        const BITS_7: u16 = 0b10 << 6;
+        const BITS_8: u16 = 0b11 << 6;
+        const EVEN: u16 = 0b000 << 9;
+        const ODD: u16 = 0b001 << 9;
+        const NO_PARITY: u16 = 0b100 << 9;
+        const STOP_1: u16 = 0b00 << 12;
+        const STOP_2: u16 = 0b10 << 12;
+        0x10 | match self {
+            UartMode::Mode8N1 => BITS_8 | NO_PARITY | STOP_1,
+            UartMode::Mode8E1 => BITS_8 | EVEN | STOP_1,
+            UartMode::Mode8O1 => BITS_8 | ODD | STOP_1,
+            UartMode::Mode8N2 => BITS_8 | NO_PARITY | STOP_2,
+            UartMode::Mode7N1 => BITS_7 | NO_PARITY | STOP_1,
+            UartMode::Mode7E1 => BITS_7 | EVEN | STOP_1,
+            UartMode::Mode7O1 => BITS_7 | ODD | STOP_1,
+        }
+    }
 }
 #[derive(Debug)]
 pub struct UartCfg {
@@ -30,6 +81,18 @@ pub struct UartCfg {
     pub lsb: bool,
 }
 #[derive(Debug)]
+pub struct SpiCfg {
+    /// SPI mode 0-3, i.e. clock polarity and phase
+    pub mode: u8,
+    /// 0xFF bytes in a row after which the receiver stops reading, 0-63
+    pub ff_count: u8,
+}
+#[derive(Debug)]
+pub struct DdcCfg {
+    /// 7 bit slave address, 0x42 by default
+    pub address: u8,
+}
+#[derive(Debug)]
 pub struct Port {
     pub port_mode: PortMode,
     pub proto_in: PortProto,
@@ -53,9 +116,7 @@ impl Serialize for UartCfg {
     fn serialize(&self) -> Vec<u8> {
         let mut out = Vec::new();
         out.extend(u32::to_le_bytes(1)); // 1 = UART; 4 SPI
-        out.extend(match self.mode {
-            UartMode::Mode8N1 => vec![0xD0, 0x08],
-        });
+        out.extend(u16::to_le_bytes(self.mode.bits()));
         out.extend(match self.lsb {
             true => u16::to_le_bytes(0),
             false => u16::to_le_bytes(1),
@@ -67,9 +128,19 @@ impl Serialize for UartCfg {
 impl Serialize for PortMode {
     fn serialize(&self) -> Vec<u8> {
         let mut out = Vec::new();
-        out.extend(match self {
-            PortMode::UART(cfg) => cfg.serialize(),
-        });
+        // port id, reserved and no TX-ready pin; then the mode, and a baud rate only for UART
+        let (id, mode): (u32, u32) = match self {
+            PortMode::UART(cfg) => return cfg.serialize(),
+            PortMode::USB => (3, 0),
+            PortMode::SPI(cfg) => (
+                4,
+                u32::from(cfg.mode & 0x3) << 1 | u32::from(cfg.ff_count & 0x3f) << 8,
+            ),
+            PortMode::DDC(cfg) => (0, u32::from(cfg.address & 0x7f) << 1),
+        };
+        out.extend(u32::to_le_bytes(id));
+        out.extend(u32::to_le_bytes(mode));
+        out.extend(u32::to_le_bytes(0));
         out
     }
 }
@@ -84,26 +155,364 @@ impl Serialize for Port {
     }
 }
 
-#[derive(Debug)]
+/// The receiver's ports, in the order CFG-MSG lists their rates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortId {
+    I2C,
+    UART1,
+    UART2,
+    USB,
+    SPI,
+}
+
+/// CFG-MSG: how often the receiver outputs message `c`/`id` by itself, on each port
+#[derive(Debug, PartialEq)]
 pub struct CfgMsg {
     pub c: Class,
     pub id: u8,
-    pub rate: Duration,
+    /// Indexed by `PortId`, plus a reserved port. A rate of n sends the message every n-th
+    /// navigation solution; 0 turns it off
+    pub rates: [u8; 6],
 }
 
 impl CfgMsg {
-    pub fn serialize_request(&self) -> Vec<u8> {
-        let p = Packet {
+    pub const ID: u8 = 0x01;
+
+    /// Output on `port` only, every `rate`-th navigation solution
+    pub fn on_port(c: Class, id: u8, port: PortId, rate: u8) -> CfgMsg {
+        let mut rates = [0; 6];
+        rates[port as usize] = rate;
+        CfgMsg { c, id, rates }
+    }
+
+    pub fn packet(&self) -> Packet {
+        Packet {
+            class: Class::ConfigInput,
+            id: CfgMsg::ID,
+            payload: self.serialize(),
+            received_at: None,
+        }
+    }
+
+    /// Asks for the current rates of `c`/`id`; the answer decodes as a `CfgMsg`
+    pub fn poll(c: Class, id: u8) -> Packet {
+        Packet {
+            class: Class::ConfigInput,
+            id: CfgMsg::ID,
+            payload: vec![c.into(), id],
+            received_at: None,
+        }
+    }
+}
+
+impl Serialize for CfgMsg {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = vec![self.c.into(), self.id];
+        out.extend(self.rates);
+        out
+    }
+}
+
+impl TryFrom<&[u8]> for CfgMsg {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<CfgMsg, DecodeError> {
+        check_len(buf, 8)?;
+        Ok(CfgMsg {
+            c: Class::from(buf[0]),
+            id: buf[1],
+            rates: buf[2..8].try_into().unwrap(),
+        })
+    }
+}
+
+/// Which time scale the measurements are aligned to; the constellations other than GPS need
+/// a receiver that tracks them, e.g. u-blox M8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeRef {
+    UTC,
+    GPS,
+    GLONASS,
+    BeiDou,
+    Galileo,
+    /// A value this crate has no name for, kept as the receiver sent it
+    Other(u16),
+}
+
+impl From<u16> for TimeRef {
+    fn from(v: u16) -> Self {
+        match v {
+            0 => TimeRef::UTC,
+            1 => TimeRef::GPS,
+            2 => TimeRef::GLONASS,
+            3 => TimeRef::BeiDou,
+            4 => TimeRef::Galileo,
+            v => TimeRef::Other(v),
+        }
+    }
+}
+
+impl From<TimeRef> for u16 {
+    fn from(t: TimeRef) -> Self {
+        match t {
+            TimeRef::UTC => 0,
+            TimeRef::GPS => 1,
+            TimeRef::GLONASS => 2,
+            TimeRef::BeiDou => 3,
+            TimeRef::Galileo => 4,
+            TimeRef::Other(v) => v,
+        }
+    }
+}
+
+/// CFG-RATE: how often the receiver measures, and how many measurements go into a navigation
+/// solution
+#[derive(Debug, PartialEq)]
+pub struct CfgRate {
+    /// Whole milliseconds, up to 65535; longer is sent as 65535
+    pub measurement: Duration,
+    pub measurements_per_solution: u16,
+    pub time_ref: TimeRef,
+}
+
+impl CfgRate {
+    pub const ID: u8 = 0x08;
+
+    pub fn packet(&self) -> Packet {
+        Packet {
+            class: Class::ConfigInput,
+            id: CfgRate::ID,
+            payload: self.serialize(),
+            received_at: None,
+        }
+    }
+}
+
+impl Poll for CfgRate {
+    fn class() -> Class {
+        Class::ConfigInput
+    }
+    fn id() -> u8 {
+        CfgRate::ID
+    }
+    fn polling_payload() -> Vec<u8> {
+        vec![]
+    }
+}
+
+impl Serialize for CfgRate {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let ms = u16::try_from(self.measurement.as_millis()).unwrap_or(u16::MAX);
+        out.extend(u16::to_le_bytes(ms));
+        out.extend(u16::to_le_bytes(self.measurements_per_solution));
+        out.extend(u16::to_le_bytes(self.time_ref.into()));
+        out
+    }
+}
+
+impl TryFrom<&[u8]> for CfgRate {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<CfgRate, DecodeError> {
+        check_len(buf, 6)?;
+        let ms = u16::from_le_bytes([buf[0], buf[1]]);
+        Ok(CfgRate {
+            measurement: Duration::from_millis(ms.into()),
+            measurements_per_solution: u16::from_le_bytes([buf[2], buf[3]]),
+            time_ref: TimeRef::from(u16::from_le_bytes([buf[4], buf[5]])),
+        })
+    }
+}
+
+/// How often a timepulse comes, before and after the receiver locks to GPS time
+#[derive(Debug, PartialEq)]
+pub enum PulseRate {
+    /// Whole microseconds, up to `u32::MAX`; longer is sent as that
+    Period {
+        unlocked: Duration,
+        locked: Duration,
+    },
+    /// In Hz
+    Frequency { unlocked: u32, locked: u32 },
+}
+
+/// How long a timepulse lasts, before and after the receiver locks to GPS time
+#[derive(Debug, PartialEq)]
+pub enum PulseWidth {
+    /// Whole microseconds, up to `u32::MAX`; 0 turns the pulse off
+    Length {
+        unlocked: Duration,
+        locked: Duration,
+    },
+    /// Share of the period, 0 to 1
+    DutyCycle { unlocked: f64, locked: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    /// The time is on the rising edge
+    Rising,
+    Falling,
+}
+
+/// CFG-TP5: the shape of timepulse `index` and what it is aligned to
+#[derive(Debug, PartialEq)]
+pub struct CfgTp5 {
+    /// 0 for TIMEPULSE, 1 for TIMEPULSE2
+    pub index: u8,
+    /// Message version; version 0 only has a UTC or GPS grid, version 1 (M8) adds the other
+    /// GNSS time scales. Version 1 is sent whenever `time_ref` is one of those.
+    pub version: u8,
+    /// Nanoseconds
+    pub antenna_cable_delay: i16,
+    /// Nanoseconds; read only, the receiver reports it and ignores what is sent
+    pub rf_group_delay: i16,
+    pub rate: PulseRate,
+    pub width: PulseWidth,
+    /// Nanoseconds
+    pub user_delay: i32,
+    pub active: bool,
+    /// Lock the pulse to GPS time once there is a fix, rather than the local oscillator
+    pub lock_to_gps: bool,
+    /// Switch to the `locked` rate and width once locked; otherwise the `unlocked` ones are used
+    /// throughout
+    pub locked_set: bool,
+    /// Align the pulses to the top of a second
+    pub align_to_tow: bool,
+    pub polarity: Polarity,
+    pub time_ref: TimeRef,
+}
+
+impl CfgTp5 {
+    pub const ID: u8 = 0x31;
+
+    pub fn packet(&self) -> Packet {
+        Packet {
             class: Class::ConfigInput,
-            id: self.id,
-            //B5 62 hdr
-            //06 08 class id
-            //06 00 len
-            //E8 03 01 00 01 00 payload
-            //01 39 ck
-            payload: vec![0xe8, 0x03, 0x1, 0x0, 0x1, 0x0],
+            id: CfgTp5::ID,
+            payload: self.serialize(),
+            received_at: None,
+        }
+    }
+
+    /// Asks for the configuration of timepulse `index`; the answer decodes as a `CfgTp5`
+    pub fn poll(index: u8) -> Packet {
+        Packet {
+            class: Class::ConfigInput,
+            id: CfgTp5::ID,
+            payload: vec![index],
+            received_at: None,
+        }
+    }
+}
+
+const ACTIVE: u32 = 1 << 0;
+const LOCK_GPS_FREQ: u32 = 1 << 1;
+const LOCKED_OTHER_SET: u32 = 1 << 2;
+const IS_FREQ: u32 = 1 << 3;
+const IS_LENGTH: u32 = 1 << 4;
+const ALIGN_TO_TOW: u32 = 1 << 5;
+const POLARITY: u32 = 1 << 6;
+/// `TimeRef`, 1 bit in version 0 and 4 bits in version 1
+const GRID_SHIFT: u32 = 7;
+
+fn grid_mask(version: u8) -> u32 {
+    match version {
+        0 => 0x1,
+        _ => 0xf,
+    }
+}
+
+/// A duty cycle in units of 2^-32
+fn ratio(share: f64) -> u32 {
+    (share.clamp(0.0, 1.0) * 2f64.powi(32)).min(u32::MAX.into()) as u32
+}
+
+impl Serialize for CfgTp5 {
+    fn serialize(&self) -> Vec<u8> {
+        let us = |d: Duration| u32::try_from(d.as_micros()).unwrap_or(u32::MAX);
+        let (is_freq, rate, rate_locked) = match self.rate {
+            PulseRate::Period { unlocked, locked } => (0, us(unlocked), us(locked)),
+            PulseRate::Frequency { unlocked, locked } => (IS_FREQ, unlocked, locked),
+        };
+        let (is_length, width, width_locked) = match self.width {
+            PulseWidth::Length { unlocked, locked } => (IS_LENGTH, us(unlocked), us(locked)),
+            PulseWidth::DutyCycle { unlocked, locked } => (0, ratio(unlocked), ratio(locked)),
         };
-        p.serialize()
+        let grid = u32::from(u16::from(self.time_ref));
+        let version = if grid > 1 {
+            self.version.max(1)
+        } else {
+            self.version
+        };
+        let flag = |set: bool, bit: u32| if set { bit } else { 0 };
+        let flags = flag(self.active, ACTIVE)
+            | flag(self.lock_to_gps, LOCK_GPS_FREQ)
+            | flag(self.locked_set, LOCKED_OTHER_SET)
+            | is_freq
+            | is_length
+            | flag(self.align_to_tow, ALIGN_TO_TOW)
+            | flag(self.polarity == Polarity::Rising, POLARITY)
+            | (grid & grid_mask(version)) << GRID_SHIFT;
+
+        let mut out = vec![self.index, version, 0, 0];
+        out.extend(i16::to_le_bytes(self.antenna_cable_delay));
+        out.extend(i16::to_le_bytes(self.rf_group_delay));
+        out.extend(u32::to_le_bytes(rate));
+        out.extend(u32::to_le_bytes(rate_locked));
+        out.extend(u32::to_le_bytes(width));
+        out.extend(u32::to_le_bytes(width_locked));
+        out.extend(i32::to_le_bytes(self.user_delay));
+        out.extend(u32::to_le_bytes(flags));
+        out
+    }
+}
+
+impl TryFrom<&[u8]> for CfgTp5 {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<CfgTp5, DecodeError> {
+        check_len(buf, 32)?;
+        let u32_at = |i: usize| u32::from_le_bytes(buf_to_4u8(&buf[i..]));
+        let flags = u32_at(28);
+        let (rate, rate_locked) = (u32_at(8), u32_at(12));
+        let (width, width_locked) = (u32_at(16), u32_at(20));
+        let us = |v: u32| Duration::from_micros(v.into());
+        let share = |v: u32| f64::from(v) / 2f64.powi(32);
+        Ok(CfgTp5 {
+            index: buf[0],
+            version: buf[1],
+            antenna_cable_delay: i16::from_le_bytes(buf_to_2u8(&buf[4..])),
+            rf_group_delay: i16::from_le_bytes(buf_to_2u8(&buf[6..])),
+            rate: match flags & IS_FREQ {
+                0 => PulseRate::Period {
+                    unlocked: us(rate),
+                    locked: us(rate_locked),
+                },
+                _ => PulseRate::Frequency {
+                    unlocked: rate,
+                    locked: rate_locked,
+                },
+            },
+            width: match flags & IS_LENGTH {
+                0 => PulseWidth::DutyCycle {
+                    unlocked: share(width),
+                    locked: share(width_locked),
+                },
+                _ => PulseWidth::Length {
+                    unlocked: us(width),
+                    locked: us(width_locked),
+                },
+            },
+            user_delay: i32::from_le_bytes(buf_to_4u8(&buf[24..])),
+            active: flags & ACTIVE != 0,
+            lock_to_gps: flags & LOCK_GPS_FREQ != 0,
+            locked_set: flags & LOCKED_OTHER_SET != 0,
+            align_to_tow: flags & ALIGN_TO_TOW != 0,
+            polarity: match flags & POLARITY {
+                0 => Polarity::Falling,
+                _ => Polarity::Rising,
+            },
+            time_ref: TimeRef::from(((flags >> GRID_SHIFT) & grid_mask(buf[1])) as u16),
+        })
     }
 }
 
@@ -127,5 +536,202 @@ mod tests {
             0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
         ];
         assert_eq!(pc.serialize(), expected);
+
+        let pc = Port {
+            port_mode: PortMode::UART(UartCfg {
+                baudrate: 115200,
+                mode: UartMode::Mode7E1,
+                lsb: true,
+            }),
+            proto_in: PortProto::UBX_NMEA,
+            proto_out: PortProto::UBX,
+        };
+        assert_eq!(
+            pc.serialize()[4..12],
+            [0x90, 0x00, 0x00, 0x00, 0x00, 0xc2, 0x01, 0x00]
+        );
+        assert_eq!(pc.serialize()[12..14], [0x03, 0x00]);
+
+        let pc = Port {
+            port_mode: PortMode::DDC(DdcCfg { address: 0x42 }),
+            proto_in: PortProto::UBX,
+            proto_out: PortProto::UBX,
+        };
+        assert_eq!(
+            pc.serialize()[..12],
+            [0x00, 0x00, 0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        let pc = Port {
+            port_mode: PortMode::SPI(SpiCfg {
+                mode: 1,
+                ff_count: 50,
+            }),
+            proto_in: PortProto::UBX,
+            proto_out: PortProto::UBX,
+        };
+        assert_eq!(
+            pc.serialize()[..8],
+            [0x04, 0x00, 0x00, 0x00, 0x02, 0x32, 0x00, 0x00]
+        );
+        assert_eq!(pc.serialize().len(), 20);
+    }
+
+    #[test]
+    fn tp5_round_trip() {
+        // the receiver's defaults: 1Hz, 100ms high, on UTC seconds, off until locked
+        let tp = CfgTp5 {
+            index: 0,
+            version: 0,
+            antenna_cable_delay: 50,
+            rf_group_delay: 0,
+            rate: PulseRate::Frequency {
+                unlocked: 1,
+                locked: 1,
+            },
+            width: PulseWidth::Length {
+                unlocked: Duration::ZERO,
+                locked: Duration::from_millis(100),
+            },
+            user_delay: 0,
+            active: true,
+            lock_to_gps: true,
+            locked_set: true,
+            align_to_tow: true,
+            polarity: Polarity::Rising,
+            time_ref: TimeRef::UTC,
+        };
+        let payload = tp.serialize();
+        assert_eq!(
+            payload,
+            vec![
+                0x00, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x86, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x7f, 0x00, 0x00, 0x00
+            ]
+        );
+        let p = Packet::deserialize(&tp.packet().serialize()).unwrap();
+        match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Configuration(CfgPacket::Tp5(t)) => assert_eq!(t, tp),
+            r => panic!("{:?}", r),
+        }
+
+        // 1MHz square wave on GPS time, the other timepulse
+        let tp = CfgTp5 {
+            index: 1,
+            version: 0,
+            antenna_cable_delay: -5,
+            rf_group_delay: 20,
+            rate: PulseRate::Period {
+                unlocked: Duration::from_secs(1),
+                locked: Duration::from_micros(1),
+            },
+            width: PulseWidth::DutyCycle {
+                unlocked: 0.0,
+                locked: 0.5,
+            },
+            user_delay: -100,
+            active: true,
+            lock_to_gps: false,
+            locked_set: true,
+            align_to_tow: false,
+            polarity: Polarity::Falling,
+            time_ref: TimeRef::GPS,
+        };
+        assert_eq!(CfgTp5::try_from(&tp.serialize()[..]), Ok(tp));
+
+        // grids other than UTC and GPS need version 1, and its 4 bit field
+        let mut galileo = CfgTp5 {
+            time_ref: TimeRef::Galileo,
+            ..CfgTp5::try_from(&payload[..]).unwrap()
+        };
+        let mut buf = galileo.serialize();
+        assert_eq!(buf[1], 1);
+        assert_eq!(buf[28..], [0x7f, 0x02, 0x00, 0x00]);
+        galileo.version = 1;
+        assert_eq!(CfgTp5::try_from(&buf[..]), Ok(galileo));
+        // version 0 only has the UTC/GPS bit
+        buf[1] = 0;
+        assert_eq!(CfgTp5::try_from(&buf[..]).unwrap().time_ref, TimeRef::UTC);
+
+        // periods too long for the field are capped, not wrapped
+        let tp = CfgTp5 {
+            rate: PulseRate::Period {
+                unlocked: Duration::from_secs(5000),
+                locked: Duration::from_secs(1),
+            },
+            ..CfgTp5::try_from(&payload[..]).unwrap()
+        };
+        assert_eq!(tp.serialize()[8..12], u32::MAX.to_le_bytes());
+        assert_eq!(ratio(1.0), u32::MAX);
+
+        let p = CfgTp5::poll(1);
+        assert_eq!(
+            p.serialize(),
+            vec![0xb5, 0x62, 0x06, 0x31, 0x01, 0x00, 0x01, 0x39, 0xe6]
+        );
+    }
+
+    #[test]
+    fn msg_and_rate_round_trip() {
+        // NAV-TIMEGPS on UART1 every solution
+        let msg = CfgMsg::on_port(Class::Navigation, 0x20, PortId::UART1, 1);
+        let buf = msg.packet().serialize();
+        assert_eq!(
+            buf,
+            vec![
+                0xb5, 0x62, 0x06, 0x01, 0x08, 0x00, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+                0x31, 0x90
+            ]
+        );
+        let p = Packet::deserialize(&buf).unwrap();
+        match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Configuration(CfgPacket::Msg(m)) => assert_eq!(m, msg),
+            r => panic!("{:?}", r),
+        }
+
+        // 1Hz, aligned to GPS time
+        let rate = CfgRate {
+            measurement: Duration::from_millis(1000),
+            measurements_per_solution: 1,
+            time_ref: TimeRef::GPS,
+        };
+        let buf = rate.packet().serialize();
+        assert_eq!(
+            buf,
+            vec![
+                0xb5, 0x62, 0x06, 0x08, 0x06, 0x00, 0xe8, 0x03, 0x01, 0x00, 0x01, 0x00, 0x01, 0x39
+            ]
+        );
+        let p = Packet::deserialize(&buf).unwrap();
+        match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Configuration(CfgPacket::Rate(r)) => assert_eq!(r, rate),
+            r => panic!("{:?}", r),
+        }
+
+        // the time scale is kept as sent, the rate is capped
+        let rate = CfgRate {
+            measurement: Duration::from_secs(100),
+            measurements_per_solution: 1,
+            time_ref: TimeRef::Galileo,
+        };
+        let buf = rate.serialize();
+        assert_eq!(buf, vec![0xff, 0xff, 0x01, 0x00, 0x04, 0x00]);
+        assert_eq!(
+            CfgRate::try_from(&buf[..]).unwrap().measurement,
+            Duration::from_millis(65535)
+        );
+        let buf = [0xe8, 0x03, 0x01, 0x00, 0x09, 0x00];
+        assert_eq!(
+            CfgRate::try_from(&buf[..]).unwrap().time_ref,
+            TimeRef::Other(9)
+        );
+        assert_eq!(CfgRate::try_from(&buf[..]).unwrap().serialize(), buf);
+
+        // polls are too short to decode
+        let p = CfgMsg::poll(Class::Navigation, 0x20);
+        assert_eq!(
+            CfgPacket::try_from(&p).unwrap_err(),
+            DecodeError::TooShort { needed: 8, got: 2 }
+        );
     }
 }
@@ -1,6 +1,7 @@
 use crate::helpers::*;
 use crate::proto::*;
 use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use ntp::leapfile::LeapTable;
 use std::time::Duration;
 
 #[derive(Debug)]
@@ -9,15 +10,22 @@ pub enum NavPacket {
     TimeUTC(TimeUTC),
     TimeGPS(TimeGPS),
     SVInfo(SVInfo),
+    LeapSecond(TimeLS),
 }
-impl From<Packet> for NavPacket {
-    fn from(p: Packet) -> NavPacket {
+impl TryFrom<&Packet> for NavPacket {
+    type Error = DecodeError;
+    fn try_from(p: &Packet) -> Result<NavPacket, DecodeError> {
+        let buf = p.payload.as_slice();
         match p.id {
-            0x03 => NavPacket::Status(NavStatus::from(p.payload.as_slice())),
-            0x20 => NavPacket::TimeGPS(TimeGPS::from(p.payload.as_slice())),
-            0x21 => NavPacket::TimeUTC(TimeUTC::from(p.payload.as_slice())),
-            0x30 => NavPacket::SVInfo(SVInfo::from(p.payload.as_slice())),
-            _ => unimplemented!("idk how to handle id {}", p.id),
+            0x03 => NavStatus::try_from(buf).map(NavPacket::Status),
+            0x20 => TimeGPS::try_from(buf).map(NavPacket::TimeGPS),
+            0x21 => TimeUTC::try_from(buf).map(NavPacket::TimeUTC),
+            0x26 => TimeLS::try_from(buf).map(NavPacket::LeapSecond),
+            0x30 => SVInfo::try_from(buf).map(NavPacket::SVInfo),
+            _ => Err(DecodeError::UnknownMessage {
+                class: p.class,
+                id: p.id,
+            }),
         }
     }
 }
@@ -69,9 +77,12 @@ pub struct SVInfo {
     pub healthy_channels: u8,
 }
 
-impl From<&[u8]> for SVInfo {
-    fn from(buf: &[u8]) -> SVInfo {
+impl TryFrom<&[u8]> for SVInfo {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<SVInfo, DecodeError> {
+        check_len(buf, 8)?;
         let chan_n = buf[4];
+        check_len(buf, 8 + 12 * chan_n as usize)?;
         let mut healthy_n = 0;
         for i in 0..chan_n {
             let _chn = buf[8 + 12 * i as usize];
@@ -85,9 +96,9 @@ impl From<&[u8]> for SVInfo {
                 healthy_n += 1;
             }
         }
-        SVInfo {
+        Ok(SVInfo {
             healthy_channels: healthy_n,
-        }
+        })
     }
 }
 impl Poll for SVInfoPoll {
@@ -158,15 +169,17 @@ impl Poll for NavStatusPoll {
     }
 }
 
-impl From<&[u8]> for NavStatus {
-    fn from(buf: &[u8]) -> NavStatus {
+impl TryFrom<&[u8]> for NavStatus {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<NavStatus, DecodeError> {
+        check_len(buf, 16)?;
         let up = u32::from_le_bytes(buf_to_4u8(&buf[12..16])) as u64;
-        NavStatus {
+        Ok(NavStatus {
             _milli: u32::from_le_bytes(buf_to_4u8(&buf[0..4])),
             fix: NavFix::from(buf[4]),
             _time_to_fix: u32::from_le_bytes(buf_to_4u8(&buf[8..12])),
             uptime: Duration::from_millis(up),
-        }
+        })
     }
 }
 #[derive(Debug, Clone, Copy)]
@@ -180,7 +193,7 @@ impl From<u8> for Valid {
         Valid {
             time_of_week: (b & 0x1) > 0,
             week_num: (b & 0x2) > 0,
-            leap_sec: (b & 0x3) > 0,
+            leap_sec: (b & 0x4) > 0,
         }
     }
 }
@@ -196,48 +209,84 @@ pub struct TimeGPS {
     pub accuracy: Duration,
 }
 
-impl From<&[u8]> for TimeGPS {
-    fn from(buf: &[u8]) -> TimeGPS {
-        TimeGPS {
+impl TryFrom<&[u8]> for TimeGPS {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<TimeGPS, DecodeError> {
+        check_len(buf, 16)?;
+        Ok(TimeGPS {
             milli: u32::from_le_bytes(buf_to_4u8(buf)),
             nanos: i32::from_le_bytes(buf_to_4u8(&buf[4..8])),
             week: i16::from_le_bytes(buf_to_2u8(&buf[8..10])),
             leap_sec: buf[10] as i8,
             valid_flags: Valid::from(buf[11]),
             accuracy: Duration::from_nanos(u32::from_le_bytes(buf_to_4u8(&buf[12..])) as u64),
-        }
+        })
     }
 }
 
-impl From<TimeGPS> for Option<DateTime<Utc>> {
-    fn from(t: TimeGPS) -> Option<DateTime<Utc>> {
-        if t.accuracy > Duration::from_millis(100) {
+impl TimeGPS {
+    /// GPS time minus UTC, once the receiver knows it
+    pub fn leap_seconds(&self) -> Option<i8> {
+        self.valid_flags.leap_sec.then_some(self.leap_sec)
+    }
+
+    /// GPS time minus UTC, from `table` while it is current: after a cold start the receiver
+    /// uses the count in its firmware until it downloads the almanac. The receiver's value once
+    /// the table expired.
+    pub fn leap_seconds_from(&self, table: &LeapTable) -> Option<i8> {
+        self.table_leap_seconds(table).or(self.leap_seconds())
+    }
+
+    /// GPS time minus UTC according to `table` alone
+    pub fn table_leap_seconds(&self, table: &LeapTable) -> Option<i8> {
+        let gps = self.gps_time()?;
+        // the table goes by UTC, a first guess is at most a second off
+        let guess = table.gps_offset(gps)?;
+        table.gps_offset(gps - TimeDelta::seconds(guess.into()))
+    }
+
+    /// The receiver's count, whether or not it confirmed it
+    pub fn leap_seconds_unchecked(&self) -> i8 {
+        self.leap_sec
+    }
+
+    /// UTC, using `leap_seconds_from`
+    pub fn utc(&self, table: &LeapTable) -> Option<DateTime<Utc>> {
+        self.utc_with(self.leap_seconds_from(table)?)
+    }
+
+    /// UTC, given GPS time minus UTC
+    pub fn utc_with(&self, leap_sec: i8) -> Option<DateTime<Utc>> {
+        Some(self.gps_time()? - TimeDelta::seconds(leap_sec.into()))
+    }
+
+    /// GPS time, as if it were UTC; None unless it is valid and accurate enough
+    fn gps_time(&self) -> Option<DateTime<Utc>> {
+        if self.accuracy > Duration::from_millis(100) {
             return None;
         }
-        if !t.valid_flags.time_of_week || !t.valid_flags.week_num || !t.valid_flags.leap_sec {
+        if !self.valid_flags.time_of_week || !self.valid_flags.week_num {
             return None;
         }
-        // https://www.gps.gov/technical/icwg/IS-GPS-200G.pdf, page 39
-        let d = DateTime::<Utc>::from_naive_utc_and_offset(
-            NaiveDate::from_ymd_opt(1980, 1, 6)
-                .unwrap()
-                .and_hms_opt(0, 0, 0)
-                .unwrap(),
-            Utc,
-        );
-
-        let d = d + TimeDelta::weeks(t.week as i64);
-        let d = d + TimeDelta::milliseconds(t.milli as i64);
-        let d = d + TimeDelta::nanoseconds(t.nanos as i64);
+        let d = gps_epoch() + TimeDelta::weeks(self.week as i64);
+        let d = d + TimeDelta::milliseconds(self.milli as i64);
+        Some(d + TimeDelta::nanoseconds(self.nanos as i64))
+    }
+}
 
+/// Trusts the receiver's leap seconds, see `TimeGPS::utc` for a checked conversion
+impl From<TimeGPS> for Option<DateTime<Utc>> {
+    fn from(t: TimeGPS) -> Option<DateTime<Utc>> {
         // this converts GPS time to UTC time
-        Some(d - TimeDelta::seconds(t.leap_sec as i64))
+        t.utc_with(t.leap_seconds()?)
     }
 }
 
-impl From<&[u8]> for TimeUTC {
-    fn from(buf: &[u8]) -> TimeUTC {
-        TimeUTC {
+impl TryFrom<&[u8]> for TimeUTC {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<TimeUTC, DecodeError> {
+        check_len(buf, 20)?;
+        Ok(TimeUTC {
             _weeks_milli: u32::from_le_bytes(buf_to_4u8(buf)),
             _accuracy: u32::from_le_bytes(buf_to_4u8(&buf[4..8])),
             nanos: i32::from_le_bytes(buf_to_4u8(&buf[8..12])),
@@ -248,7 +297,7 @@ impl From<&[u8]> for TimeUTC {
             min: buf[17],
             sec: buf[18],
             _valid: Valid::from(buf[19]),
-        }
+        })
     }
 }
 
@@ -266,19 +315,75 @@ pub struct TimeUTC {
     _valid: Valid,
 }
 
-impl From<TimeUTC> for DateTime<Utc> {
-    fn from(t: TimeUTC) -> DateTime<Utc> {
+/// None for dates the receiver should not send, like the all zero ones before it has a fix
+impl From<TimeUTC> for Option<DateTime<Utc>> {
+    fn from(t: TimeUTC) -> Option<DateTime<Utc>> {
+        let date = NaiveDate::from_ymd_opt(t.year.into(), t.month.into(), t.day.into())?;
+        // 23:59:60 during a leap second
+        let (sec, leap_nanos) = match t.sec {
+            60 => (59, 1_000_000_000),
+            s => (s, 0),
+        };
         let d = DateTime::<Utc>::from_naive_utc_and_offset(
-            NaiveDate::from_ymd_opt(t.year.into(), t.month.into(), t.day.into())
-                .unwrap()
-                .and_hms_opt(t.hour.into(), t.min.into(), t.sec.into())
-                .unwrap(),
+            date.and_hms_nano_opt(t.hour.into(), t.min.into(), sec.into(), leap_nanos)?,
             Utc,
         );
 
-        d + TimeDelta::nanoseconds(t.nanos as i64)
+        Some(d + TimeDelta::nanoseconds(t.nanos as i64))
+    }
+}
+/// Leap second information, NAV-TIMELS
+#[derive(Debug, Clone, Copy)]
+pub struct TimeLS {
+    current: i8,
+    current_valid: bool,
+    /// Seconds added to UTC at the next event: -1, 0 or 1
+    change: i8,
+    /// Seconds until the event; negative once it passed
+    time_to_event: i32,
+    time_to_event_valid: bool,
+}
+
+impl TryFrom<&[u8]> for TimeLS {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<TimeLS, DecodeError> {
+        check_len(buf, 24)?;
+        Ok(TimeLS {
+            current: buf[9] as i8,
+            change: buf[11] as i8,
+            time_to_event: i32::from_le_bytes(buf_to_4u8(&buf[12..16])),
+            current_valid: (buf[23] & 0x1) > 0,
+            time_to_event_valid: (buf[23] & 0x2) > 0,
+        })
     }
 }
+
+impl TimeLS {
+    /// GPS time minus UTC, once the receiver knows it
+    pub fn leap_seconds(&self) -> Option<i8> {
+        self.current_valid.then_some(self.current)
+    }
+
+    /// Seconds until the next event and the seconds it adds to UTC, once the receiver has the
+    /// UTC parameters; the change is 0 when no event is scheduled
+    pub fn countdown(&self) -> Option<(i64, i8)> {
+        self.time_to_event_valid
+            .then_some((self.time_to_event.into(), self.change))
+    }
+}
+
+impl Poll for TimeLS {
+    fn class() -> Class {
+        Class::Navigation
+    }
+    fn id() -> u8 {
+        0x26
+    }
+    fn polling_payload() -> Vec<u8> {
+        vec![]
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -290,7 +395,7 @@ mod tests {
             0x81, 0x07, 0x11, 0x07, 0x1, 0x00, 0x00, 0x00, 163, 125,
         ];
         let p = Packet::deserialize(&buf).unwrap();
-        let pp = ParsedPacket::from(p);
+        let pp = ParsedPacket::try_from(p).unwrap();
         match pp {
             ParsedPacket::Navigation(n) => match n {
                 NavPacket::TimeGPS(t) => {
@@ -312,4 +417,66 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn leap_seconds_from_table() {
+        let mut payload = vec![
+            0xce, 0x74, 0x3e, 0x04, 0x88, 0xcc, 0xfa, 0xff, 0x81, 0x07, 0x11, 0x07, 0x1, 0x00,
+            0x00, 0x00,
+        ];
+        let table = LeapTable::builtin();
+        let t = TimeGPS::try_from(payload.as_slice()).unwrap();
+        let trusted = Option::<DateTime<Utc>>::from(t).unwrap();
+        assert_eq!(t.utc(&table), Some(trusted));
+
+        // a cold start: the firmware's count, not yet confirmed by the almanac
+        payload[10] = 15;
+        payload[11] = 0x3;
+        let t = TimeGPS::try_from(payload.as_slice()).unwrap();
+        assert_eq!(t.leap_seconds(), None);
+        assert_eq!(Option::<DateTime<Utc>>::from(t), None);
+        assert_eq!(t.leap_seconds_from(&table), Some(17));
+        assert_eq!(t.utc(&table), Some(trusted));
+
+        // past its expiry, the table has nothing to say
+        let expired = LeapTable::parse("#$ 3550089600\n#@ 3600000000\n3550089600 35\n").unwrap();
+        assert_eq!(t.leap_seconds_from(&expired), None);
+        payload[11] = 0x7;
+        let t = TimeGPS::try_from(payload.as_slice()).unwrap();
+        assert_eq!(t.leap_seconds_from(&expired), Some(15));
+    }
+
+    #[test]
+    fn parse_leap_second_packet() {
+        let mut payload = vec![0; 24];
+        payload[8] = 2;
+        payload[9] = 17;
+        payload[10] = 2;
+        payload[11] = 1;
+        // 2016-12-31 23:59:60 is a day away
+        payload[12..16].copy_from_slice(&86_401i32.to_le_bytes());
+        payload[16..18].copy_from_slice(&1930u16.to_le_bytes());
+        payload[18..20].copy_from_slice(&7u16.to_le_bytes());
+        payload[23] = 0x3;
+        let buf = Packet {
+            class: Class::Navigation,
+            id: 0x26,
+            payload,
+            received_at: None,
+        }
+        .serialize();
+        let p = Packet::deserialize(&buf).unwrap();
+        match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Navigation(NavPacket::LeapSecond(t)) => {
+                assert_eq!(t.leap_seconds(), Some(17));
+                assert_eq!(t.countdown(), Some((86_401, 1)));
+            }
+            _ => panic!(),
+        }
+
+        // nothing is known before the UTC parameters are received
+        let t = TimeLS::try_from(&[0u8; 24][..]).unwrap();
+        assert_eq!(t.leap_seconds(), None);
+        assert_eq!(t.countdown(), None);
+    }
 }
@@ -0,0 +1,109 @@
+use crate::helpers::*;
+use crate::proto::*;
+use chrono::{DateTime, TimeDelta, Utc};
+
+#[derive(Debug)]
+pub enum TimPacket {
+    TimePulse(TimePulse),
+}
+impl TryFrom<&Packet> for TimPacket {
+    type Error = DecodeError;
+    fn try_from(p: &Packet) -> Result<TimPacket, DecodeError> {
+        match p.id {
+            0x01 => TimePulse::try_from(p.payload.as_slice()).map(TimPacket::TimePulse),
+            _ => Err(DecodeError::UnknownMessage {
+                class: p.class,
+                id: p.id,
+            }),
+        }
+    }
+}
+
+/// TIM-TP, the time of the next time pulse; it is sent ahead of the pulse it describes
+#[derive(Debug, Clone, Copy)]
+pub struct TimePulse {
+    milli: u32,
+    /// 2^-32 ms
+    sub_milli: u32,
+    /// Quantization error of the pulse, in picoseconds
+    pub quantization_error: i32,
+    week: u16,
+    /// The pulse is aligned to UTC instead of GPS time
+    utc: bool,
+}
+
+impl Poll for TimePulse {
+    fn class() -> Class {
+        Class::Timing
+    }
+    fn id() -> u8 {
+        0x01
+    }
+    fn polling_payload() -> Vec<u8> {
+        vec![]
+    }
+}
+
+impl TryFrom<&[u8]> for TimePulse {
+    type Error = DecodeError;
+    fn try_from(buf: &[u8]) -> Result<TimePulse, DecodeError> {
+        check_len(buf, 16)?;
+        Ok(TimePulse {
+            milli: u32::from_le_bytes(buf_to_4u8(buf)),
+            sub_milli: u32::from_le_bytes(buf_to_4u8(&buf[4..8])),
+            quantization_error: i32::from_le_bytes(buf_to_4u8(&buf[8..12])),
+            week: u16::from_le_bytes(buf_to_2u8(&buf[12..14])),
+            utc: (buf[14] & 0x1) > 0,
+        })
+    }
+}
+
+impl TimePulse {
+    /// UTC time of the pulse; `leap_sec` is GPS time minus UTC, only needed for pulses aligned to
+    /// GPS time
+    pub fn time(&self, leap_sec: i8) -> DateTime<Utc> {
+        let d = gps_epoch() + TimeDelta::weeks(self.week as i64);
+        let d = d + TimeDelta::milliseconds(self.milli as i64);
+        let d = d + TimeDelta::nanoseconds(((self.sub_milli as u64 * 1_000_000) >> 32) as i64);
+        if self.utc {
+            d
+        } else {
+            d - TimeDelta::seconds(leap_sec as i64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_time_pulse() {
+        let mut payload = vec![];
+        // week 1921, Sunday 00:00:01.5005
+        payload.extend(1500u32.to_le_bytes());
+        payload.extend((1u32 << 31).to_le_bytes());
+        payload.extend((-20i32).to_le_bytes());
+        payload.extend(1921u16.to_le_bytes());
+        payload.extend([0, 0]);
+        let buf = Packet {
+            class: Class::Timing,
+            id: 0x01,
+            payload,
+            received_at: None,
+        }
+        .serialize();
+        let p = Packet::deserialize(&buf).unwrap();
+        match ParsedPacket::try_from(p).unwrap() {
+            ParsedPacket::Timing(TimPacket::TimePulse(t)) => {
+                assert_eq!(t.quantization_error, -20);
+                let expected = Utc.with_ymd_and_hms(2016, 10, 30, 0, 0, 1).unwrap()
+                    + TimeDelta::microseconds(500_500)
+                    - TimeDelta::seconds(17);
+                assert_eq!(t.time(17), expected);
+            }
+            _ => panic!(),
+        }
+    }
+}